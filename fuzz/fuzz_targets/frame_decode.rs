@@ -0,0 +1,25 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use vpn::protocol::{Framing, Padding};
+
+// Decode a stream of frames from arbitrary bytes, as received off the network.
+// The first byte selects the frame version, mux and padding.
+fuzz_target!(|data: &[u8]| {
+    let Some((&version, data)) = data.split_first() else {
+        return;
+    };
+    let framing = match version % 4 {
+        0 => Framing::V1,
+        1 => Framing::v2(1 << 20),
+        2 => Framing::v2(1 << 20).muxed(),
+        _ => Framing::v2(1 << 20).muxed().padded(Padding::Buckets),
+    };
+    let mut stream = Cursor::new(data);
+    let mut buf = [0u8; 1500];
+    while let Ok(n) = framing.recv(&mut stream, &mut buf) {
+        assert!(n <= buf.len());
+    }
+});
@@ -0,0 +1,34 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use vpn::protocol::{Framing, Padding, PAD_LEN};
+
+// Every packet that can be encoded must decode back to the same bytes,
+// in both frame versions, with mux and with padding
+fuzz_target!(|packet: &[u8]| {
+    for framing in [
+        Framing::V1,
+        Framing::v2(1 << 20),
+        Framing::v2(1 << 20).muxed(),
+        Framing::v2(1 << 12).muxed().padded(Padding::Mtu),
+        Framing::v2(1 << 20).muxed().padded(Padding::Buckets),
+    ] {
+        let mut wire = Vec::new();
+        if framing.send(&mut wire, packet).is_err() {
+            let overhead = if framing.pad == Padding::None { 0 } else { PAD_LEN };
+            assert!(packet.len() + overhead > framing.max_len);
+            continue;
+        }
+        if framing.pad == Padding::None {
+            assert_eq!(wire.len(), framing.header_len() + packet.len());
+        } else {
+            assert!(wire.len() >= framing.header_len() + PAD_LEN + packet.len());
+            assert!(wire.len() <= framing.header_len() + framing.max_len);
+        }
+        let mut buf = vec![0u8; packet.len()];
+        let n = framing.recv(&mut Cursor::new(&wire), &mut buf).unwrap();
+        assert_eq!(&buf[..n], packet);
+    }
+});
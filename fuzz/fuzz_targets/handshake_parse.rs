@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use vpn::protocol::{parse_handshake_response, read_line, HandshakeRequest, MAX_LINE_LEN};
+
+// Parse handshake lines from arbitrary bytes; anything accepted must round-trip.
+// However long the input, no more than MAX_LINE_LEN bytes are taken in.
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = read_line(&mut Cursor::new(data)) else {
+        return;
+    };
+    assert!(line.len() <= MAX_LINE_LEN);
+    let _ = parse_handshake_response(&line);
+    if let Ok(request) = HandshakeRequest::parse(&line) {
+        let encoded = request.encode();
+        assert_eq!(HandshakeRequest::parse(&encoded).unwrap(), request);
+    }
+});
@@ -0,0 +1,141 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::json::{self, Object};
+use crate::session::Session;
+
+// Size of the kernel's CPU mask (CPU_SETSIZE)
+const MAX_CPUS: usize = 1024;
+
+// Pinning the server's data path to chosen CPUs (`cpus = 2-5`), to keep it
+// away from other load on the machine.
+//
+// The TUN reader runs on the first CPU of the list. Sessions are spread
+// over the list by id, and both threads of a session (the receive loop and
+// the sender it starts) run on that session's CPU. A session resumed after
+// an upgrade keeps its id and so its CPU.
+
+// The CPUs of a `cpus` value such as `0,2-3`
+pub fn parse(text: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (low, high) = part.split_once('-').unwrap_or((part, part));
+        let (low, high): (usize, usize) = (low.trim().parse().ok()?, high.trim().parse().ok()?);
+        if low > high || high >= MAX_CPUS {
+            return None;
+        }
+        cpus.extend(low..=high);
+    }
+    Some(cpus)
+}
+
+// The CPU for a session, None when not pinning
+pub fn for_session(cpus: &[usize], id: u64) -> Option<usize> {
+    if cpus.is_empty() {
+        return None;
+    }
+    Some(cpus[(id.saturating_sub(1) % cpus.len() as u64) as usize])
+}
+
+// Pin the calling thread; threads it starts afterwards inherit the CPU
+#[cfg(target_os = "linux")]
+pub fn pin(cpu: usize) -> io::Result<()> {
+    use nix::libc;
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let res = unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("pinning to CPU {} is only supported on Linux", cpu),
+    ))
+}
+
+// Sessions and traffic of one CPU of `cpus`
+struct Load {
+    cpu: usize,
+    // Whether the TUN reader runs here
+    tun: bool,
+    sessions: usize,
+    rx_packets: u64,
+    rx_bytes: u64,
+    tx_packets: u64,
+    tx_bytes: u64,
+    tx_dropped: u64,
+}
+
+fn loads(cpus: &[usize], sessions: &[Arc<Session>]) -> Vec<Load> {
+    let mut loads = Vec::new();
+    let mut seen = Vec::new();
+    for &cpu in cpus {
+        if seen.contains(&cpu) {
+            continue;
+        }
+        seen.push(cpu);
+        let mine: Vec<_> = sessions
+            .iter()
+            .filter(|s| for_session(cpus, s.id) == Some(cpu))
+            .collect();
+        let sum = |f: fn(&Session) -> u64| mine.iter().map(|s| f(s)).sum::<u64>();
+        loads.push(Load {
+            cpu,
+            tun: cpu == cpus[0],
+            sessions: mine.len(),
+            rx_packets: sum(|s| s.rx_packets.load(Ordering::Relaxed)),
+            rx_bytes: sum(|s| s.rx_bytes.load(Ordering::Relaxed)),
+            tx_packets: sum(|s| s.tx_packets.load(Ordering::Relaxed)),
+            tx_bytes: sum(|s| s.tx_bytes.load(Ordering::Relaxed)),
+            tx_dropped: sum(|s| s.tx_dropped.load(Ordering::Relaxed)),
+        });
+    }
+    loads
+}
+
+// One line per CPU for `ctl cpus`: its sessions and their traffic
+pub fn report(cpus: &[usize], sessions: &[Arc<Session>]) -> String {
+    if cpus.is_empty() {
+        return "not pinned to CPUs\n".to_string();
+    }
+    let mut out = String::new();
+    for load in loads(cpus, sessions) {
+        writeln!(
+            out,
+            "cpu{}{} sessions={} rx={}/{}B tx={}/{}B drop={}",
+            load.cpu,
+            if load.tun { " tun" } else { "" },
+            load.sessions,
+            load.rx_packets,
+            load.rx_bytes,
+            load.tx_packets,
+            load.tx_bytes,
+            load.tx_dropped,
+        )
+        .unwrap();
+    }
+    out
+}
+
+// The same as a JSON array; empty when not pinned
+pub fn json(cpus: &[usize], sessions: &[Arc<Session>]) -> String {
+    json::array(loads(cpus, sessions).into_iter().map(|load| {
+        Object::new()
+            .num("cpu", load.cpu)
+            .bool("tun", load.tun)
+            .num("sessions", load.sessions)
+            .num("rx_packets", load.rx_packets)
+            .num("rx_bytes", load.rx_bytes)
+            .num("tx_packets", load.tx_packets)
+            .num("tx_bytes", load.tx_bytes)
+            .num("tx_dropped", load.tx_dropped)
+            .build()
+    }))
+}
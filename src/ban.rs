@@ -0,0 +1,272 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::json::{self, Object};
+
+// Window `handshake_rate` is counted over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// Window `ban_after` failed handshakes are counted over
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+// How often state for quiet sources is dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Per-source limits on the TCP listeners: at most `rate` connections a
+// minute, and a temporary ban after `ban_after` rejected handshakes.
+// Sources can also be banned and unbanned by hand (`ctl ban/unban`).
+#[derive(Debug)]
+pub struct BanList {
+    // 0 = unlimited
+    rate: usize,
+    // 0 = never ban automatically
+    ban_after: usize,
+    ban_time: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+    // Banned sources and when the ban ends (None = until unbanned)
+    banned: HashMap<IpAddr, Option<Instant>>,
+    last_prune: Instant,
+}
+
+impl BanList {
+    pub fn new(rate: usize, ban_after: usize, ban_time: Duration) -> BanList {
+        BanList {
+            rate,
+            ban_after,
+            ban_time,
+            state: Mutex::new(State {
+                attempts: HashMap::new(),
+                failures: HashMap::new(),
+                banned: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    // Record a new connection from `ip`; false if it must be refused
+    pub fn admit(&self, ip: IpAddr) -> bool {
+        self.admit_at(ip, Instant::now())
+    }
+
+    fn admit_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+        if now - state.last_prune >= PRUNE_INTERVAL {
+            state.prune(now);
+        }
+        if state.is_banned(ip, now) {
+            return false;
+        }
+        if self.rate == 0 {
+            return true;
+        }
+        let attempts = state.attempts.entry(ip).or_default();
+        expire(attempts, now, RATE_WINDOW);
+        if attempts.len() >= self.rate {
+            return false;
+        }
+        attempts.push_back(now);
+        true
+    }
+
+    // Record a rejected handshake from `ip`, banning it once there are
+    // `ban_after` within FAILURE_WINDOW
+    pub fn failure(&self, ip: IpAddr) {
+        self.failure_at(ip, Instant::now())
+    }
+
+    fn failure_at(&self, ip: IpAddr, now: Instant) {
+        if self.ban_after == 0 {
+            return;
+        }
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+        let failures = state.failures.entry(ip).or_default();
+        expire(failures, now, FAILURE_WINDOW);
+        failures.push_back(now);
+        if failures.len() >= self.ban_after {
+            state.failures.remove(&ip);
+            state.banned.insert(ip, Some(now + self.ban_time));
+            warn!(
+                "Banning {} for {}s after {} failed handshakes",
+                ip,
+                self.ban_time.as_secs(),
+                self.ban_after
+            );
+        }
+    }
+
+    // Ban `ip` for `time`, or until unbanned
+    pub fn ban(&self, ip: IpAddr, time: Option<Duration>) {
+        let ip = ip.to_canonical();
+        info!("Banning {}", ip);
+        let until = time.map(|t| Instant::now() + t);
+        self.state.lock().unwrap().banned.insert(ip, until);
+    }
+
+    // Lift a ban; false if `ip` was not banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(&ip);
+        let was_banned = state.banned.remove(&ip).is_some();
+        if was_banned {
+            info!("Unbanned {}", ip);
+        }
+        was_banned
+    }
+
+    // One line per banned source, for `ctl bans`
+    pub fn report(&self) -> String {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        let mut bans: Vec<_> = state.banned.iter().collect();
+        bans.sort();
+        let mut out = String::new();
+        for (ip, until) in bans {
+            match until {
+                Some(until) => out += &format!("{} {}s\n", ip, (*until - now).as_secs()),
+                None => out += &format!("{} manual\n", ip),
+            }
+        }
+        if out.is_empty() {
+            out = "no bans\n".to_string();
+        }
+        out
+    }
+
+    // The same as a JSON array; `remaining` is null for manual bans
+    pub fn json(&self) -> String {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        let mut bans: Vec<_> = state.banned.iter().collect();
+        bans.sort();
+        json::array(bans.into_iter().map(|(ip, until)| {
+            Object::new()
+                .str("addr", &ip.to_string())
+                .opt_num("remaining", until.map(|until| (until - now).as_secs()))
+                .build()
+        }))
+    }
+}
+
+impl State {
+    fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        match self.banned.get(&ip) {
+            Some(Some(until)) => *until > now,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.banned
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        for (times, window) in [
+            (&mut self.attempts, RATE_WINDOW),
+            (&mut self.failures, FAILURE_WINDOW),
+        ] {
+            times.retain(|_, times| {
+                expire(times, now, window);
+                !times.is_empty()
+            });
+        }
+        self.last_prune = now;
+    }
+}
+
+// Drop timestamps older than `window`
+fn expire(times: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while times.front().is_some_and(|&t| now - t >= window) {
+        times.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 7));
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn rate_counts_over_a_rolling_window() {
+        let bans = BanList::new(2, 0, secs(300));
+        let start = Instant::now();
+        assert!(bans.admit_at(SOURCE, start));
+        assert!(bans.admit_at(SOURCE, start + secs(30)));
+        assert!(!bans.admit_at(SOURCE, start + secs(59)));
+        // The first attempt has left the window, the second not yet
+        assert!(bans.admit_at(SOURCE, start + secs(60)));
+        assert!(!bans.admit_at(SOURCE, start + secs(61)));
+        assert!(bans.admit_at(SOURCE, start + secs(90)));
+        // Other sources have their own count
+        assert!(bans.admit_at("192.0.2.8".parse().unwrap(), start + secs(90)));
+    }
+
+    #[test]
+    fn failures_count_over_a_rolling_window() {
+        let bans = BanList::new(0, 3, secs(300));
+        let start = Instant::now();
+        bans.failure_at(SOURCE, start);
+        bans.failure_at(SOURCE, start + secs(1));
+        // The first two have expired by now
+        bans.failure_at(SOURCE, start + secs(601));
+        assert!(bans.admit_at(SOURCE, start + secs(602)));
+        bans.failure_at(SOURCE, start + secs(603));
+        assert!(bans.admit_at(SOURCE, start + secs(604)));
+        bans.failure_at(SOURCE, start + secs(605));
+        assert!(!bans.admit_at(SOURCE, start + secs(606)));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let bans = BanList::new(0, 2, secs(30));
+        let start = Instant::now();
+        bans.failure_at(SOURCE, start);
+        assert!(bans.admit_at(SOURCE, start + secs(1)));
+        bans.failure_at(SOURCE, start + secs(1));
+        assert!(!bans.admit_at(SOURCE, start + secs(2)));
+        assert!(!bans.admit_at(SOURCE, start + secs(30)));
+        assert!(bans.admit_at(SOURCE, start + secs(31)));
+        // Failures before the ban do not count towards the next one
+        bans.failure_at(SOURCE, start + secs(32));
+        assert!(bans.admit_at(SOURCE, start + secs(33)));
+    }
+
+    #[test]
+    fn ban_after_zero_never_bans() {
+        let bans = BanList::new(0, 0, secs(30));
+        let start = Instant::now();
+        for i in 0..100 {
+            bans.failure_at(SOURCE, start + secs(i));
+        }
+        assert!(bans.admit_at(SOURCE, start + secs(100)));
+        assert_eq!(bans.report(), "no bans\n");
+    }
+
+    #[test]
+    fn manual_bans_last_until_lifted() {
+        let bans = BanList::new(0, 0, secs(30));
+        // An IPv4-mapped IPv6 address is the same source
+        bans.ban("::ffff:192.0.2.7".parse().unwrap(), None);
+        assert!(!bans.admit_at(SOURCE, Instant::now() + secs(86400)));
+        assert_eq!(bans.report(), "192.0.2.7 manual\n");
+        assert!(bans.unban(SOURCE));
+        assert!(!bans.unban(SOURCE));
+        assert!(bans.admit(SOURCE));
+    }
+}
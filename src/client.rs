@@ -0,0 +1,913 @@
+use std::io::{self, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info, warn};
+
+use crate::config::{Config, Endpoint, Prefer, ServerOrder, SharedConfig, Tos, Tuning, NO_TUN};
+use crate::control::{self, Context};
+use crate::dns::Forwarder;
+use crate::error::{Result, VpnError};
+use crate::filter::Filter;
+use crate::forward::{Forward, Streams};
+use crate::greeting::{self, Greeting};
+use crate::knock;
+use crate::mux::{self, Message, Requests};
+use crate::packet;
+use crate::protocol::{
+    parse_handshake_response, peer_mtu, read_line, write_line, Channel, Framing, HandshakeRequest,
+    Negotiated, Options,
+};
+use crate::queue::SendQueue;
+use crate::relay::{self, Role};
+use crate::sandbox;
+use crate::socket;
+use crate::state::{State, StateReporter};
+use crate::status::Status;
+use crate::transport::{Marking, Stream};
+use crate::tun::TunDevice;
+use crate::watchdog::Watchdog;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Keepalive intervals without a frame from the server before reconnecting
+const KEEPALIVE_MISSES: u32 = 3;
+
+// The current session as seen by the sender thread
+struct Connection {
+    stream: Stream,
+    framing: Framing,
+    tos: Tos,
+    // Packet size TCP SYNs are clamped for, if clamping is on
+    mss_limit: Option<usize>,
+}
+
+// None while disconnected; packets read from the TUN are dropped then
+type CurrentStream = Arc<Mutex<Option<Arc<Connection>>>>;
+
+pub fn client_mode(config: SharedConfig) -> Result<()> {
+    info!("Starting client mode.");
+    let status = Arc::new(Status::new("client"));
+    let settings = config.read().unwrap().clone();
+    let queue = Arc::new(SendQueue::new(settings.tuning.queue_depth));
+    let requests = Arc::new(Requests::new(queue.clone()));
+    let filter = Arc::new(Filter::new(&settings.block));
+    let _control = control::start(Arc::new(Context {
+        config: config.clone(),
+        status: status.clone(),
+        sessions: None,
+        bans: None,
+        peer: Some(requests.clone()),
+        filter: Some(filter.clone()),
+    }));
+
+    let reporter = StateReporter::new(
+        settings.status_file.clone(),
+        settings.state_exec.clone(),
+        &settings.tun_ip,
+        status.clone(),
+    );
+
+    let mut request = HandshakeRequest::parse(&settings.tun_ip)
+        .map_err(|_| VpnError::Config(format!("Invalid client address: {}", settings.tun_ip)))?;
+
+    for (key, value) in &settings.credentials {
+        request.options.set(key, value);
+    }
+    settings.framing().offer(&mut request.options);
+    request.options.set("mtu", settings.mtu);
+
+    let watchdog = match settings.watchdog {
+        Some(target) => match Watchdog::new(request.addr, target) {
+            Some(watchdog) => Some(Arc::new(watchdog)),
+            None => {
+                return Err(VpnError::Config(format!(
+                    "watchdog {} is not of the same family as {}",
+                    target, settings.tun_ip
+                )))
+            }
+        },
+        None => None,
+    };
+
+    let streams = Arc::new(Streams::new(settings.mtu));
+    for forward in &settings.forward {
+        let listener = TcpListener::bind(forward.bind)
+            .map_err(|e| VpnError::Config(format!("Cannot listen on {}: {}", forward.bind, e)))?;
+        info!("Forwarding {}", forward);
+        spawn_forward(
+            forward.clone(),
+            listener,
+            requests.clone(),
+            queue.clone(),
+            streams.clone(),
+        );
+    }
+
+    let current: CurrentStream = Arc::new(Mutex::new(None));
+    let mut started = false;
+    let mut tun = None;
+    let mut routes = None;
+    let mut dns: Option<Arc<Forwarder>> = None;
+    let mut tun_mtu = 0;
+    // False for an inherited TUN, whose configuration is not ours to change
+    let mut manage_tun = false;
+    let reconnect = settings.reconnect;
+    let min_delay = Duration::from_secs(reconnect);
+    let mut delay = min_delay;
+    let endpoints = settings.endpoints();
+    let mut last_good = None;
+
+    let error = loop {
+        let result = connect_any(&settings, &endpoints, &mut last_good, &request);
+
+        match result {
+            Ok((stream, negotiated, pushed)) => {
+                // The TUN is created once, after the first successful
+                // handshake, unless we only forward ports
+                let first = !started;
+                started = true;
+                if first && settings.tun_name != NO_TUN {
+                    let (t, owned) = settings.open_tun(negotiated.mtu)?;
+                    status.tun_up.store(true, Ordering::Relaxed);
+                    spawn_tun_reader(
+                        t.try_clone()?,
+                        queue.clone(),
+                        status.clone(),
+                        filter.clone(),
+                        settings.mtu,
+                    );
+                    routes = Some(PushedRoutes::new(t.try_clone()?));
+                    tun_mtu = negotiated.mtu;
+                    manage_tun = owned;
+                    // An inherited TUN keeps its own MTU
+                    if !owned {
+                        match t.mtu() {
+                            Ok(mtu) if mtu < negotiated.mtu => warn!(
+                                "{} has MTU {}, below the negotiated {}; larger packets will be dropped.",
+                                t.name(),
+                                mtu,
+                                negotiated.mtu
+                            ),
+                            Ok(_) => {}
+                            Err(e) => debug!("{}", e),
+                        }
+                    }
+                    tun = Some(t);
+                }
+                // Started with the TUN, whose address it may listen on
+                if let (true, Some(listen)) = (first, settings.dns_listen) {
+                    let queue = queue.clone();
+                    let forwarder =
+                        Forwarder::start(listen, request.addr, move |packet| queue.push(&packet))
+                            .map_err(|e| {
+                            VpnError::Config(format!("Cannot listen on {}: {}", listen, e))
+                        })?;
+                    dns = Some(forwarder);
+                }
+                if let Some(dns) = &dns {
+                    dns.set_resolvers(&pushed_resolvers(&pushed));
+                }
+                if first {
+                    // Without a TUN, health only waits for the session
+                    if tun.is_none() {
+                        status.tun_up.store(true, Ordering::Relaxed);
+                    }
+                    spawn_sender(current.clone(), queue.clone(), settings.tuning);
+                    if settings.keepalive > 0 {
+                        spawn_keepalive(
+                            Duration::from_secs(settings.keepalive),
+                            current.clone(),
+                            requests.clone(),
+                            status.clone(),
+                        );
+                    }
+                    if let Some(watchdog) = &watchdog {
+                        spawn_watchdog(
+                            watchdog.clone(),
+                            &settings,
+                            current.clone(),
+                            queue.clone(),
+                            status.clone(),
+                        );
+                    }
+                }
+                if let (Some(tun), Some(routes)) = (tun.as_mut(), &routes) {
+                    if manage_tun && negotiated.mtu != tun_mtu {
+                        tun.set_mtu(negotiated.mtu)?;
+                        tun_mtu = negotiated.mtu;
+                    }
+                    // Routes and DNS need ip(8), which the sandbox no longer
+                    // allows after the first session
+                    if manage_tun && (first || !settings.sandbox) {
+                        apply_pushed(tun, routes, &pushed, dns.is_some());
+                    }
+                }
+                streams.set_mtu(negotiated.mtu);
+                if first && settings.sandbox {
+                    sandbox::apply()?;
+                }
+                let session = SessionContext {
+                    settings: &settings,
+                    current: &current,
+                    queue: &queue,
+                    requests: &requests,
+                    status: &status,
+                    watchdog: watchdog.as_deref(),
+                    filter: &filter,
+                    streams: &streams,
+                    dns: dns.as_deref(),
+                    apply_pushed: manage_tun && !settings.sandbox,
+                    routes: routes.as_ref(),
+                };
+                reporter.set(State::Connected, stream.peer().ok().map(|p| p.to_string()));
+                let ended = run_session(stream, negotiated, tun.as_mut(), &session);
+                // Whatever was queued for the old connection is stale now
+                queue.clear();
+                delay = min_delay;
+                // Without reconnecting, why the session ended is what we
+                // exit with
+                if reconnect == 0 {
+                    break ended;
+                }
+            }
+            Err(e) if reconnect > 0 => {
+                error!("Connection to server failed: {}", e);
+                status.event(format!("Connection to server failed: {}", e));
+            }
+            Err(e) => break e,
+        }
+
+        reporter.set(State::Reconnecting, None);
+        info!("Reconnecting in {}s...", delay.as_secs());
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY.max(min_delay));
+    };
+
+    info!("Client shutting down.");
+    if let Some(tun) = tun {
+        tun.close();
+    }
+    Err(error)
+}
+
+// Resolve the server name and return its addresses, preferred family first.
+// Called on every (re)connect so DNS changes are picked up.
+pub fn resolve(host: &str, port: &str, prefer: Prefer) -> Result<Vec<SocketAddr>> {
+    let port: u16 = port
+        .parse()
+        .map_err(|_| VpnError::Config(format!("Invalid port: {}", port)))?;
+    let mut addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| {
+            VpnError::Transport(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("cannot resolve {}: {}", host, e),
+            ))
+        })?
+        .collect();
+    match prefer {
+        Prefer::Any => {}
+        Prefer::Ipv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+        Prefer::Ipv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+    }
+    if addrs.is_empty() {
+        return Err(VpnError::Config(format!("{} did not resolve", host)));
+    }
+    Ok(addrs)
+}
+
+// Try the configured endpoints until one accepts our handshake.
+// The last endpoint that worked is tried first next time.
+// With proxy_command the command is the only "endpoint".
+fn connect_any(
+    settings: &Config,
+    endpoints: &[Endpoint],
+    last_good: &mut Option<usize>,
+    request: &HandshakeRequest,
+) -> Result<(Stream, Negotiated, Options)> {
+    if let Some(command) = &settings.proxy_command {
+        let mut stream = Stream::command(command)?;
+        return match handshake(&mut stream, None, request, settings) {
+            Ok((negotiated, reply)) => Ok((stream, negotiated, reply)),
+            Err(e) => {
+                stream.shutdown();
+                Err(e)
+            }
+        };
+    }
+
+    let mut order: Vec<usize> = (0..endpoints.len()).collect();
+    if settings.server_order == ServerOrder::Random {
+        shuffle(&mut order);
+    }
+    if let Some(i) = *last_good {
+        order.retain(|&j| j != i);
+        order.insert(0, i);
+    }
+
+    let mut last_err = None;
+    for i in order {
+        let endpoint = &endpoints[i];
+        let result = connect(settings, endpoint).and_then(|mut stream| {
+            if let Some(id) = &settings.relay_id {
+                relay::join(&mut stream, id, Role::Client)?;
+            }
+            let (negotiated, reply) =
+                handshake(&mut stream, Some(&endpoint.host), request, settings)?;
+            Ok((stream, negotiated, reply))
+        });
+        match result {
+            Ok(connection) => {
+                *last_good = Some(i);
+                return Ok(connection);
+            }
+            Err(e) => {
+                if endpoints.len() > 1 {
+                    warn!("Server {} failed: {}", endpoint, e);
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+// Fisher-Yates shuffle seeded from the clock; good enough to spread load
+fn shuffle(items: &mut [usize]) {
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+        | 1;
+    for i in (1..items.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+}
+
+// Connect to the first reachable address of an endpoint
+fn connect(settings: &Config, endpoint: &Endpoint) -> Result<Stream> {
+    if let Some(path) = endpoint.unix_path() {
+        info!("Connecting to {}...", endpoint);
+        let stream = Stream::unix(path)?;
+        info!("Connected to server at {}.", endpoint);
+        return Ok(stream);
+    }
+    let (host, port) = (&endpoint.host, &endpoint.port);
+    info!("Connecting to {}:{}...", host, port);
+    let addrs = resolve(host, port, settings.prefer)?;
+    debug!("{} resolved to {:?}", host, addrs);
+    let mut last_err = None;
+    for addr in addrs {
+        if !settings.knock.is_empty() {
+            // An address of the other family may be unusable from here
+            let key = settings.knock_key.as_deref();
+            if let Err(e) = knock::knock(addr.ip(), &settings.knock, key, settings.bind_addr) {
+                warn!("Knocking on {} failed: {}", addr.ip(), e);
+                last_err = Some(e);
+                continue;
+            }
+        }
+        match socket::connect_tcp(
+            &addr,
+            settings.bind_dev.as_deref(),
+            settings.bind_addr,
+            CONNECT_TIMEOUT,
+        ) {
+            Ok(stream) => {
+                info!("Connected to server at {}.", addr);
+                let stream = Stream::from(stream);
+                if let Some(keepalive) = settings.kernel_keepalive() {
+                    if let Err(e) = stream.set_keepalive(&keepalive) {
+                        warn!("Cannot enable TCP keepalive: {}", e);
+                    }
+                }
+                return Ok(stream);
+            }
+            Err(e) => {
+                warn!("Connecting to {} failed: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap().into())
+}
+
+// Send our request and return what the server accepted, along with the
+// full reply for the options it pushes. `host` is the server's name, if
+// it has one.
+fn handshake(
+    stream: &mut Stream,
+    host: Option<&str>,
+    request: &HandshakeRequest,
+    settings: &Config,
+) -> Result<(Negotiated, Options)> {
+    info!("Starting handshake with server...");
+    let timeout = settings.handshake_timeout;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    if settings.greeting == Greeting::Tls {
+        let host = settings.sni.as_deref().or(host);
+        greeting::client(stream, host, deadline).map_err(|e| match e.io_kind() {
+            Some(io::ErrorKind::TimedOut) => {
+                VpnError::Handshake(format!("No answer to the TLS greeting within {}s", timeout))
+            }
+            _ => e,
+        })?;
+    }
+    write_line(stream, &request.encode())?;
+
+    let line = read_line(&mut stream.until(deadline)).map_err(|e| match e.io_kind() {
+        Some(io::ErrorKind::TimedOut) => {
+            VpnError::Handshake(format!("No reply from the server within {}s", timeout))
+        }
+        _ => e,
+    })?;
+    stream.set_read_timeout(None)?;
+    info!("Server response: {}", line.trim_end());
+    let reply = parse_handshake_response(&line)?;
+    let mtu = peer_mtu(&reply).min(settings.mtu);
+    let framing = settings.framing().accepted(&reply)?.capped(mtu);
+    debug!(
+        "Using frame version {} (max {} bytes, pad={}), MTU {}.",
+        framing.version,
+        framing.max_len,
+        framing.pad.name(),
+        mtu
+    );
+    Ok((Negotiated { framing, mtu }, reply))
+}
+
+// Apply the per-client settings the server pushed in its reply. Failures
+// are logged; the tunnel itself works without them.
+fn apply_pushed<T: TunDevice>(tun: &T, routes: &PushedRoutes<T>, reply: &Options, forwarder: bool) {
+    for (key, value) in reply.iter() {
+        let result = match key {
+            // Negotiated above
+            "frame" | "max_frame" | "mux" | "pad" | "mtu" => continue,
+            // Taken by the DNS forwarder instead of the system
+            "dns" if forwarder => continue,
+            "ip6" => tun.add_address(value),
+            "route" => value.split(',').try_for_each(|cidr| routes.add(cidr)),
+            "dns" if !tun.features().dns => {
+                info!(
+                    "Ignoring pushed dns={}: not supported on this platform",
+                    value
+                );
+                continue;
+            }
+            "dns" => match value
+                .split(',')
+                .map(str::parse)
+                .collect::<std::result::Result<Vec<_>, _>>()
+            {
+                Ok(servers) => tun.set_dns(&servers),
+                Err(_) => Err(VpnError::Handshake(format!("Invalid dns: {}", value))),
+            },
+            _ => {
+                info!("Ignoring pushed option {}={}", key, value);
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            warn!("Cannot apply pushed {}: {}", key, e);
+        }
+    }
+}
+
+// Routes added through the TUN for the server. They are removed when the
+// client stops rather than left pointing at a tunnel that is gone.
+struct PushedRoutes<T: TunDevice> {
+    tun: T,
+    added: Mutex<Vec<String>>,
+}
+
+impl<T: TunDevice> PushedRoutes<T> {
+    fn new(tun: T) -> PushedRoutes<T> {
+        PushedRoutes {
+            tun,
+            added: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn add(&self, cidr: &str) -> Result<()> {
+        info!("Adding pushed route {} via {}", cidr, self.tun.name());
+        self.tun.add_route(cidr)?;
+        let mut added = self.added.lock().unwrap();
+        if !added.iter().any(|route| route == cidr) {
+            added.push(cidr.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl<T: TunDevice> Drop for PushedRoutes<T> {
+    fn drop(&mut self) {
+        for cidr in self.added.get_mut().unwrap().drain(..).rev() {
+            info!("Removing pushed route {} via {}", cidr, self.tun.name());
+            if let Err(e) = self.tun.del_route(&cidr) {
+                warn!("Cannot remove route {}: {}", cidr, e);
+            }
+        }
+    }
+}
+
+// The resolvers in a pushed `dns` option
+fn pushed_resolvers(options: &Options) -> Vec<IpAddr> {
+    options
+        .get("dns")
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.parse().ok())
+        .collect()
+}
+
+// Thread: TUN -> Client queue, for the lifetime of the process
+fn spawn_tun_reader<T: TunDevice>(
+    mut tun: T,
+    queue: Arc<SendQueue>,
+    status: Arc<Status>,
+    filter: Arc<Filter>,
+    mtu: usize,
+) {
+    thread::spawn(move || {
+        info!("TUN->Server forwarding thread started.");
+        let mut buf = vec![0u8; mtu];
+        loop {
+            let n = match tun.next_packet(&mut buf, &status.tun_up) {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Error reading from TUN: {}", e);
+                    status.tun_up.store(false, Ordering::Relaxed);
+                    break;
+                }
+            };
+            if !filter.allows(&buf[..n]) {
+                continue;
+            }
+            if !status.session_established.load(Ordering::Relaxed) {
+                debug!("Not connected; dropping {} bytes from TUN.", n);
+            } else if !queue.push(&buf[..n]) {
+                debug!("Send queue full; dropping {} bytes from TUN.", n);
+            }
+        }
+        info!("TUN->Server forwarding thread ended.");
+    });
+}
+
+// Thread: Client queue -> Server, interactive packets first. Frames are
+// gathered into one write while more are queued.
+fn spawn_sender(current: CurrentStream, queue: Arc<SendQueue>, tuning: Tuning) {
+    thread::spawn(move || {
+        let mut batch = Vec::new();
+        // The connection being sent on, with our own handle and marking for
+        // it, so `current` is not locked while waiting for frames to batch
+        let mut sending: Option<(Arc<Connection>, Stream, Marking)> = None;
+        while let Some(mut frame) = queue.pop() {
+            let Some(conn) = current.lock().unwrap().clone() else {
+                sending = None;
+                continue;
+            };
+            if !sending
+                .as_ref()
+                .is_some_and(|(c, ..)| Arc::ptr_eq(c, &conn))
+            {
+                match conn.stream.try_clone() {
+                    Ok(stream) => sending = Some((conn.clone(), stream, Marking::new(conn.tos))),
+                    Err(e) => {
+                        error!("Cannot send to server: {}", e);
+                        conn.stream.shutdown();
+                        continue;
+                    }
+                }
+            }
+            let Some((conn, stream, marking)) = sending.as_mut() else {
+                continue;
+            };
+            let result = loop {
+                let (channel, mut packet) = frame;
+                let sent = match channel {
+                    // Requests wait for a muxed connection, but replies may
+                    // still be queued after a reconnect without one
+                    Channel::Control | Channel::Stream if !conn.framing.mux => Ok(()),
+                    Channel::Control | Channel::Stream => {
+                        conn.framing.send_on(&mut batch, channel, &packet)
+                    }
+                    Channel::Data => {
+                        if let Some(mtu) = conn.mss_limit {
+                            packet::clamp_mss(&mut packet, mtu);
+                        }
+                        if marking.changes(&packet) && !batch.is_empty() {
+                            if let Err(e) = stream.write_all(&batch) {
+                                break Err(e.into());
+                            }
+                            batch.clear();
+                        }
+                        marking.mark(stream, &packet);
+                        conn.framing.send(&mut batch, &packet)
+                    }
+                };
+                if let Err(e) = sent {
+                    break Err(e);
+                }
+                match queue.pop_for_batch(batch.len(), &tuning) {
+                    Some(next) => frame = next,
+                    None => break stream.write_all(&batch).map_err(VpnError::from),
+                }
+            };
+            batch.clear();
+            if let Err(e) = result {
+                error!("Error sending to server: {}", e);
+                stream.shutdown();
+                let mut current = current.lock().unwrap();
+                // Unless a new session has started meanwhile
+                if current.as_ref().is_some_and(|c| Arc::ptr_eq(c, conn)) {
+                    *current = None;
+                }
+                sending = None;
+            }
+        }
+    });
+}
+
+// Thread: ping the server on the control channel when it has been quiet for
+// `interval`, and drop the connection when nothing at all came for
+// KEEPALIVE_MISSES times that. A connection whose far end is gone (server
+// rebooted, NAT state lost) otherwise leaves the reader waiting until the
+// kernel keepalive gives up, or forever with that off. Sessions without a
+// control channel (mux=0) are left to the kernel.
+fn spawn_keepalive(
+    interval: Duration,
+    current: CurrentStream,
+    requests: Arc<Requests>,
+    status: Arc<Status>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if !status.session_established.load(Ordering::Relaxed) || !requests.is_connected() {
+            continue;
+        }
+        let quiet = status.last_rx_age().unwrap_or_default();
+        if quiet < interval {
+            continue;
+        }
+        if quiet >= interval * KEEPALIVE_MISSES {
+            warn!(
+                "Nothing from the server for {}s; reconnecting.",
+                quiet.as_secs()
+            );
+            status.event("Keepalive: server not answering".to_string());
+            if let Some(conn) = current.lock().unwrap().as_ref() {
+                conn.stream.shutdown();
+            }
+            continue;
+        }
+        match requests.request("PING", "") {
+            Ok((_, rtt)) => debug!("Keepalive answered in {}ms", rtt.as_millis()),
+            Err(e) => debug!("Keepalive: {}", e),
+        }
+    });
+}
+
+// Thread: ping the watchdog address through the tunnel and drop the
+// connection when the pings go unanswered
+fn spawn_watchdog(
+    watchdog: Arc<Watchdog>,
+    settings: &Config,
+    current: CurrentStream,
+    queue: Arc<SendQueue>,
+    status: Arc<Status>,
+) {
+    let interval = Duration::from_secs(settings.watchdog_interval);
+    let failures = settings.watchdog_failures;
+    thread::spawn(move || {
+        let mut missed = 0;
+        loop {
+            thread::sleep(interval);
+            if !status.session_established.load(Ordering::Relaxed) {
+                missed = 0;
+                continue;
+            }
+            let (probe, answered) = watchdog.probe();
+            missed = if answered { 0 } else { missed + 1 };
+            if missed >= failures {
+                warn!(
+                    "No reply from {} to {} pings through the tunnel; reconnecting.",
+                    watchdog.target(),
+                    missed
+                );
+                status.event(format!("Watchdog: {} unreachable", watchdog.target()));
+                if let Some(conn) = current.lock().unwrap().as_ref() {
+                    conn.stream.shutdown();
+                }
+                missed = 0;
+                continue;
+            }
+            if !queue.push(&probe) {
+                debug!("Send queue full; dropping watchdog ping.");
+            }
+        }
+    });
+}
+
+// Thread: accept connections on a forwarded port and have the server open
+// each to the target
+fn spawn_forward(
+    forward: Forward,
+    listener: TcpListener,
+    requests: Arc<Requests>,
+    queue: Arc<SendQueue>,
+    streams: Arc<Streams>,
+) {
+    let forward = Arc::new(forward);
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Accepting on {} failed: {}", forward.bind, e);
+                    continue;
+                }
+            };
+            let (forward, requests) = (forward.clone(), requests.clone());
+            let (queue, streams) = (queue.clone(), streams.clone());
+            thread::spawn(move || {
+                let id = streams.next_id();
+                if let Err(e) = streams.add(id, conn) {
+                    warn!("Cannot forward to {}: {}", forward.target(), e);
+                    return;
+                }
+                let args = format!("{} {}", id, forward.target());
+                match requests.request("OPEN", &args) {
+                    Ok((reply, _)) if reply == "ok" => {
+                        debug!("Forwarded connection {} to {} open", id, forward.target());
+                        streams.start(id, move |frame| queue.push_stream(frame));
+                    }
+                    Ok((reply, _)) => {
+                        let reason = reply.strip_prefix("error ").unwrap_or(&reply);
+                        warn!("Server cannot forward to {}: {}", forward.target(), reason);
+                        streams.remove(id);
+                    }
+                    Err(e) => {
+                        warn!("Cannot forward to {}: {}", forward.target(), e);
+                        streams.remove(id);
+                    }
+                }
+            });
+        }
+    });
+}
+
+// What run_session needs besides the connection and the TUN
+struct SessionContext<'a, T: TunDevice> {
+    settings: &'a Config,
+    current: &'a CurrentStream,
+    queue: &'a SendQueue,
+    requests: &'a Requests,
+    status: &'a Status,
+    watchdog: Option<&'a Watchdog>,
+    filter: &'a Filter,
+    streams: &'a Streams,
+    dns: Option<&'a Forwarder>,
+    // Whether options pushed mid-session may be applied
+    apply_pushed: bool,
+    routes: Option<&'a PushedRoutes<T>>,
+}
+
+// Main: Server -> Client -> TUN, until the connection ends. Returns why it
+// ended.
+fn run_session<T: TunDevice>(
+    mut stream: Stream,
+    negotiated: Negotiated,
+    mut tun: Option<&mut T>,
+    ctx: &SessionContext<T>,
+) -> VpnError {
+    let SessionContext {
+        settings,
+        current,
+        status,
+        ..
+    } = *ctx;
+    let framing = negotiated.framing;
+    let sender = match stream.try_clone() {
+        Ok(sender) => sender,
+        Err(e) => return e.into(),
+    };
+    *current.lock().unwrap() = Some(Arc::new(Connection {
+        stream: sender,
+        framing,
+        tos: settings.tos,
+        mss_limit: settings.mssfix.limit(negotiated.mtu),
+    }));
+    status.session_established.store(true, Ordering::Relaxed);
+    status.event("Session established".to_string());
+    ctx.requests.set_connected(framing.mux);
+    // The handshake reply was the last we heard, not whatever came on the
+    // previous connection
+    status.touch_rx();
+    if let Some(watchdog) = ctx.watchdog {
+        watchdog.reset();
+    }
+    info!("Handshake complete. Start forwarding packets.");
+
+    info!("Server->TUN forwarding loop started.");
+    let started = Instant::now();
+    let (mut rx_packets, mut rx_bytes) = (0u64, 0u64);
+    let mut buf = vec![0u8; negotiated.mtu];
+    let mut reader = BufReader::with_capacity(settings.tuning.read_buffer, &mut stream);
+    let ended = loop {
+        let n = match framing.recv_frame(&mut reader, &mut buf) {
+            Ok((Channel::Data, n)) => n,
+            Ok((Channel::Control, n)) => {
+                status.touch_rx();
+                match Message::parse(&buf[..n]) {
+                    Some(Message::Request { verb, token, .. }) => {
+                        let stats = || {
+                            format!(
+                                "uptime={} rx_packets={} rx_bytes={}",
+                                started.elapsed().as_secs(),
+                                rx_packets,
+                                rx_bytes
+                            )
+                        };
+                        ctx.queue.push_control(mux::answer(verb, token, stats));
+                    }
+                    Some(Message::Reply { token, text }) => ctx.requests.resolve(token, text),
+                    Some(Message::Push(options)) => {
+                        match parse_handshake_response(&format!("OK{}", options)) {
+                            Ok(pushed) => {
+                                if let Some(dns) = ctx.dns {
+                                    dns.set_resolvers(&pushed_resolvers(&pushed));
+                                }
+                                match (tun.as_deref(), ctx.routes) {
+                                    (Some(tun), Some(routes)) if ctx.apply_pushed => {
+                                        apply_pushed(tun, routes, &pushed, ctx.dns.is_some())
+                                    }
+                                    _ => info!("Not applying pushed options{}", options),
+                                }
+                            }
+                            Err(e) => warn!("Invalid options pushed by server: {}", e),
+                        }
+                    }
+                    Some(Message::Close(reason)) => {
+                        warn!("Server is closing the session: {}", reason);
+                        status.event(format!("Session closed by server: {}", reason));
+                        break VpnError::closed(reason);
+                    }
+                    None => debug!("Ignoring malformed control message."),
+                }
+                continue;
+            }
+            Ok((Channel::Stream, n)) => {
+                status.touch_rx();
+                ctx.streams.deliver(&buf[..n]);
+                continue;
+            }
+            Err(e) => {
+                error!("Error receiving from server: {}", e);
+                break match e {
+                    VpnError::Transport(e) => VpnError::Lost(e.to_string()),
+                    e => VpnError::Lost(e.to_string()),
+                };
+            }
+        };
+
+        if n == 0 {
+            info!("Received zero-length packet. Possibly connection closed.");
+            break VpnError::Lost("empty packet from the server".to_string());
+        }
+        status.touch_rx();
+        rx_packets += 1;
+        rx_bytes += n as u64;
+        if ctx.watchdog.is_some_and(|w| w.take_reply(&buf[..n]))
+            || ctx.dns.is_some_and(|d| d.take_reply(&buf[..n]))
+            || !ctx.filter.allows(&buf[..n])
+        {
+            continue;
+        }
+
+        let Some(tun) = tun.as_mut() else {
+            debug!("No TUN; dropping {} bytes from the server.", n);
+            continue;
+        };
+        if let Err(e) = tun.deliver(&buf[..n], &status.tun_up) {
+            error!("Error writing to TUN: {}", e);
+            break e;
+        }
+    };
+
+    info!("Server->TUN forwarding loop ended.");
+    ctx.requests.set_connected(false);
+    status.session_established.store(false, Ordering::Relaxed);
+    status.event("Session ended".to_string());
+    // Shut down first so a blocked send in the TUN->Server thread returns
+    stream.shutdown();
+    current.lock().unwrap().take();
+    ctx.streams.close_all();
+    ended
+}
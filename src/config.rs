@@ -0,0 +1,1380 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::affinity;
+use crate::control;
+use crate::error::{Result, VpnError};
+use crate::expose::Mapping;
+use crate::filter::Rule;
+use crate::forward::Forward;
+use crate::greeting::Greeting;
+use crate::protocol::{
+    cidr_contains, parse_cidr, Framing, Options, Padding, DEFAULT_MTU, MAX_FRAME_V1, MAX_MTU,
+    PAD_LEN,
+};
+use crate::socket::Keepalive;
+use crate::transport::UNIX_PREFIX;
+use crate::tun::{self, TunDevice, TunInterface};
+
+// Settings shared by the server and client.
+//
+// The file format is a flat list of `key = value` lines; `#` and `;` start
+// comments. For the server `addr` is the bind address, for the client it is
+// the address of the server to connect to.
+//
+// Every key can also be set through a `RUST_VPN_<KEY>` environment variable
+// (e.g. `RUST_VPN_TUN_NAME`). Precedence is: command line > environment >
+// config file.
+//
+// Secrets (web_password, knock_key and the values in credentials) may be
+// given as `file:/path` or `env:NAME` instead, so they need not sit in the
+// config file itself, or sealed with a passphrase (`vpn secret seal`); see
+// `secret`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub path: Option<PathBuf>,
+    pub mode: String,
+    pub addr: String,
+    pub port: String,
+    pub tun_ip: String,
+    pub tun_name: String,
+    // Start even if the tun_ip subnet overlaps a local route, with a warning
+    pub allow_overlap: bool,
+    // Server: IPv6 address of the TUN next to tun_ip (e.g. fd00:9::1/64);
+    // clients get an address in its prefix (see client_ip6)
+    pub tun_ip6: Option<String>,
+    // Server: push a default IPv6 route to clients that get an address, as
+    // two halves so the client's own default route stays in place
+    pub ip6_default_route: bool,
+    pub log_level: Option<LevelFilter>,
+    // Tunnel addresses clients may request (empty = any)
+    pub allow: Vec<(IpAddr, u8)>,
+    // Path of the local control socket (default control::DEFAULT_SOCKET)
+    pub ctl_socket: Option<PathBuf>,
+    // Address for the HTTP /healthz endpoint (disabled when unset)
+    pub healthz: Option<String>,
+    // Address for the read-only web dashboard (disabled when unset)
+    pub web: Option<String>,
+    // Password for the web dashboard (HTTP basic auth; none when unset)
+    pub web_password: Option<String>,
+    // Client: seconds to wait before reconnecting (0 = exit when the session ends)
+    pub reconnect: u64,
+    // Client: address family to try first when the server name resolves to both
+    pub prefer: Prefer,
+    // Client: interface the outer connection is bound to (SO_BINDTODEVICE)
+    pub bind_dev: Option<String>,
+    // Client: local source address for the outer connection
+    pub bind_addr: Option<IpAddr>,
+    // Server: addr:port pairs to listen on (default: addr and port above)
+    pub listen: Vec<String>,
+    // Client: what a connection starts with, to look like TLS (see greeting)
+    pub greeting: Greeting,
+    // Server name in the TLS greeting (server: the one that marks clients
+    // on a shared port; client: default the server's host name)
+    pub sni: Option<String>,
+    // Server: web server (host:port) to pass connections on to that are not
+    // from clients, to share the port with it
+    pub share: Option<String>,
+    // Client: server endpoints to try (default: addr and port above)
+    pub servers: Vec<Endpoint>,
+    // Client: order in which `servers` are tried
+    pub server_order: ServerOrder,
+    // Largest frame accepted from the peer; above 65535 needs frame version 2
+    pub max_frame: usize,
+    // Offer/accept a control channel next to the packets (frame version 2 only)
+    pub mux: bool,
+    // Client: pad frames to hide packet lengths, at a bandwidth cost (see protocol)
+    pub pad: Padding,
+    // MTU of the TUN device; buffers are sized from the negotiated value
+    pub mtu: usize,
+    // Already configured TUN descriptor to use instead of creating one
+    pub tun_fd: Option<i32>,
+    // Install the seccomp filter once setup is done
+    pub sandbox: bool,
+    // Server: serve one session over stdin/stdout instead of listening
+    pub stdio: bool,
+    // Client: run this shell command and use its stdin/stdout as the transport
+    pub proxy_command: Option<String>,
+    // TOS/traffic class of the outer connection
+    pub tos: Tos,
+    // Clamp the MSS of TCP SYNs entering the tunnel
+    pub mssfix: MssFix,
+    // Server: ask the router for a port mapping of the TCP listen ports (NAT-PMP)
+    pub upnp: bool,
+    // Router to ask (default: the default gateway)
+    pub upnp_gateway: Option<Ipv4Addr>,
+    // Server: wait for clients at this relay (host:port) instead of listening
+    pub relay: Option<String>,
+    // Name both peers give the relay so it can pair them
+    pub relay_id: Option<String>,
+    // UDP ports to knock on, in order, before connecting (server: to require)
+    pub knock: Vec<u16>,
+    // Key to sign knocks with (server: to require signed knocks); without
+    // it the knock sequence is sent in the clear and can be replayed
+    pub knock_key: Option<String>,
+    // Server: sessions at the same time (0 = unlimited)
+    pub max_clients: usize,
+    // Server: CPUs to pin the data path to (see affinity)
+    pub cpus: Vec<usize>,
+    // Server: default daily quota per client
+    pub quota: Quota,
+    // Server: connections accepted per source address per minute (0 = unlimited)
+    pub handshake_rate: usize,
+    // Server: rejected handshakes within 10 minutes before a source is banned
+    // for ban_time (0 = never, the default). Off by default because clients
+    // behind one NAT address share the count; set e.g. ban_after = 5 on a
+    // server whose clients have addresses of their own.
+    pub ban_after: usize,
+    // Server: seconds an automatic ban lasts
+    pub ban_time: u64,
+    // Seconds the peer has to complete its part of the handshake
+    pub handshake_timeout: u64,
+    // Server: connections still in the handshake at the same time (0 = unlimited)
+    pub max_handshakes: usize,
+    // Server: program that accepts (exit 0) or rejects each client
+    pub auth_exec: Option<String>,
+    // Client: key=value pairs sent in the handshake for the server's auth
+    pub credentials: Vec<(String, String)>,
+    // Client: file kept up to date with the connection state as JSON
+    pub status_file: Option<PathBuf>,
+    // Client: program run on every connection state change
+    pub state_exec: Option<String>,
+    // Classes of tunnel traffic to drop, in both directions (see filter)
+    pub block: Vec<Rule>,
+    // Client: local ports forwarded to hosts on the server side (see forward)
+    pub forward: Vec<Forward>,
+    // Server: networks clients may have forwarded connections to (empty = none)
+    pub forward_allow: Vec<(IpAddr, u8)>,
+    // Client: where to run a caching DNS forwarder to the pushed resolvers (see dns)
+    pub dns_listen: Option<SocketAddr>,
+    // Client: address pinged through the tunnel to check the data path
+    pub watchdog: Option<IpAddr>,
+    // Client: seconds between watchdog pings
+    pub watchdog_interval: u64,
+    // Client: unanswered watchdog pings in a row before reconnecting
+    pub watchdog_failures: u32,
+    // Seconds without traffic before the kernel probes the outer TCP
+    // connection (0 = no kernel keepalive)
+    pub tcp_keepalive: u64,
+    // Seconds between kernel keepalive probes
+    pub tcp_keepalive_interval: u64,
+    // Unanswered kernel keepalive probes before the connection is dropped
+    pub tcp_keepalive_count: u32,
+    // Client: seconds without a frame from the server before pinging it on
+    // the control channel; after about three times that without one it
+    // reconnects (0 = off)
+    pub keepalive: u64,
+    // Server: options pushed to the client with a given tunnel address, from
+    // `[client <addr>]` sections of the config file
+    pub clients: Vec<(IpAddr, Options)>,
+    // Buffer and queue sizes, from the `[tuning]` section
+    pub tuning: Tuning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: String,
+}
+
+impl Endpoint {
+    // Parse "host:port", "[v6addr]:port" or "unix:/path"
+    pub fn parse(s: &str) -> Option<Endpoint> {
+        if s.starts_with(UNIX_PREFIX) {
+            return Some(Endpoint {
+                host: s.to_string(),
+                port: String::new(),
+            });
+        }
+        let (host, port) = s.rsplit_once(':')?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return None;
+        }
+        Some(Endpoint {
+            host: host.to_string(),
+            port: port.to_string(),
+        })
+    }
+
+    // Socket path of a "unix:" endpoint
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.host.strip_prefix(UNIX_PREFIX).map(Path::new)
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.unix_path().is_some() {
+            write!(f, "{}", self.host)
+        } else if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerOrder {
+    #[default]
+    Ordered,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Prefer {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+// Marking of the outer packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Tos {
+    // Leave it to the system
+    #[default]
+    Off,
+    // Always use this TOS byte
+    Fixed(u8),
+    // Copy the DSCP of the packet being sent
+    Inherit,
+}
+
+// TCP MSS clamping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MssFix {
+    Off,
+    // Fit segments into the session MTU
+    #[default]
+    Auto,
+    // Fit segments into this packet size (or the session MTU if smaller)
+    Mtu(usize),
+}
+
+impl MssFix {
+    // Packet size SYNs are clamped for in a session with this MTU
+    pub fn limit(self, mtu: usize) -> Option<usize> {
+        match self {
+            MssFix::Off => None,
+            MssFix::Auto => Some(mtu),
+            MssFix::Mtu(n) => Some(n.min(mtu)),
+        }
+    }
+}
+
+// Trade-offs between latency and throughput on each side's connection.
+// They only affect this side: the server's values apply to what it sends
+// to and reads from clients, the client's to the other direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    // Bytes read from the outer connection at a time (0 = unbuffered)
+    pub read_buffer: usize,
+    // Packets queued per class before new ones are dropped
+    pub queue_depth: usize,
+    // Frames are gathered into one write of up to this many bytes while
+    // more are queued (0 = one write per frame)
+    pub write_coalesce: usize,
+    // How long a partly filled write waits for more frames
+    pub flush_interval: Duration,
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning {
+            read_buffer: 65536,
+            queue_depth: 256,
+            write_coalesce: 16384,
+            flush_interval: Duration::ZERO,
+        }
+    }
+}
+
+// Server: limits per client and UTC day (`quota_bytes`, `quota_time`),
+// overridable in the client's [client] section. Bytes count both
+// directions; time is time connected. 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes == 0 && self.time.is_zero()
+    }
+}
+
+// Keys of a [client] section that configure the server rather than being
+// pushed to the client
+const CLIENT_ONLY_ON_SERVER: &[&str] = &["quota_bytes", "quota_time", "expose"];
+
+// Byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, unit) = match value.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_uppercase()),
+        _ => (value, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        'T' => 40,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// Decimal or 0x-prefixed hex
+fn parse_tos(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// Value of a secret setting: `file:/path` reads the file (without its
+// trailing newline), `env:NAME` the environment variable and `sealed:...`
+// is unsealed with the passphrase (see the secret module); anything else
+// is the secret itself
+fn secret(value: &str) -> std::result::Result<String, String> {
+    if value.starts_with(crate::secret::PREFIX) {
+        crate::secret::unseal(value, &crate::secret::passphrase()?)
+    } else if let Some(path) = value.strip_prefix("file:") {
+        fs::read_to_string(path)
+            .map(|s| s.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| format!("cannot read {}: {}", path, e))
+    } else if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).map_err(|_| format!("environment variable {} is not set", name))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+fn parse_bool(value: &str) -> std::result::Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "yes" | "true" | "on" => Ok(true),
+        "0" | "no" | "false" | "off" => Ok(false),
+        _ => Err(format!("invalid boolean: {}", value)),
+    }
+}
+
+// Section of the config file a line belongs to
+enum Section {
+    // Before the first section header
+    Main,
+    // Index into `clients`
+    Client(usize),
+    Tuning,
+}
+
+// Header of a "client <addr>" or "tuning" section; None for tuning
+fn parse_section(name: &str) -> std::result::Result<Option<IpAddr>, String> {
+    match name.split_whitespace().collect::<Vec<_>>()[..] {
+        ["client", addr] => addr
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid client address: {}", addr)),
+        ["tuning"] => Ok(None),
+        _ => Err(format!("unknown section: [{}]", name)),
+    }
+}
+
+// One `key = value` of a [client] section. Values go out in the handshake
+// reply, so they may not contain spaces; lists are comma separated.
+fn set_pushed(options: &mut Options, key: &str, value: &str) -> std::result::Result<(), String> {
+    if key.is_empty() || key.contains(char::is_whitespace) || key.contains('=') {
+        return Err(format!("invalid key: {}", key));
+    }
+    if value.contains(char::is_whitespace) {
+        return Err(format!("{}: value may not contain spaces", key));
+    }
+    let valid = match key {
+        "frame" | "max_frame" | "mux" | "pad" => return Err(format!("{} cannot be pushed", key)),
+        "mtu" => value
+            .parse::<usize>()
+            .is_ok_and(|mtu| (68..=MAX_MTU).contains(&mtu)),
+        "route" => value.split(',').all(|cidr| parse_cidr(cidr).is_some()),
+        "quota_bytes" => parse_size(value).is_some(),
+        "quota_time" => value.parse::<u64>().is_ok(),
+        "expose" => value.split(',').all(|m| Mapping::parse(m).is_some()),
+        "dns" => value.split(',').all(|ip| ip.parse::<IpAddr>().is_ok()),
+        "ip6" => matches!(parse_cidr(value), Some((IpAddr::V6(_), _))),
+        _ => true,
+    };
+    if !valid {
+        return Err(format!("invalid {}: {}", key, value));
+    }
+    options.set(key, value);
+    Ok(())
+}
+
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+// Keys accepted in the config file and as RUST_VPN_* variables
+pub const KEYS: &[&str] = &[
+    "mode",
+    "addr",
+    "port",
+    "tun_ip",
+    "tun_name",
+    "allow_overlap",
+    "tun_ip6",
+    "ip6_default_route",
+    "log_level",
+    "allow",
+    "ctl_socket",
+    "healthz",
+    "web",
+    "web_password",
+    "reconnect",
+    "prefer",
+    "greeting",
+    "sni",
+    "share",
+    "bind_dev",
+    "bind_addr",
+    "listen",
+    "servers",
+    "server_order",
+    "max_frame",
+    "mux",
+    "pad",
+    "mtu",
+    "tun_fd",
+    "sandbox",
+    "stdio",
+    "proxy_command",
+    "tos",
+    "mssfix",
+    "upnp",
+    "upnp_gateway",
+    "relay",
+    "relay_id",
+    "knock",
+    "knock_key",
+    "max_clients",
+    "cpus",
+    "quota_bytes",
+    "quota_time",
+    "handshake_rate",
+    "ban_after",
+    "ban_time",
+    "handshake_timeout",
+    "max_handshakes",
+    "auth_exec",
+    "credentials",
+    "status_file",
+    "state_exec",
+    "block",
+    "forward",
+    "forward_allow",
+    "dns_listen",
+    "watchdog",
+    "watchdog_interval",
+    "watchdog_failures",
+    "tcp_keepalive",
+    "tcp_keepalive_interval",
+    "tcp_keepalive_count",
+    "keepalive",
+    "read_buffer",
+    "queue_depth",
+    "write_coalesce",
+    "flush_interval",
+];
+
+// Keys that go in the `[tuning]` section of the file
+const TUNING_KEYS: &[&str] = &[
+    "read_buffer",
+    "queue_depth",
+    "write_coalesce",
+    "flush_interval",
+];
+
+// Environment variable holding the config file path
+pub const CONFIG_ENV: &str = "RUST_VPN_CONFIG";
+
+// tun_name of a client that only forwards ports and opens no TUN
+pub const NO_TUN: &str = "none";
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            path: None,
+            mode: String::new(),
+            addr: String::new(),
+            port: String::new(),
+            tun_ip: String::new(),
+            tun_name: String::new(),
+            allow_overlap: false,
+            tun_ip6: None,
+            ip6_default_route: false,
+            log_level: None,
+            allow: Vec::new(),
+            ctl_socket: None,
+            healthz: None,
+            web: None,
+            web_password: None,
+            reconnect: 0,
+            prefer: Prefer::Any,
+            greeting: Greeting::None,
+            sni: None,
+            share: None,
+            bind_dev: None,
+            bind_addr: None,
+            listen: Vec::new(),
+            servers: Vec::new(),
+            server_order: ServerOrder::Ordered,
+            max_frame: MAX_FRAME_V1,
+            mux: true,
+            pad: Padding::None,
+            mtu: DEFAULT_MTU,
+            tun_fd: None,
+            sandbox: false,
+            stdio: false,
+            proxy_command: None,
+            tos: Tos::Off,
+            mssfix: MssFix::Auto,
+            upnp: false,
+            upnp_gateway: None,
+            relay: None,
+            relay_id: None,
+            knock: Vec::new(),
+            knock_key: None,
+            max_clients: 0,
+            cpus: Vec::new(),
+            quota: Quota::default(),
+            handshake_rate: 30,
+            ban_after: 0,
+            ban_time: 600,
+            handshake_timeout: 10,
+            max_handshakes: 64,
+            auth_exec: None,
+            credentials: Vec::new(),
+            status_file: None,
+            state_exec: None,
+            block: Vec::new(),
+            forward: Vec::new(),
+            forward_allow: Vec::new(),
+            dns_listen: None,
+            watchdog: None,
+            watchdog_interval: 10,
+            watchdog_failures: 3,
+            tcp_keepalive: 30,
+            tcp_keepalive_interval: 10,
+            tcp_keepalive_count: 3,
+            keepalive: 25,
+            clients: Vec::new(),
+            tuning: Tuning::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| VpnError::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+        let mut config = Config::parse(&text)
+            .map_err(|e| VpnError::Config(format!("{}: {}", path.display(), e)))?;
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> std::result::Result<Config, String> {
+        let mut config = Config::default();
+        let mut section = Section::Main;
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section =
+                    match parse_section(name).map_err(|e| format!("line {}: {}", lineno + 1, e))? {
+                        Some(addr) => {
+                            config.clients.retain(|(a, _)| *a != addr);
+                            config.clients.push((addr, Options::new()));
+                            Section::Client(config.clients.len() - 1)
+                        }
+                        None => Section::Tuning,
+                    };
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", lineno + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+            match section {
+                Section::Client(i) => set_pushed(&mut config.clients[i].1, key, value),
+                Section::Tuning if !TUNING_KEYS.contains(&key) => {
+                    Err(format!("unknown key in [tuning]: {}", key))
+                }
+                Section::Main if TUNING_KEYS.contains(&key) => {
+                    Err(format!("{} belongs in the [tuning] section", key))
+                }
+                Section::Tuning | Section::Main => config.set(key, value),
+            }
+            .map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+        }
+        Ok(config)
+    }
+
+    // Options pushed to the client with tunnel address `addr`
+    pub fn pushed(&self, addr: IpAddr) -> Options {
+        let mut options = Options::new();
+        for (key, value) in self
+            .client_section(addr)
+            .into_iter()
+            .flat_map(Options::iter)
+        {
+            if !CLIENT_ONLY_ON_SERVER.contains(&key) {
+                options.set(key, value);
+            }
+        }
+        options
+    }
+
+    // Quota of the client with tunnel address `addr`
+    pub fn quota(&self, addr: IpAddr) -> Quota {
+        let mut quota = self.quota;
+        if let Some(section) = self.client_section(addr) {
+            if let Some(bytes) = section.get("quota_bytes").and_then(parse_size) {
+                quota.bytes = bytes;
+            }
+            if let Some(secs) = section.get("quota_time").and_then(|v| v.parse().ok()) {
+                quota.time = Duration::from_secs(secs);
+            }
+        }
+        quota
+    }
+
+    // Server ports forwarded to the client with tunnel address `addr`
+    pub fn exposed(&self, addr: IpAddr) -> Vec<Mapping> {
+        self.client_section(addr)
+            .and_then(|section| section.get("expose"))
+            .map(|value| value.split(',').filter_map(Mapping::parse).collect())
+            .unwrap_or_default()
+    }
+
+    fn client_section(&self, addr: IpAddr) -> Option<&Options> {
+        self.clients
+            .iter()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, options)| options)
+    }
+
+    // Override settings from RUST_VPN_* environment variables
+    pub fn apply_env(&mut self) -> Result<()> {
+        for key in KEYS {
+            let var = env_var_name(key);
+            if let Ok(value) = std::env::var(&var) {
+                self.set(key, value.trim())
+                    .map_err(|e| VpnError::Config(format!("{}: {}", var, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Set one option by key, as used in the file, RUST_VPN_* and --key flags
+    pub fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        match key {
+            "mode" => self.mode = value.to_string(),
+            "addr" => self.addr = value.to_string(),
+            "port" => self.port = value.to_string(),
+            "tun_ip" => self.tun_ip = value.to_string(),
+            "tun_name" => self.tun_name = value.to_string(),
+            "allow_overlap" => self.allow_overlap = parse_bool(value)?,
+            "tun_ip6" => {
+                self.tun_ip6 = match value {
+                    "" => None,
+                    _ => match parse_cidr(value) {
+                        Some((IpAddr::V6(_), _)) => Some(value.to_string()),
+                        _ => return Err(format!("invalid tun_ip6: {}", value)),
+                    },
+                }
+            }
+            "ip6_default_route" => self.ip6_default_route = parse_bool(value)?,
+            "log_level" => {
+                self.log_level = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid log_level: {}", value))?,
+                )
+            }
+            "allow" => {
+                self.allow = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| parse_cidr(s).ok_or_else(|| format!("invalid CIDR in allow: {}", s)))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "ctl_socket" => self.ctl_socket = Some(PathBuf::from(value)),
+            "healthz" => self.healthz = Some(value.to_string()),
+            "web" => self.web = Some(value.to_string()),
+            "web_password" => self.web_password = Some(secret(value)?),
+            "reconnect" => {
+                self.reconnect = value
+                    .parse()
+                    .map_err(|_| format!("invalid reconnect: {}", value))?
+            }
+            "prefer" => {
+                self.prefer = match value {
+                    "any" => Prefer::Any,
+                    "ipv4" => Prefer::Ipv4,
+                    "ipv6" => Prefer::Ipv6,
+                    _ => return Err(format!("invalid prefer (any|ipv4|ipv6): {}", value)),
+                }
+            }
+            "greeting" => {
+                self.greeting = match value {
+                    "none" => Greeting::None,
+                    "tls" => Greeting::Tls,
+                    _ => return Err(format!("invalid greeting (none|tls): {}", value)),
+                }
+            }
+            "sni" => self.sni = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "share" => self.share = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "bind_dev" => self.bind_dev = Some(value.to_string()),
+            "bind_addr" => {
+                self.bind_addr = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid bind_addr: {}", value))?,
+                )
+            }
+            "listen" => {
+                self.listen = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "servers" => {
+                self.servers = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| Endpoint::parse(s).ok_or_else(|| format!("invalid server: {}", s)))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "server_order" => {
+                self.server_order = match value {
+                    "ordered" => ServerOrder::Ordered,
+                    "random" => ServerOrder::Random,
+                    _ => return Err(format!("invalid server_order (ordered|random): {}", value)),
+                }
+            }
+            "max_frame" => {
+                self.max_frame = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid max_frame: {}", value))?
+            }
+            "mtu" => {
+                self.mtu = value
+                    .parse()
+                    .ok()
+                    .filter(|n| (68..=MAX_MTU).contains(n))
+                    .ok_or_else(|| format!("invalid mtu (68-{}): {}", MAX_MTU, value))?
+            }
+            "tun_fd" => {
+                self.tun_fd = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|&fd| fd >= 0)
+                        .ok_or_else(|| format!("invalid tun_fd: {}", value))?,
+                )
+            }
+            "mux" => self.mux = parse_bool(value)?,
+            "pad" => {
+                self.pad = Padding::parse(value)
+                    .ok_or_else(|| format!("invalid pad (none|mtu|buckets): {}", value))?
+            }
+            "sandbox" => self.sandbox = parse_bool(value)?,
+            "upnp" => self.upnp = parse_bool(value)?,
+            "upnp_gateway" => {
+                self.upnp_gateway = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid upnp_gateway: {}", value))?,
+                )
+            }
+            "relay" => self.relay = Some(value.to_string()),
+            "relay_id" => {
+                if value.is_empty() || value.contains(char::is_whitespace) {
+                    return Err(format!("invalid relay_id: {:?}", value));
+                }
+                self.relay_id = Some(value.to_string())
+            }
+            "knock" => {
+                self.knock = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| match s.parse() {
+                        Ok(port) if port > 0 => Ok(port),
+                        _ => Err(format!("invalid port in knock: {}", s)),
+                    })
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "knock_key" => self.knock_key = Some(secret(value)?),
+            "max_clients" => {
+                self.max_clients = value
+                    .parse()
+                    .map_err(|_| format!("invalid max_clients: {}", value))?
+            }
+            "cpus" => {
+                self.cpus =
+                    affinity::parse(value).ok_or_else(|| format!("invalid cpus: {}", value))?
+            }
+            "quota_bytes" => {
+                self.quota.bytes =
+                    parse_size(value).ok_or_else(|| format!("invalid quota_bytes: {}", value))?
+            }
+            "quota_time" => {
+                self.quota.time = value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| format!("invalid quota_time (seconds): {}", value))?
+            }
+            "handshake_rate" => {
+                self.handshake_rate = value
+                    .parse()
+                    .map_err(|_| format!("invalid handshake_rate: {}", value))?
+            }
+            "ban_after" => {
+                self.ban_after = value
+                    .parse()
+                    .map_err(|_| format!("invalid ban_after: {}", value))?
+            }
+            "ban_time" => {
+                self.ban_time = value
+                    .parse()
+                    .map_err(|_| format!("invalid ban_time: {}", value))?
+            }
+            "handshake_timeout" => {
+                self.handshake_timeout = value
+                    .parse()
+                    .ok()
+                    .filter(|&t| t > 0)
+                    .ok_or_else(|| format!("invalid handshake_timeout (seconds): {}", value))?
+            }
+            "max_handshakes" => {
+                self.max_handshakes = value
+                    .parse()
+                    .map_err(|_| format!("invalid max_handshakes: {}", value))?
+            }
+            "auth_exec" => self.auth_exec = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "status_file" => {
+                self.status_file = Some(PathBuf::from(value)).filter(|_| !value.is_empty())
+            }
+            "state_exec" => self.state_exec = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "block" => {
+                self.block = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| Rule::parse(s).ok_or_else(|| format!("invalid block rule: {}", s)))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "forward" => {
+                self.forward = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| Forward::parse(s).ok_or_else(|| format!("invalid forward: {}", s)))
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "forward_allow" => {
+                self.forward_allow = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| {
+                        parse_cidr(s).ok_or_else(|| format!("invalid CIDR in forward_allow: {}", s))
+                    })
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            // An address alone listens on the DNS port
+            "dns_listen" => {
+                self.dns_listen = match value {
+                    "" => None,
+                    _ => Some(
+                        value
+                            .parse()
+                            .or_else(|_| value.parse().map(|ip| SocketAddr::new(ip, 53)))
+                            .map_err(|_| format!("invalid dns_listen: {}", value))?,
+                    ),
+                }
+            }
+            "watchdog" => {
+                self.watchdog = match value {
+                    "" => None,
+                    _ => Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid watchdog address: {}", value))?,
+                    ),
+                }
+            }
+            "watchdog_interval" => {
+                self.watchdog_interval = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid watchdog_interval: {}", value))?
+            }
+            "watchdog_failures" => {
+                self.watchdog_failures = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid watchdog_failures: {}", value))?
+            }
+            "tcp_keepalive" => {
+                self.tcp_keepalive = value
+                    .parse()
+                    .map_err(|_| format!("invalid tcp_keepalive: {}", value))?
+            }
+            "tcp_keepalive_interval" => {
+                self.tcp_keepalive_interval = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid tcp_keepalive_interval: {}", value))?
+            }
+            "tcp_keepalive_count" => {
+                self.tcp_keepalive_count = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid tcp_keepalive_count: {}", value))?
+            }
+            "keepalive" => {
+                self.keepalive = value
+                    .parse()
+                    .map_err(|_| format!("invalid keepalive: {}", value))?
+            }
+            "read_buffer" => {
+                self.tuning.read_buffer = value
+                    .parse()
+                    .map_err(|_| format!("invalid read_buffer: {}", value))?
+            }
+            "queue_depth" => {
+                self.tuning.queue_depth = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid queue_depth: {}", value))?
+            }
+            "write_coalesce" => {
+                self.tuning.write_coalesce = value
+                    .parse()
+                    .map_err(|_| format!("invalid write_coalesce: {}", value))?
+            }
+            "flush_interval" => {
+                self.tuning.flush_interval = value
+                    .parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| format!("invalid flush_interval (ms): {}", value))?
+            }
+            "credentials" => {
+                self.credentials = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| match s.split_once('=') {
+                        Some((k, v)) if !k.is_empty() && !s.contains(char::is_whitespace) => {
+                            let v = secret(v)?;
+                            // It goes into the handshake line as one word
+                            if v.contains(char::is_whitespace) {
+                                return Err(format!("credential {} contains whitespace", k));
+                            }
+                            Ok((k.to_string(), v))
+                        }
+                        _ => Err("invalid credentials (key=value,... without spaces)".to_string()),
+                    })
+                    .collect::<std::result::Result<_, _>>()?
+            }
+            "mssfix" => {
+                self.mssfix = match value {
+                    "off" => MssFix::Off,
+                    "auto" => MssFix::Auto,
+                    _ => MssFix::Mtu(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|n| (68..=MAX_MTU).contains(n))
+                            .ok_or_else(|| {
+                                format!("invalid mssfix (off|auto|68-{}): {}", MAX_MTU, value)
+                            })?,
+                    ),
+                }
+            }
+            "tos" => {
+                self.tos = match value {
+                    "off" => Tos::Off,
+                    "inherit" => Tos::Inherit,
+                    _ => Tos::Fixed(
+                        parse_tos(value)
+                            .ok_or_else(|| format!("invalid tos (off|inherit|0-255): {}", value))?,
+                    ),
+                }
+            }
+            "stdio" => self.stdio = parse_bool(value)?,
+            "proxy_command" => {
+                self.proxy_command = Some(value.to_string()).filter(|v| !v.is_empty())
+            }
+            _ => return Err(format!("unknown key: {}", key)),
+        }
+        Ok(())
+    }
+
+    // Check that everything needed to start is present
+    pub fn validate(&self) -> Result<()> {
+        let listen_only = (self.mode == "server"
+            && (!self.listen.is_empty() || self.stdio || self.relay.is_some()))
+            || (self.mode == "relay" && !self.listen.is_empty())
+            || (self.mode == "client"
+                && (!self.servers.is_empty() || self.proxy_command.is_some()));
+        let unix_addr = self.addr.starts_with(UNIX_PREFIX);
+        for (name, value) in [
+            ("mode", &self.mode),
+            ("addr", &self.addr),
+            ("port", &self.port),
+            ("tun_ip", &self.tun_ip),
+            ("tun_name", &self.tun_name),
+        ] {
+            if (listen_only && (name == "addr" || name == "port")) || (unix_addr && name == "port")
+            {
+                continue;
+            }
+            // An inherited TUN is already named and (on the server) addressed
+            if self.tun_fd.is_some()
+                && (name == "tun_name" || (name == "tun_ip" && self.mode == "server"))
+            {
+                continue;
+            }
+            // The relay has no TUN
+            if self.mode == "relay" && (name == "tun_ip" || name == "tun_name") {
+                continue;
+            }
+            if value.is_empty() {
+                return Err(VpnError::Config(format!("Missing setting: {}", name)));
+            }
+        }
+        // The seccomp filter does not allow starting programs
+        if self.sandbox && self.auth_exec.is_some() {
+            return Err(VpnError::Config(
+                "auth_exec cannot be used with sandbox".into(),
+            ));
+        }
+        if self.sandbox && self.state_exec.is_some() {
+            return Err(VpnError::Config(
+                "state_exec cannot be used with sandbox".into(),
+            ));
+        }
+        // A client may go without a TUN when it only forwards ports
+        if self.tun_name == NO_TUN && (self.mode != "client" || self.forward.is_empty()) {
+            return Err(VpnError::Config(format!(
+                "tun_name {} is only for a client with forward",
+                NO_TUN
+            )));
+        }
+        if self.tun_ip6.is_some() && self.mode != "server" {
+            return Err(VpnError::Config("tun_ip6 is only for a server".into()));
+        }
+        if self.dns_listen.is_some() && (self.mode != "client" || self.tun_name == NO_TUN) {
+            return Err(VpnError::Config(
+                "dns_listen is only for a client with a TUN".into(),
+            ));
+        }
+        if self.relay.is_some() && self.relay_id.is_none() {
+            return Err(VpnError::Config("relay needs relay_id".into()));
+        }
+        if self.knock_key.is_some() && self.knock.is_empty() {
+            return Err(VpnError::Config("knock_key needs knock".into()));
+        }
+        Ok(())
+    }
+
+    pub fn listen_addrs(&self) -> Vec<String> {
+        if self.listen.is_empty() && self.addr.starts_with(UNIX_PREFIX) {
+            vec![self.addr.clone()]
+        } else if self.listen.is_empty() {
+            vec![format!("{}:{}", self.addr, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        if self.servers.is_empty() {
+            vec![Endpoint {
+                host: self.addr.clone(),
+                port: self.port.clone(),
+            }]
+        } else {
+            self.servers.clone()
+        }
+    }
+
+    // Framing this side offers or accepts; always large enough for the MTU
+    pub fn framing(&self) -> Framing {
+        // Room for a full packet in a padded frame. Padding to the MTU
+        // offers just that, as the size every frame is padded to.
+        let framing = match (self.mode.as_str(), self.pad) {
+            ("client", Padding::Mtu) => Framing::v2(self.mtu + PAD_LEN),
+            _ => Framing::v2(self.max_frame.max(self.mtu + PAD_LEN)),
+        };
+        let framing = if self.mux { framing.muxed() } else { framing };
+        match self.mode.as_str() {
+            "client" => framing.padded(self.pad),
+            _ => framing,
+        }
+    }
+
+    // Kernel keepalive for outer TCP connections, None when off
+    pub fn kernel_keepalive(&self) -> Option<Keepalive> {
+        (self.tcp_keepalive > 0).then(|| Keepalive {
+            idle: Duration::from_secs(self.tcp_keepalive),
+            interval: Duration::from_secs(self.tcp_keepalive_interval),
+            count: self.tcp_keepalive_count,
+        })
+    }
+
+    // IPv6 address and prefix length of the client with tunnel address
+    // `addr`: `ip6` from its [client] section, or else its IPv4 address in
+    // the last 32 bits of the tun_ip6 prefix (fd00:9::1/64 and 10.9.0.2
+    // make fd00:9::a09:2/64). None without tun_ip6, or for an IPv6 client
+    // or a prefix too long to hold an IPv4 address that has no section.
+    pub fn client_ip6(&self, addr: IpAddr) -> Option<(IpAddr, u8)> {
+        if let Some(ip6) = self.client_section(addr).and_then(|s| s.get("ip6")) {
+            return parse_cidr(ip6);
+        }
+        let (IpAddr::V6(net), prefix) = parse_cidr(self.tun_ip6.as_deref()?)? else {
+            return None;
+        };
+        let IpAddr::V4(v4) = addr else {
+            return None;
+        };
+        if prefix > 96 {
+            return None;
+        }
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        let ip6 = (u128::from(net) & mask) | u32::from(v4) as u128;
+        Some((IpAddr::V6(ip6.into()), prefix))
+    }
+
+    // Open the TUN device: adopt tun_fd if given, otherwise create and
+    // configure tun_name. Returns the device and whether we configured it.
+    pub fn open_tun(&self, mtu: usize) -> Result<(TunInterface, bool)> {
+        if let Some(fd) = self.tun_fd {
+            return Ok((TunInterface::from_fd(fd)?, false));
+        }
+        tun::check_overlap(&self.tun_ip, &self.tun_name, self.allow_overlap)?;
+        if let Some(ip6) = &self.tun_ip6 {
+            tun::check_overlap(ip6, &self.tun_name, self.allow_overlap)?;
+        }
+        let tun = TunInterface::new(&self.tun_name)?;
+        tun.set_mtu(mtu)?;
+        tun.set_addr(&self.tun_ip)?;
+        if let Some(ip6) = &self.tun_ip6 {
+            tun.add_address(ip6)?;
+        }
+        Ok((tun, true))
+    }
+
+    pub fn ctl_socket_path(&self) -> PathBuf {
+        self.ctl_socket
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(control::DEFAULT_SOCKET))
+    }
+
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|(net, prefix)| cidr_contains(*net, *prefix, addr))
+    }
+}
+
+pub fn env_var_name(key: &str) -> String {
+    format!("RUST_VPN_{}", key.to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("1500"), Some(1500));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("10M"), Some(10 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("3T"), Some(3 << 40));
+        for bad in ["", "K", "1.5G", "-1", "10X", "10 M", "G10"] {
+            assert_eq!(parse_size(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn sizes_that_overflow_are_refused() {
+        assert_eq!(parse_size(&u64::MAX.to_string()), Some(u64::MAX));
+        assert_eq!(parse_size("18446744073709551616"), None);
+        assert_eq!(parse_size("16777215T"), Some(16777215 << 40));
+        assert_eq!(parse_size("16777216T"), None);
+        assert_eq!(parse_size("17179869184G"), None);
+    }
+
+    #[test]
+    fn tuning_defaults_and_limits() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.tuning, Tuning::default());
+        assert_eq!(config.tuning.read_buffer, 65536);
+        assert_eq!(config.tuning.queue_depth, 256);
+        assert_eq!(config.tuning.write_coalesce, 16384);
+        assert_eq!(config.tuning.flush_interval, Duration::ZERO);
+
+        let mut config = Config::default();
+        config.set("read_buffer", "0").unwrap();
+        config.set("write_coalesce", "0").unwrap();
+        config.set("flush_interval", "5").unwrap();
+        assert_eq!(config.tuning.read_buffer, 0);
+        assert_eq!(config.tuning.write_coalesce, 0);
+        assert_eq!(config.tuning.flush_interval, Duration::from_millis(5));
+        for (key, value) in [
+            ("queue_depth", "0"),
+            ("queue_depth", "-1"),
+            ("read_buffer", "-1"),
+            ("read_buffer", "64k"),
+            ("write_coalesce", "lots"),
+            ("flush_interval", "1.5"),
+        ] {
+            assert!(config.set(key, value).is_err(), "{} = {}", key, value);
+        }
+        // A refused value leaves the setting as it was
+        assert_eq!(config.tuning.queue_depth, 256);
+    }
+
+    #[test]
+    fn tuning_keys_belong_in_their_section() {
+        let config = Config::parse("[tuning]\nqueue_depth = 64\nflush_interval = 2\n").unwrap();
+        assert_eq!(config.tuning.queue_depth, 64);
+        assert_eq!(config.tuning.flush_interval, Duration::from_millis(2));
+
+        let e = Config::parse("queue_depth = 64\n").unwrap_err();
+        assert!(e.contains("[tuning]"), "{}", e);
+        let e = Config::parse("[tuning]\nmtu = 1400\n").unwrap_err();
+        assert!(e.contains("unknown key in [tuning]: mtu"), "{}", e);
+        let e = Config::parse("[tuning]\nqueue_depth = 0\n").unwrap_err();
+        assert!(e.starts_with("line 2:"), "{}", e);
+    }
+
+    #[test]
+    fn client_sections_override_per_address() {
+        let text = "\
+quota_bytes = 1G
+[client 10.9.0.2]
+route = 192.168.1.0/24
+quota_bytes = 10M
+[client 10.9.0.3]
+mtu = 1400
+[client 10.9.0.2]
+quota_time = 60
+";
+        let config = Config::parse(text).unwrap();
+        let two: IpAddr = "10.9.0.2".parse().unwrap();
+        let three: IpAddr = "10.9.0.3".parse().unwrap();
+        let other: IpAddr = "10.9.0.4".parse().unwrap();
+        // A repeated section replaces the earlier one
+        assert_eq!(
+            config.quota(two),
+            Quota {
+                bytes: 1 << 30,
+                time: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(config.pushed(two).get("route"), None);
+        assert_eq!(config.pushed(three).get("mtu"), Some("1400"));
+        assert_eq!(config.quota(three).bytes, 1 << 30);
+        assert_eq!(config.quota(other).bytes, 1 << 30);
+        assert_eq!(config.pushed(other), Options::new());
+
+        // Server-side keys are not pushed
+        let config =
+            Config::parse("[client 10.9.0.2]\nquota_bytes = 10M\ndns = 10.9.0.1\n").unwrap();
+        assert_eq!(config.quota(two).bytes, 10 << 20);
+        let pushed = config.pushed(two);
+        assert_eq!(pushed.get("quota_bytes"), None);
+        assert_eq!(pushed.get("dns"), Some("10.9.0.1"));
+    }
+
+    #[test]
+    fn bad_sections_and_keys_are_refused() {
+        for (text, error) in [
+            ("[server]\n", "line 1: unknown section: [server]"),
+            (
+                "[client nowhere]\n",
+                "line 1: invalid client address: nowhere",
+            ),
+            ("no_such_key = 1\n", "line 1: unknown key: no_such_key"),
+            ("mtu\n", "line 1: expected key = value"),
+            ("[client 10.9.0.2]\nmtu = 20\n", "line 2: invalid mtu: 20"),
+            (
+                "[client 10.9.0.2]\nmux = 0\n",
+                "line 2: mux cannot be pushed",
+            ),
+            (
+                "[client 10.9.0.2]\nroute = a b\n",
+                "line 2: route: value may not contain spaces",
+            ),
+            (
+                "[client 10.9.0.2]\nquota_bytes = 5X\n",
+                "line 2: invalid quota_bytes: 5X",
+            ),
+        ] {
+            assert_eq!(Config::parse(text).unwrap_err(), error);
+        }
+        // Keys a [client] section does not know are pushed as they are, for
+        // newer clients
+        let config = Config::parse("[client 10.9.0.2]\nfuture_option = 1\n").unwrap();
+        let pushed = config.pushed("10.9.0.2".parse().unwrap());
+        assert_eq!(pushed.get("future_option"), Some("1"));
+    }
+
+    // The order main applies them in: file, then environment, then flags
+    #[test]
+    fn command_line_beats_environment_beats_file() {
+        let mut config = Config::parse("reconnect = 5\nkeepalive = 30\nmtu = 1400\n").unwrap();
+        std::env::set_var("RUST_VPN_RECONNECT", "7");
+        std::env::set_var("RUST_VPN_KEEPALIVE", "45");
+        let applied = config.apply_env();
+        std::env::remove_var("RUST_VPN_RECONNECT");
+        std::env::remove_var("RUST_VPN_KEEPALIVE");
+        applied.unwrap();
+        config.set("reconnect", "9").unwrap();
+        assert_eq!(config.reconnect, 9);
+        assert_eq!(config.keepalive, 45);
+        assert_eq!(config.mtu, 1400);
+
+        std::env::set_var("RUST_VPN_QUEUE_DEPTH", "0");
+        let e = Config::default().apply_env().unwrap_err();
+        std::env::remove_var("RUST_VPN_QUEUE_DEPTH");
+        assert!(e.to_string().contains("RUST_VPN_QUEUE_DEPTH"), "{}", e);
+    }
+}
@@ -0,0 +1,505 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use crate::affinity;
+use crate::ban::BanList;
+use crate::config::SharedConfig;
+use crate::error::Result;
+use crate::filter::Filter;
+use crate::handoff;
+use crate::json::Object;
+use crate::logging;
+use crate::mux::Requests;
+use crate::protocol::MAX_LINE_LEN;
+use crate::reload;
+use crate::session::SessionManager;
+use crate::status::{Health, Status};
+use crate::web;
+
+pub const DEFAULT_SOCKET: &str = "/run/vpn.sock";
+
+// State the control socket can query and act on
+pub struct Context {
+    pub config: SharedConfig,
+    pub status: Arc<Status>,
+    // Server only
+    pub sessions: Option<Arc<SessionManager>>,
+    pub bans: Option<Arc<BanList>>,
+    // Client only: requests to the server over the control channel
+    pub peer: Option<Arc<Requests>>,
+    // Block rules and their counters; not on the relay
+    pub filter: Option<Arc<Filter>>,
+}
+
+// Local control socket (`vpn ctl <command>`).
+// Removes the socket file when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn spawn(path: &Path, ctx: Arc<Context>) -> Result<ControlSocket> {
+        let name = format!("control {}", path.display());
+        let listener = handoff::claim(&name, || {
+            // Remove a stale socket left by a previous run, but never steal a live one
+            if path.exists() {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!(
+                            "Control socket {} is in use by another instance",
+                            path.display()
+                        ),
+                    ));
+                }
+                fs::remove_file(path).ok();
+            }
+            UnixListener::bind(path)
+        })?;
+        info!("Control socket listening on {}", path.display());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle(stream, &ctx) {
+                            warn!("Control connection error: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Control socket accept failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+// Start the control socket and, if configured, the health endpoint and
+// web dashboard.
+// Failures are logged but do not stop the VPN itself.
+pub fn start(ctx: Arc<Context>) -> Option<ControlSocket> {
+    let (path, healthz, web, web_password) = {
+        let c = ctx.config.read().unwrap();
+        (
+            c.ctl_socket_path(),
+            c.healthz.clone(),
+            c.web.clone(),
+            c.web_password.clone(),
+        )
+    };
+    if let Some(addr) = healthz {
+        if let Err(e) = spawn_healthz(&addr, ctx.status.clone()) {
+            error!("Cannot start health endpoint on {}: {}", addr, e);
+        }
+    }
+    if let Some(addr) = web {
+        if let Err(e) = web::spawn(&addr, web_password, ctx.clone()) {
+            error!("Cannot start web dashboard on {}: {}", addr, e);
+        }
+    }
+    match ControlSocket::spawn(&path, ctx) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            error!("Cannot start control socket {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn handle(stream: UnixStream, ctx: &Context) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let json = words.contains(&"--json");
+    let command = words
+        .into_iter()
+        .filter(|&word| word != "--json")
+        .collect::<Vec<_>>()
+        .join(" ");
+    let command = command.as_str();
+    debug!("Control command: {}", command);
+
+    if json {
+        let reply = if command == "trace" || command.starts_with("trace ") {
+            Some(error_json("trace has no JSON output"))
+        } else {
+            query_json(ctx, command)
+        };
+        if let Some(reply) = reply {
+            (&stream).write_all(format!("{}\n", reply).as_bytes())?;
+            return Ok(());
+        }
+    }
+    let finish = |reply: String| if json { text_to_json(&reply) } else { reply };
+    let reply = match command {
+        "health" | "status" => ctx.status.health_report(),
+        "clients" => match &ctx.sessions {
+            Some(sessions) => sessions.report(),
+            None => "error: not a server\n".to_string(),
+        },
+        "reload" => match reload::reload(&ctx.config) {
+            Ok(()) => "ok\n".to_string(),
+            Err(e) => format!("error: {}\n", e),
+        },
+        "bans" => match &ctx.bans {
+            Some(bans) => bans.report(),
+            None => "error: not a server\n".to_string(),
+        },
+        "cpus" => match &ctx.sessions {
+            Some(sessions) => affinity::report(&ctx.config.read().unwrap().cpus, &sessions.list()),
+            None => "error: not a server\n".to_string(),
+        },
+        "blocked" => match &ctx.filter {
+            Some(filter) => filter.report(),
+            None => "error: no tunnel traffic here\n".to_string(),
+        },
+        "upgrade" => match handoff::upgrade() {
+            Ok(()) => {
+                (&stream).write_all(finish("ok\n".to_string()).as_bytes())?;
+                std::process::exit(0);
+            }
+            Err(e) => format!("error: {}\n", e),
+        },
+        "ping" | "peer-stats" => match &ctx.peer {
+            Some(peer) => ask_peer(peer, command),
+            None => "error: not a client\n".to_string(),
+        },
+        _ if command.starts_with("ban ") || command.starts_with("unban ") => ban(ctx, command),
+        _ if command.starts_with("log-level") => log_level(command),
+        _ if command.starts_with("dump-packets") => dump_packets(command),
+        _ if command == "trace" || command.starts_with("trace ") => {
+            return trace(stream, ctx, command)
+        }
+        _ => format!("error: unknown command: {}\n", command),
+    };
+    (&stream).write_all(finish(reply).as_bytes())?;
+    Ok(())
+}
+
+// `<command> --json`: the same answers as JSON, one document per reply, for
+// scripts and the web dashboard instead of scraping the text. Keys are only
+// ever added, never renamed or dropped. Queries answer with:
+//
+//   health, status  {"health","code","role","tun","listener","session","last_rx","uptime"}
+//                   (tun, listener, session and last_rx as they apply to the role)
+//   clients         [{"id","addr","peer","uptime","rx_packets","rx_bytes",
+//                     "tx_packets","tx_bytes","tx_dropped"}]
+//   bans            [{"addr","remaining"}]  (remaining: seconds, null if manual)
+//   blocked         [{"rule","dropped"}]
+//   cpus            [{"cpu","tun","sessions","rx_packets","rx_bytes",
+//                     "tx_packets","tx_bytes","tx_dropped"}]
+//   log-level       {"level"}
+//   dump-packets    {"dump"}  ("on", "off" or "auto")
+//   ping            {"rtt_ms"}
+//   peer-stats      {"uptime","rx_packets","rx_bytes",...}  (the server's counters)
+//
+// and everything else with {"ok":true} or {"error":"<message>"}.
+// None if `command` is not a query; its text reply is converted then.
+fn query_json(ctx: &Context, command: &str) -> Option<String> {
+    let not_server = || error_json("not a server");
+    let reply = match command {
+        "health" | "status" => ctx.status.health_json(),
+        "clients" => ctx.sessions.as_ref().map_or_else(not_server, |s| s.json()),
+        "bans" => ctx.bans.as_ref().map_or_else(not_server, |b| b.json()),
+        "cpus" => match &ctx.sessions {
+            Some(sessions) => affinity::json(&ctx.config.read().unwrap().cpus, &sessions.list()),
+            None => not_server(),
+        },
+        "blocked" => match &ctx.filter {
+            Some(filter) => filter.json(),
+            None => error_json("no tunnel traffic here"),
+        },
+        "log-level" => Object::new()
+            .str("level", &log::max_level().to_string().to_lowercase())
+            .build(),
+        "dump-packets" => {
+            let dump = dump_packets(command);
+            Object::new().str("dump", dump.trim_end()).build()
+        }
+        "ping" | "peer-stats" => {
+            let Some(peer) = &ctx.peer else {
+                return Some(error_json("not a client"));
+            };
+            let text = ask_peer(peer, command);
+            if let Some(e) = text.strip_prefix("error: ") {
+                return Some(error_json(e.trim_end()));
+            }
+            // `rtt=1.5ms`, or the server's `key=value` counters
+            let mut object = Object::new();
+            for (key, value) in text.split_whitespace().filter_map(|w| w.split_once('=')) {
+                object = match (key, value.strip_suffix("ms")) {
+                    ("rtt", Some(ms)) => object.num("rtt_ms", ms),
+                    _ if value.parse::<u64>().is_ok() => object.num(key, value),
+                    _ => object.str(key, value),
+                };
+            }
+            object.build()
+        }
+        _ => return None,
+    };
+    Some(reply)
+}
+
+// A text reply as JSON: `ok` and `error: ...`, or else the text as it is
+fn text_to_json(reply: &str) -> String {
+    let reply = reply.trim_end();
+    let json = match reply.strip_prefix("error: ") {
+        Some(e) => error_json(e),
+        None if reply == "ok" => Object::new().bool("ok", true).build(),
+        None => Object::new().str("text", reply).build(),
+    };
+    json + "\n"
+}
+
+fn error_json(message: &str) -> String {
+    Object::new().str("error", message).build()
+}
+
+// `ban <ip> [seconds]` and `unban <ip>`. Banning also ends the source's
+// current sessions.
+fn ban(ctx: &Context, command: &str) -> String {
+    let (Some(bans), Some(sessions)) = (&ctx.bans, &ctx.sessions) else {
+        return "error: not a server\n".to_string();
+    };
+    let args: Vec<&str> = command.split_whitespace().collect();
+    let Some(Ok(ip)) = args.get(1).map(|ip| ip.parse::<IpAddr>()) else {
+        return format!("error: invalid address: {}\n", args.get(1).unwrap_or(&""));
+    };
+    match (args[0], args.get(2)) {
+        ("ban", time) => {
+            let time = match time.map(|t| t.parse()) {
+                None => None,
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                Some(Err(_)) => return format!("error: invalid seconds: {}\n", time.unwrap()),
+            };
+            bans.ban(ip, time);
+            for session in sessions.list() {
+                if session.peer.ip().map(|p| p.to_canonical()) == Some(ip.to_canonical()) {
+                    session.close();
+                }
+            }
+            "ok\n".to_string()
+        }
+        (_, None) if bans.unban(ip) => "ok\n".to_string(),
+        (_, None) => format!("error: {} is not banned\n", ip),
+        _ => "error: usage: unban <ip>\n".to_string(),
+    }
+}
+
+// `log-level [level]`: show or change the log level. A reload sets it back
+// to log_level from the config file, if there is one there.
+fn log_level(command: &str) -> String {
+    match command.split_whitespace().collect::<Vec<_>>()[..] {
+        ["log-level"] => format!("{}\n", log::max_level()).to_lowercase(),
+        ["log-level", level] => match level.parse() {
+            Ok(level) => match logging::set_level(level) {
+                Ok(()) => {
+                    info!("Log level set to {}", level);
+                    "ok\n".to_string()
+                }
+                Err(e) => format!("error: {}\n", e),
+            },
+            Err(_) => format!("error: invalid level: {}\n", level),
+        },
+        _ => "error: usage: log-level [off|error|warn|info|debug|trace]\n".to_string(),
+    }
+}
+
+// `dump-packets [on|off|auto]`: hex dumps of every packet regardless of the
+// log level, none at all, or (auto, the default) with debug logging
+fn dump_packets(command: &str) -> String {
+    let dump = match command.split_whitespace().collect::<Vec<_>>()[..] {
+        ["dump-packets"] => {
+            return match logging::dump() {
+                Some(true) => "on\n",
+                Some(false) => "off\n",
+                None => "auto\n",
+            }
+            .to_string()
+        }
+        ["dump-packets", "on"] => Some(true),
+        ["dump-packets", "off"] => Some(false),
+        ["dump-packets", "auto"] => None,
+        _ => return "error: usage: dump-packets [on|off|auto]\n".to_string(),
+    };
+    logging::set_dump(dump);
+    "ok\n".to_string()
+}
+
+// `trace <client> [count]`, also as `trace --client <client> --count <n>`:
+// stream summaries of one session's packets (see trace) until `count` were
+// shown, the session ends or the caller hangs up. The client is a session
+// id or tunnel address. Runs on its own thread so other commands are not
+// held up.
+fn trace(stream: UnixStream, ctx: &Context, command: &str) -> Result<()> {
+    let mut stream = stream;
+    let Some(sessions) = &ctx.sessions else {
+        stream.write_all(b"error: not a server\n")?;
+        return Ok(());
+    };
+    let (mut client, mut count) = (None, None);
+    let mut words = command.split_whitespace().skip(1);
+    while let Some(word) = words.next() {
+        match word {
+            "--client" => client = words.next(),
+            "--count" => count = words.next(),
+            _ if client.is_none() => client = Some(word),
+            _ if count.is_none() => count = Some(word),
+            // Too many arguments: answered with the usage below
+            _ => client = None,
+        }
+    }
+    let count = match count.map(str::parse::<u64>) {
+        None => None,
+        Some(Ok(n)) if n > 0 => Some(n),
+        Some(_) => {
+            stream.write_all(b"error: invalid count\n")?;
+            return Ok(());
+        }
+    };
+    let Some(client) = client else {
+        stream.write_all(b"error: usage: trace <id|address> [count]\n")?;
+        return Ok(());
+    };
+    let Some(session) = sessions
+        .list()
+        .into_iter()
+        .find(|s| s.id.to_string() == client || s.addr.to_string() == client)
+    else {
+        stream.write_all(format!("error: no session {}\n", client).as_bytes())?;
+        return Ok(());
+    };
+    let packets = session.watch();
+    writeln!(
+        stream,
+        "tracing session {} ({}); rx = from the client, tx = to the client",
+        session.id, session.addr
+    )?;
+    drop(session);
+
+    thread::spawn(move || {
+        let mut shown = 0;
+        while count.is_none_or(|count| shown < count) {
+            match packets.recv_timeout(Duration::from_secs(1)) {
+                Ok(line) => {
+                    if writeln!(stream, "{}", line).is_err() {
+                        return;
+                    }
+                    shown += 1;
+                }
+                Err(RecvTimeoutError::Timeout) if hung_up(&stream) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    stream.write_all(b"session ended\n").ok();
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Whether the other end of a control connection has gone away
+fn hung_up(stream: &UnixStream) -> bool {
+    stream.set_read_timeout(Some(Duration::from_millis(1))).ok();
+    matches!((&*stream).read(&mut [0u8; 1]), Ok(0))
+}
+
+// `ping` (round-trip time to the server) and `peer-stats` (the server's
+// counters for this session)
+fn ask_peer(peer: &Requests, command: &str) -> String {
+    let verb = if command == "ping" { "PING" } else { "STATS" };
+    match peer.request(verb, "") {
+        Ok((_, rtt)) if verb == "PING" => format!("rtt={:.1}ms\n", rtt.as_secs_f64() * 1000.0),
+        Ok((text, _)) => format!("{}\n", text),
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+// Client side: send one command and return the full reply
+pub fn request(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+// Client side: send a command whose reply keeps coming (trace) and copy it
+// to `out` line by line. Returns false if the reply is an error.
+pub fn follow(path: &Path, command: &str, out: &mut impl Write) -> Result<bool> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut first = true;
+    while reader.read_line(&mut line)? > 0 {
+        if first && (line.starts_with("error:") || line.starts_with("{\"error\":")) {
+            out.write_all(line.as_bytes())?;
+            return Ok(false);
+        }
+        first = false;
+        out.write_all(line.as_bytes())?;
+        out.flush()?;
+        line.clear();
+    }
+    Ok(true)
+}
+
+// Minimal HTTP endpoint answering GET /healthz with 200 or 503
+pub fn spawn_healthz(addr: &str, status: Arc<Status>) -> Result<()> {
+    let listener = handoff::claim(&format!("healthz {}", addr), || TcpListener::bind(addr))?;
+    info!("Health endpoint listening on http://{}/healthz", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // One request at a time, so a checker that sends nothing or
+            // never ends its line must not hold up the others
+            stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+            let mut request_line = String::new();
+            if BufReader::new((&stream).take(MAX_LINE_LEN as u64))
+                .read_line(&mut request_line)
+                .is_err()
+            {
+                continue;
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or("");
+            let (code, body) = if path == "/healthz" {
+                let body = status.health_report();
+                match status.health() {
+                    Health::Healthy => ("200 OK", body),
+                    _ => ("503 Service Unavailable", body),
+                }
+            } else {
+                ("404 Not Found", "not found\n".to_string())
+            };
+            let response = format!(
+                "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                code,
+                body.len(),
+                body
+            );
+            (&stream).write_all(response.as_bytes()).ok();
+        }
+    });
+    Ok(())
+}
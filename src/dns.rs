@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::packet;
+
+// Caching DNS forwarder on the client (`dns_listen = 127.0.0.53:53`).
+//
+// Pointing the system resolver at a fixed local address once is simpler,
+// and on some platforms sturdier, than changing it on every connect. The
+// forwarder listens there (UDP) and passes queries on to the resolvers the
+// server pushes with `dns`, which it takes instead of the system.
+//
+// Queries to the resolvers go from the tunnel address straight into the
+// send queue, like the watchdog's probes, and their answers are taken out
+// before they reach the TUN: they cannot leave through another interface
+// whatever the routes say. Without resolvers, e.g. before the first session,
+// queries are answered with SERVFAIL.
+//
+// Answers are cached for the smallest TTL in them (at most MAX_TTL), and
+// served from the cache even while the tunnel is down. Truncated answers
+// are passed on uncached; there is no TCP listener to retry on.
+#[derive(Debug)]
+pub struct Forwarder {
+    socket: UdpSocket,
+    // Our end of queries into the tunnel
+    source: SocketAddr,
+    resolvers: Mutex<Vec<IpAddr>>,
+    // Resolver the next query goes to, round robin
+    next: AtomicUsize,
+    pending: Mutex<HashMap<u16, Pending>>,
+    next_id: AtomicU16,
+    cache: Mutex<HashMap<Vec<u8>, Cached>>,
+}
+
+// A query sent on, under the id it was given
+#[derive(Debug)]
+struct Pending {
+    client: SocketAddr,
+    id: u16,
+    resolver: IpAddr,
+    key: Option<Vec<u8>>,
+    sent: Instant,
+}
+
+#[derive(Debug)]
+struct Cached {
+    answer: Vec<u8>,
+    // Where the TTLs are in `answer`
+    ttls: Vec<usize>,
+    stored: Instant,
+    expires: Instant,
+}
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+// Queries unanswered for this long are forgotten
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PENDING: usize = 1024;
+const MAX_CACHED: usize = 4096;
+// Longest an answer is cached, whatever its TTL says
+const MAX_TTL: u32 = 3600;
+const TYPE_OPT: u16 = 41;
+const RCODE_SERVFAIL: u8 = 2;
+
+impl Forwarder {
+    // Listen on `listen` and send queries from `source`, our tunnel address,
+    // through `send`
+    pub fn start(
+        listen: SocketAddr,
+        source: IpAddr,
+        send: impl Fn(Vec<u8>) -> bool + Send + 'static,
+    ) -> io::Result<Arc<Forwarder>> {
+        let socket = UdpSocket::bind(listen)?;
+        info!("DNS forwarder listening on {}", listen);
+        let forwarder = Arc::new(Forwarder {
+            socket,
+            // A port of the ephemeral range for this process, as the
+            // watchdog picks its ICMP id
+            source: SocketAddr::new(source, 0xc000 | (std::process::id() as u16 & 0x3fff)),
+            resolvers: Mutex::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(std::process::id() as u16),
+            cache: Mutex::new(HashMap::new()),
+        });
+        let this = forwarder.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                let (n, client) = match this.socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("DNS forwarder: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(packet) = this.query(&buf[..n], client) {
+                    if !send(packet) {
+                        debug!("Send queue full; dropping DNS query.");
+                    }
+                }
+            }
+        });
+        Ok(forwarder)
+    }
+
+    // Resolvers pushed by the server; those of the other address family
+    // than the tunnel are left out
+    pub fn set_resolvers(&self, resolvers: &[IpAddr]) {
+        let usable: Vec<IpAddr> = resolvers
+            .iter()
+            .copied()
+            .filter(|r| r.is_ipv4() == self.source.is_ipv4())
+            .collect();
+        if usable.len() < resolvers.len() {
+            info!(
+                "DNS forwarder: skipping resolvers not reachable from {}",
+                self.source.ip()
+            );
+        }
+        let mut current = self.resolvers.lock().unwrap();
+        if *current != usable {
+            info!("DNS forwarder resolving through {:?}", usable);
+            *current = usable;
+            self.cache.lock().unwrap().clear();
+        }
+    }
+
+    // A packet for the tunnel carrying `query` on to a resolver, or None
+    // if it was answered here
+    fn query(&self, query: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+        if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+            return None;
+        }
+        let key = question_end(query).map(|end| query[HEADER_LEN..end].to_ascii_lowercase());
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        if let Some(answer) = key.as_ref().and_then(|key| self.cached(key, id)) {
+            debug!("DNS forwarder: answering {} from the cache", client);
+            self.socket.send_to(&answer, client).ok();
+            return None;
+        }
+        let resolver = {
+            let resolvers = self.resolvers.lock().unwrap();
+            match resolvers.len() {
+                0 => None,
+                n => Some(resolvers[self.next.fetch_add(1, Ordering::Relaxed) % n]),
+            }
+        };
+        let Some(resolver) = resolver else {
+            debug!("DNS forwarder: no resolvers for {}", client);
+            if let Some(answer) = servfail(query) {
+                self.socket.send_to(&answer, client).ok();
+            }
+            return None;
+        };
+        let upstream_id = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, p| p.sent.elapsed() < QUERY_TIMEOUT);
+            if pending.len() >= MAX_PENDING {
+                debug!("DNS forwarder: too many queries in flight");
+                return None;
+            }
+            let mut upstream_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&upstream_id) {
+                upstream_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(
+                upstream_id,
+                Pending {
+                    client,
+                    id,
+                    resolver,
+                    key,
+                    sent: Instant::now(),
+                },
+            );
+            upstream_id
+        };
+        let mut query = query.to_vec();
+        query[..2].copy_from_slice(&upstream_id.to_be_bytes());
+        packet::udp(self.source, SocketAddr::new(resolver, DNS_PORT), &query)
+    }
+
+    // Whether `packet` is an answer to one of our queries; it is not for
+    // the TUN
+    pub fn take_reply(&self, packet: &[u8]) -> bool {
+        let (from, to, answer) = match packet::udp_payload(packet) {
+            Some(datagram) if datagram.1 == self.source && datagram.0.port() == DNS_PORT => {
+                datagram
+            }
+            _ => return false,
+        };
+        if answer.len() < HEADER_LEN {
+            return true;
+        }
+        let upstream_id = u16::from_be_bytes([answer[0], answer[1]]);
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&upstream_id) {
+                Some(p) if p.resolver == from.ip() => pending.remove(&upstream_id),
+                _ => None,
+            }
+        };
+        let Some(pending) = pending else {
+            debug!("DNS forwarder: unexpected answer from {} to {}", from, to);
+            return true;
+        };
+        let mut answer = answer.to_vec();
+        answer[..2].copy_from_slice(&pending.id.to_be_bytes());
+        if let Some(key) = pending.key {
+            self.store(key, &answer);
+        }
+        if let Err(e) = self.socket.send_to(&answer, pending.client) {
+            debug!("DNS forwarder: cannot answer {}: {}", pending.client, e);
+        }
+        true
+    }
+
+    // The cached answer to the question `key`, as the answer to query `id`
+    fn cached(&self, key: &[u8], id: u16) -> Option<Vec<u8>> {
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.get(key)?;
+        if entry.expires <= Instant::now() {
+            cache.remove(key);
+            return None;
+        }
+        let age = entry.stored.elapsed().as_secs() as u32;
+        let mut answer = entry.answer.clone();
+        answer[..2].copy_from_slice(&id.to_be_bytes());
+        for &at in &entry.ttls {
+            let ttl = u32::from_be_bytes(answer[at..at + 4].try_into().unwrap());
+            answer[at..at + 4].copy_from_slice(&ttl.saturating_sub(age).to_be_bytes());
+        }
+        Some(answer)
+    }
+
+    // Cache `answer` if it is complete and says how long it is good for
+    fn store(&self, key: Vec<u8>, answer: &[u8]) {
+        let truncated = answer[2] & 0x02 != 0;
+        let rcode = answer[3] & 0x0f;
+        // Only answers and "no such name"
+        if truncated || (rcode != 0 && rcode != 3) {
+            return;
+        }
+        let Some(ttls) = ttl_offsets(answer) else {
+            return;
+        };
+        let Some(ttl) = ttls
+            .iter()
+            .map(|&at| u32::from_be_bytes(answer[at..at + 4].try_into().unwrap()))
+            .min()
+            .filter(|&ttl| ttl > 0)
+        else {
+            return;
+        };
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, entry| entry.expires > now);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            key,
+            Cached {
+                answer: answer.to_vec(),
+                ttls,
+                stored: now,
+                expires: now + Duration::from_secs(ttl.min(MAX_TTL) as u64),
+            },
+        );
+    }
+}
+
+// End of the question of a message with exactly one, which is what a
+// query has
+fn question_end(msg: &[u8]) -> Option<usize> {
+    if msg.len() < HEADER_LEN || u16::from_be_bytes([msg[4], msg[5]]) != 1 {
+        return None;
+    }
+    let mut at = HEADER_LEN;
+    loop {
+        let len = *msg.get(at)? as usize;
+        // No compression in a lone question
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        at += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let end = at + 4;
+    (end <= msg.len()).then_some(end)
+}
+
+// Past the (possibly compressed) name at `at`
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(at + 2),
+            _ if len & 0xc0 != 0 => return None,
+            _ => at += 1 + len,
+        }
+    }
+}
+
+// Where the TTLs of all records of a message are, leaving out the EDNS
+// record, which has none
+fn ttl_offsets(msg: &[u8]) -> Option<Vec<usize>> {
+    let mut at = question_end(msg)?;
+    let records: usize = (6..12)
+        .step_by(2)
+        .map(|i| u16::from_be_bytes([msg[i], msg[i + 1]]) as usize)
+        .sum();
+    let mut ttls = Vec::new();
+    for _ in 0..records {
+        at = skip_name(msg, at)?;
+        let fixed = msg.get(at..at + 10)?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if kind != TYPE_OPT {
+            ttls.push(at + 4);
+        }
+        at += 10 + len;
+        if at > msg.len() {
+            return None;
+        }
+    }
+    Some(ttls)
+}
+
+// SERVFAIL for `query`, with its question
+fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    let end = question_end(query)?;
+    let mut answer = query[..end].to_vec();
+    // Response, recursion available; counts: just the question
+    answer[2] |= 0x80;
+    answer[3] = 0x80 | RCODE_SERVFAIL;
+    answer[6..12].fill(0);
+    Some(answer)
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+// Errors returned by the library API.
+//
+// Each variant corresponds to one stage of a VPN session so that callers can
+// react programmatically (e.g. retry on Transport, abort on Tun) instead of
+// matching on message strings.
+#[derive(Debug)]
+pub enum VpnError {
+    // Creating or configuring the TUN device failed.
+    Tun { context: String, source: io::Error },
+    // Invalid or missing configuration.
+    Config(String),
+    // The peer sent an unexpected or malformed handshake.
+    Handshake(String),
+    // The server turned the client away ("ERR <reason>").
+    Rejected(String),
+    // The peer speaks a protocol version or chose options we do not support.
+    Incompatible(String),
+    // A frame on the wire was malformed or exceeded a limit.
+    Framing(String),
+    // Encryption, decryption or key handling failed.
+    Crypto(String),
+    // The outer connection failed (connect, read, write).
+    Transport(io::Error),
+    // The connection of an established session failed.
+    Lost(String),
+}
+
+pub type Result<T> = std::result::Result<T, VpnError>;
+
+// Why the client, server or relay gave up; `code` is its exit status, so
+// scripts wrapping it can tell the cases apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Other = 1,
+    Config = 2,
+    Tun = 3,
+    AuthRejected = 10,
+    ServerFull = 11,
+    VersionMismatch = 12,
+    AddressConflict = 13,
+    AddressRejected = 14,
+    Unreachable = 15,
+    QuotaExceeded = 16,
+    ConnectionLost = 17,
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Failure::Other => "error",
+            Failure::Config => "config-error",
+            Failure::Tun => "tun-error",
+            Failure::AuthRejected => "auth-rejected",
+            Failure::ServerFull => "server-full",
+            Failure::VersionMismatch => "version-mismatch",
+            Failure::AddressConflict => "address-conflict",
+            Failure::AddressRejected => "address-rejected",
+            Failure::Unreachable => "unreachable",
+            Failure::QuotaExceeded => "quota-exceeded",
+            Failure::ConnectionLost => "connection-lost",
+        }
+    }
+}
+
+impl VpnError {
+    pub fn tun(context: impl Into<String>, source: io::Error) -> VpnError {
+        VpnError::Tun {
+            context: context.into(),
+            source,
+        }
+    }
+
+    // The server ended the session with `CLOSE <reason>` (see mux), e.g.
+    // QUOTA_EXCEEDED; such reasons read like the ones of a refused handshake
+    pub fn closed(reason: &str) -> VpnError {
+        VpnError::Rejected(reason.to_ascii_lowercase().replace('_', " "))
+    }
+
+    // The underlying I/O error kind, if any (e.g. PermissionDenied).
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            VpnError::Tun { source, .. } => Some(source.kind()),
+            VpnError::Transport(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    pub fn failure(&self) -> Failure {
+        match self {
+            VpnError::Config(_) => Failure::Config,
+            VpnError::Tun { .. } => Failure::Tun,
+            VpnError::Rejected(reason) => match reason.as_str() {
+                "authentication failed" => Failure::AuthRejected,
+                "server full" => Failure::ServerFull,
+                "address in use" => Failure::AddressConflict,
+                "address not allowed" | "invalid address" => Failure::AddressRejected,
+                "quota exceeded" => Failure::QuotaExceeded,
+                _ => Failure::Other,
+            },
+            VpnError::Incompatible(_) => Failure::VersionMismatch,
+            VpnError::Lost(_) => Failure::ConnectionLost,
+            VpnError::Transport(e) if reached_nothing(e) => Failure::Unreachable,
+            // Local I/O (a file, a socket of our own) also ends up here
+            VpnError::Transport(_)
+            | VpnError::Handshake(_)
+            | VpnError::Framing(_)
+            | VpnError::Crypto(_) => Failure::Other,
+        }
+    }
+}
+
+// Whether an I/O error means the server could not be reached or dropped
+// the connection, as opposed to a local failure
+fn reached_nothing(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+            | ErrorKind::AddrNotAvailable
+    )
+}
+
+impl fmt::Display for VpnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VpnError::Tun { context, source } => write!(f, "TUN error: {}: {}", context, source),
+            VpnError::Config(msg) => write!(f, "configuration error: {}", msg),
+            VpnError::Handshake(msg) => write!(f, "handshake error: {}", msg),
+            VpnError::Rejected(reason) => write!(f, "server rejected the client: {}", reason),
+            VpnError::Incompatible(msg) => write!(f, "incompatible peer: {}", msg),
+            VpnError::Framing(msg) => write!(f, "framing error: {}", msg),
+            VpnError::Crypto(msg) => write!(f, "crypto error: {}", msg),
+            VpnError::Transport(e) => write!(f, "transport error: {}", e),
+            VpnError::Lost(msg) => write!(f, "connection lost: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for VpnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VpnError::Tun { source, .. } => Some(source),
+            VpnError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VpnError {
+    fn from(e: io::Error) -> VpnError {
+        VpnError::Transport(e)
+    }
+}
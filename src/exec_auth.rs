@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::plugin::{AuthBackend, AuthRequest};
+
+// How long the program may take before the client is rejected
+const TIMEOUT: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(20);
+
+// `auth_exec = /path/to/program`: ask an external program whether a client
+// may connect, e.g. a script talking to PAM, RADIUS or an LDAP directory.
+//
+// The program gets VPN_PEER (where the client connects from) and VPN_ADDR
+// (the tunnel address it asked for) in its environment, and the handshake
+// options on stdin as `key=value` lines. Credentials travel on stdin rather
+// than in the environment so they are not inherited by anything the
+// program starts. Exit status 0 accepts the client; anything else, or no
+// answer within TIMEOUT, rejects it.
+#[derive(Debug)]
+pub struct ExecAuth {
+    program: String,
+}
+
+impl ExecAuth {
+    pub fn new(program: String) -> ExecAuth {
+        ExecAuth { program }
+    }
+}
+
+impl AuthBackend for ExecAuth {
+    fn authenticate(&self, request: &AuthRequest) -> Result<(), String> {
+        let mut child = Command::new(&self.program)
+            .env("VPN_PEER", request.peer.to_string())
+            .env("VPN_ADDR", request.addr.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("cannot run {}: {}", self.program, e))?;
+
+        let input: String = request
+            .options
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect();
+        let mut stdin = child.stdin.take().unwrap();
+        // A program that exits without reading its input is fine
+        if let Err(e) = stdin.write_all(input.as_bytes()) {
+            debug!("Writing to {} failed: {}", self.program, e);
+        }
+        drop(stdin);
+
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if started.elapsed() < TIMEOUT => thread::sleep(POLL),
+                Ok(None) => {
+                    warn!(
+                        "{} did not answer within {}s",
+                        self.program,
+                        TIMEOUT.as_secs()
+                    );
+                    child.kill().ok();
+                    child.wait().ok();
+                    return Err(format!("{} timed out", self.program));
+                }
+                Err(e) => return Err(format!("waiting for {} failed: {}", self.program, e)),
+            }
+        };
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("{} rejected the client ({})", self.program, status))
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::logging;
+
+// How often a listener checks whether its session has ended
+const POLL: Duration = Duration::from_millis(250);
+// How long connecting to the client's service may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Server ports forwarded to a service on a client, so something behind a
+// client's NAT can be reached at the server's address
+// (`expose = 8022:22,8080:80` in the client's [client] section).
+//
+// While the client has a session, the server listens on each port and
+// connects every accepted connection to the client's tunnel address, so
+// the traffic goes through the TUN like any other. When the session ends
+// the listeners close and open connections are cut. Sessions handed to a
+// new process by an upgrade get their listeners again there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub bind: SocketAddr,
+    // Port of the service on the client
+    pub port: u16,
+}
+
+impl Mapping {
+    // `[bind_addr:]serverport:clientport`; binds to all IPv4 addresses
+    // unless told otherwise. An IPv6 bind address goes in brackets.
+    pub fn parse(text: &str) -> Option<Mapping> {
+        let (rest, port) = text.rsplit_once(':')?;
+        let (addr, local) = match rest.rsplit_once(':') {
+            Some((addr, local)) => (
+                addr.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .ok()?,
+                local,
+            ),
+            None => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), rest),
+        };
+        Some(Mapping {
+            bind: SocketAddr::new(addr, local.parse().ok()?),
+            port: port.parse().ok().filter(|&p| p > 0)?,
+        })
+    }
+}
+
+// The listeners of one session; dropping it tears them down
+#[derive(Debug)]
+pub struct Exposed {
+    stop: Arc<AtomicBool>,
+    listeners: Vec<JoinHandle<()>>,
+    conns: Arc<Connections>,
+}
+
+// Open connections by id, both ends, for cutting them at the end
+#[derive(Debug, Default)]
+struct Connections {
+    open: Mutex<HashMap<u64, (TcpStream, TcpStream)>>,
+    next_id: AtomicU64,
+}
+
+impl Exposed {
+    // Listen on every mapping for the client at `client`. A port that
+    // cannot be bound is logged and skipped.
+    pub fn start(client: IpAddr, mappings: &[Mapping]) -> Exposed {
+        let stop = Arc::new(AtomicBool::new(false));
+        let conns = Arc::new(Connections::default());
+        let mut listeners = Vec::new();
+        for &mapping in mappings {
+            let target = SocketAddr::new(client, mapping.port);
+            let listener = match listen(mapping.bind) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!("Cannot expose {} on {}: {}", target, mapping.bind, e);
+                    continue;
+                }
+            };
+            info!("Exposing {} on {}", target, mapping.bind);
+            let (stop, conns) = (stop.clone(), conns.clone());
+            listeners.push(logging::spawn(move || {
+                accept_loop(listener, target, &stop, &conns)
+            }));
+        }
+        Exposed {
+            stop,
+            listeners,
+            conns,
+        }
+    }
+}
+
+impl Drop for Exposed {
+    // Close the listeners before returning, so the ports are free again
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for handle in self.listeners.drain(..) {
+            handle.join().ok();
+        }
+        for (_, (a, b)) in self.conns.open.lock().unwrap().drain() {
+            a.shutdown(Shutdown::Both).ok();
+            b.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+// Non-blocking, so the accept loop notices when to stop
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn accept_loop(
+    listener: TcpListener,
+    target: SocketAddr,
+    stop: &AtomicBool,
+    conns: &Arc<Connections>,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        let (conn, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Accepting for {} failed: {}", target, e);
+                thread::sleep(POLL);
+                continue;
+            }
+        };
+        let conns = conns.clone();
+        debug!("Connection from {} for {}", peer, target);
+        logging::spawn(move || {
+            if let Err(e) = proxy(conn, target, &conns) {
+                info!("Connection from {} to {} failed: {}", peer, target, e);
+            }
+        });
+    }
+}
+
+// Connect `conn` to the client's service and copy both ways until both
+// sides are done
+fn proxy(conn: TcpStream, target: SocketAddr, conns: &Connections) -> io::Result<()> {
+    conn.set_nonblocking(false)?;
+    let service = TcpStream::connect_timeout(&target, CONNECT_TIMEOUT)?;
+    let id = conns.next_id.fetch_add(1, Ordering::Relaxed);
+    conns
+        .open
+        .lock()
+        .unwrap()
+        .insert(id, (conn.try_clone()?, service.try_clone()?));
+    let (mut from, mut to) = (conn.try_clone()?, service.try_clone()?);
+    let upstream = logging::spawn(move || {
+        io::copy(&mut from, &mut to).ok();
+        to.shutdown(Shutdown::Write).ok();
+    });
+    let (mut from, mut to) = (service, conn);
+    io::copy(&mut from, &mut to).ok();
+    to.shutdown(Shutdown::Write).ok();
+    upstream.join().ok();
+    conns.open.lock().unwrap().remove(&id);
+    Ok(())
+}
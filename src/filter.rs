@@ -0,0 +1,131 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::debug;
+
+use crate::json::{self, Object};
+use crate::packet::{self, PROTO_ICMP, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+
+// Classes of tunnel traffic to drop (`block = ipv6,tcp:445,udp:137-139`).
+//
+// Rules apply in both directions, on the server and on the client:
+//   ipv4, ipv6          all packets of that family
+//   tcp, udp, icmp      all packets of that protocol (icmp covers ICMPv6)
+//   tcp:N, udp:N-M      TCP/UDP packets with either port in the range
+// Each rule counts the packets it dropped (`ctl blocked`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    text: String,
+    matcher: Match,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Match {
+    Family(u8),
+    Protocol(u8),
+    Ports(u8, u16, u16),
+}
+
+impl Rule {
+    pub fn parse(text: &str) -> Option<Rule> {
+        let matcher = match text {
+            "ipv4" => Match::Family(4),
+            "ipv6" => Match::Family(6),
+            "tcp" => Match::Protocol(PROTO_TCP),
+            "udp" => Match::Protocol(PROTO_UDP),
+            "icmp" => Match::Protocol(PROTO_ICMP),
+            _ => {
+                let (proto, ports) = text.split_once(':')?;
+                let proto = match proto {
+                    "tcp" => PROTO_TCP,
+                    "udp" => PROTO_UDP,
+                    _ => return None,
+                };
+                let (low, high) = ports.split_once('-').unwrap_or((ports, ports));
+                let (low, high) = (low.parse().ok()?, high.parse().ok()?);
+                if low > high {
+                    return None;
+                }
+                Match::Ports(proto, low, high)
+            }
+        };
+        Some(Rule {
+            text: text.to_string(),
+            matcher,
+        })
+    }
+
+    fn matches(&self, packet: &[u8]) -> bool {
+        match self.matcher {
+            Match::Family(version) => packet::ip_version(packet) == Some(version),
+            Match::Protocol(PROTO_ICMP) => matches!(
+                packet::transport(packet),
+                Some((PROTO_ICMP | PROTO_ICMPV6, _))
+            ),
+            Match::Protocol(proto) => packet::transport(packet).map(|(p, _)| p) == Some(proto),
+            Match::Ports(proto, low, high) => {
+                packet::transport(packet).map(|(p, _)| p) == Some(proto)
+                    && packet::ports(packet).is_some_and(|(src, dst)| {
+                        (low..=high).contains(&src) || (low..=high).contains(&dst)
+                    })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// The configured rules with their counters
+#[derive(Debug)]
+pub struct Filter {
+    rules: Vec<(Rule, AtomicU64)>,
+}
+
+impl Filter {
+    pub fn new(rules: &[Rule]) -> Filter {
+        Filter {
+            rules: rules
+                .iter()
+                .map(|rule| (rule.clone(), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    // False if a rule blocks the packet, which is then counted against it
+    pub fn allows(&self, packet: &[u8]) -> bool {
+        for (rule, dropped) in &self.rules {
+            if rule.matches(packet) {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                debug!("Dropping {}-byte packet: blocked by {}", packet.len(), rule);
+                return false;
+            }
+        }
+        true
+    }
+
+    // One `<rule> dropped=<n>` line per rule
+    pub fn report(&self) -> String {
+        if self.rules.is_empty() {
+            return "no block rules\n".to_string();
+        }
+        let mut out = String::new();
+        for (rule, dropped) in &self.rules {
+            writeln!(out, "{} dropped={}", rule, dropped.load(Ordering::Relaxed)).unwrap();
+        }
+        out
+    }
+
+    // The same as a JSON array
+    pub fn json(&self) -> String {
+        json::array(self.rules.iter().map(|(rule, dropped)| {
+            Object::new()
+                .str("rule", &rule.to_string())
+                .num("dropped", dropped.load(Ordering::Relaxed))
+                .build()
+        }))
+    }
+}
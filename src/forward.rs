@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::logging;
+use crate::protocol::cidr_contains;
+
+// A forwarded connection whose local end stops reading for this long is
+// dropped, so it cannot hold up the tunnel
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Bytes of a stream frame taken by the stream id
+const ID_LEN: usize = 4;
+// How long the server tries to reach a forwarding target; within the
+// client's wait for the reply (see mux)
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(4);
+
+// TCP connections carried over a multiplexed connection, for reaching a
+// few services on the server side without a route or a TUN
+// (`forward = 8080:intranet:80`).
+//
+// The client listens on each local port. For every connection accepted
+// there it asks the server with the control request
+// `OPEN <token> <id> <host>:<port>` to connect to that host from its side.
+// Once the server replies `ok`, the bytes of the connection go both ways
+// on the stream channel: each frame is the 4-byte big-endian stream id
+// followed by data, and a frame with no data means that side is done
+// sending. The server only connects to addresses in forward_allow.
+//
+// Forwarded connections end with the session that carries them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub bind: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Forward {
+    // `[bind_addr:]port:host:hostport`, like ssh -L; binds to 127.0.0.1
+    // unless told otherwise. IPv6 addresses go in brackets.
+    pub fn parse(text: &str) -> Option<Forward> {
+        let mut fields = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let (field, next) = match rest.strip_prefix('[') {
+                Some(inner) => {
+                    let (field, after) = inner.split_once(']')?;
+                    (field, after.strip_prefix(':').unwrap_or(after))
+                }
+                None => rest.split_once(':').unwrap_or((rest, "")),
+            };
+            fields.push(field);
+            rest = next;
+        }
+        let (bind_addr, local, host, port) = match fields[..] {
+            [local, host, port] => (IpAddr::V4(Ipv4Addr::LOCALHOST), local, host, port),
+            [addr, local, host, port] => (addr.parse().ok()?, local, host, port),
+            _ => return None,
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Forward {
+            bind: SocketAddr::new(bind_addr, local.parse().ok()?),
+            host: host.to_string(),
+            port: port.parse().ok().filter(|&p| p > 0)?,
+        })
+    }
+
+    // What the server is asked to connect to
+    pub fn target(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl std::fmt::Display for Forward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.bind, self.target())
+    }
+}
+
+// The forwarded connections of one session (server) or of the client
+#[derive(Debug)]
+pub struct Streams {
+    open: Mutex<HashMap<u32, Entry>>,
+    next_id: AtomicU32,
+    // Largest frame the peer accepts
+    mtu: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    stream: Arc<TcpStream>,
+    // Whether each side is done sending
+    local_done: bool,
+    peer_done: bool,
+}
+
+impl Streams {
+    pub fn new(mtu: usize) -> Streams {
+        Streams {
+            open: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            mtu: AtomicUsize::new(mtu),
+        }
+    }
+
+    // The MTU negotiated for a new session
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    pub fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Take `stream` as `id`; data from the peer is written to it from now
+    // on. Added before the OPEN reply, so no data can arrive before it.
+    pub fn add(&self, id: u32, stream: TcpStream) -> std::io::Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        self.open.lock().unwrap().insert(
+            id,
+            Entry {
+                stream: Arc::new(stream),
+                local_done: false,
+                peer_done: false,
+            },
+        );
+        Ok(())
+    }
+
+    // Start reading connection `id` into frames handed to `send`, which
+    // returns false once the session is gone
+    pub fn start(self: &Arc<Self>, id: u32, send: impl Fn(Vec<u8>) -> bool + Send + 'static) {
+        let Some(stream) = self.open.lock().unwrap().get(&id).map(|e| e.stream.clone()) else {
+            return;
+        };
+        let streams = self.clone();
+        logging::spawn(move || {
+            let mut buf = Vec::new();
+            loop {
+                let max = streams.mtu.load(Ordering::Relaxed).max(ID_LEN + 1);
+                buf.resize(max, 0);
+                buf[..ID_LEN].copy_from_slice(&id.to_be_bytes());
+                let n = match (&*stream).read(&mut buf[ID_LEN..]) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!("Forwarded connection {} failed: {}", id, e);
+                        streams.remove(id);
+                        send(frame(id, &[]));
+                        break;
+                    }
+                };
+                if !send(buf[..ID_LEN + n].to_vec()) {
+                    streams.remove(id);
+                    break;
+                }
+                if n == 0 {
+                    streams.done(id, |entry| entry.local_done = true);
+                    break;
+                }
+            }
+        });
+    }
+
+    // Forget connection `id` and close it
+    pub fn remove(&self, id: u32) {
+        if let Some(entry) = self.open.lock().unwrap().remove(&id) {
+            entry.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    // A stream frame from the peer
+    pub fn deliver(&self, frame: &[u8]) {
+        let Some(id) = frame
+            .get(..ID_LEN)
+            .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+        else {
+            debug!("Ignoring short stream frame.");
+            return;
+        };
+        let data = &frame[ID_LEN..];
+        if data.is_empty() {
+            self.done(id, |entry| {
+                entry.peer_done = true;
+                entry.stream.shutdown(Shutdown::Write).ok();
+            });
+            return;
+        }
+        let stream = match self.open.lock().unwrap().get(&id) {
+            Some(entry) => entry.stream.clone(),
+            None => {
+                debug!("Data for closed forwarded connection {}", id);
+                return;
+            }
+        };
+        // Written without holding the lock, which the reader thread needs
+        if let Err(e) = (&*stream).write_all(data) {
+            info!("Dropping forwarded connection {}: {}", id, e);
+            self.remove(id);
+        }
+    }
+
+    // Drop every connection, e.g. when the session ends
+    pub fn close_all(&self) {
+        for (_, entry) in self.open.lock().unwrap().drain() {
+            entry.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    // Update an entry; it is forgotten once both sides are done sending
+    fn done(&self, id: u32, update: impl FnOnce(&mut Entry)) {
+        let mut open = self.open.lock().unwrap();
+        let Some(entry) = open.get_mut(&id) else {
+            return;
+        };
+        update(entry);
+        if entry.local_done && entry.peer_done {
+            open.remove(&id);
+        }
+    }
+}
+
+// Server side: connect to `target` (host:port) for a client, if it
+// resolves to an address in `allow`
+pub fn connect(target: &str, allow: &[(IpAddr, u8)]) -> Result<TcpStream, String> {
+    if allow.is_empty() {
+        return Err("forwarding disabled".to_string());
+    }
+    let addrs: Vec<SocketAddr> = target
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {}: {}", target, e))?
+        .filter(|addr| {
+            allow
+                .iter()
+                .any(|(net, prefix)| cidr_contains(*net, *prefix, addr.ip()))
+        })
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} not allowed", target));
+    }
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(format!("{}: {}", addr, e)),
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+// A stream frame carrying `data` for connection `id`
+pub fn frame(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = id.to_be_bytes().to_vec();
+    frame.extend_from_slice(data);
+    frame
+}
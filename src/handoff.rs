@@ -0,0 +1,374 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use nix::libc;
+
+use crate::error::{Result, VpnError};
+use crate::protocol::Options;
+use crate::socket;
+
+// Zero-downtime upgrade (`ctl upgrade` or SIGUSR2).
+//
+// The running server starts the binary it was started as, with the same
+// arguments, and talks to it over a socketpair whose end is named in
+// RUST_VPN_HANDOFF_FD. Once the new process has loaded its config and said
+// hello, the old one stops its sessions between two frames and sends the
+// TUN device, its listening sockets and every session (state, connection
+// and any bytes already read but not yet decoded). The new process picks
+// them up where the old one left off and answers ready; the old one then
+// exits. If anything fails before that, the old process closes the stopped
+// sessions (their clients reconnect) and keeps serving.
+//
+// Each message on the socketpair is `<kind> [text]\n[payload]`, with at
+// most one descriptor attached.
+
+const ENV: &str = "RUST_VPN_HANDOFF_FD";
+// How long the new process may take to say hello, and then to take over
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+// Pending bytes are sent in chunks of this size
+const CHUNK: usize = 32 * 1024;
+const MAX_MESSAGE: usize = CHUNK + 1024;
+
+// Set while sessions are being stopped and handed over
+static FREEZING: AtomicBool = AtomicBool::new(false);
+static UPGRADING: AtomicBool = AtomicBool::new(false);
+// Listening sockets of this process, passed on by an upgrade
+static SOCKETS: Mutex<Vec<(String, RawFd)>> = Mutex::new(Vec::new());
+// Sockets passed on by the previous process and not yet claimed
+static INHERITED: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+// Set by the server once it can hand over
+type Hook = Box<dyn Fn() -> Result<()> + Send + Sync>;
+static HOOK: Mutex<Option<Hook>> = Mutex::new(None);
+
+// A socket to listen on: the one the previous process passed on under
+// `name`, or a new one from `bind`. Either way the next upgrade passes it on.
+pub fn claim<T: From<OwnedFd> + AsRawFd>(
+    name: &str,
+    bind: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    let inherited = {
+        let mut inherited = INHERITED.lock().unwrap();
+        let found = inherited.iter().position(|(n, _)| n == name);
+        found.map(|i| inherited.remove(i).1)
+    };
+    let socket = match inherited {
+        Some(fd) => {
+            debug!("Using inherited socket for {}", name);
+            T::from(fd)
+        }
+        None => bind()?,
+    };
+    SOCKETS
+        .lock()
+        .unwrap()
+        .push((name.to_string(), socket.as_raw_fd()));
+    Ok(socket)
+}
+
+// Whether sessions should stop at the next frame boundary
+pub fn freezing() -> bool {
+    FREEZING.load(Ordering::SeqCst)
+}
+
+pub fn set_freezing(freezing: bool) {
+    FREEZING.store(freezing, Ordering::SeqCst);
+}
+
+// Make `upgrade` possible; `hook` hands over and returns once the new
+// process has taken over
+pub fn enable(hook: impl Fn() -> Result<()> + Send + Sync + 'static) {
+    *HOOK.lock().unwrap() = Some(Box::new(hook));
+}
+
+// Hand over to a new process. On success the caller must exit.
+pub fn upgrade() -> Result<()> {
+    let hook = HOOK.lock().unwrap();
+    let Some(hook) = hook.as_ref() else {
+        return Err(VpnError::Config(
+            "upgrade needs a server listening for clients".into(),
+        ));
+    };
+    if UPGRADING.swap(true, Ordering::SeqCst) {
+        return Err(VpnError::Config("an upgrade is already running".into()));
+    }
+    let result = hook();
+    if result.is_err() {
+        UPGRADING.store(false, Ordering::SeqCst);
+    }
+    result
+}
+
+// Old process side: the new process and the channel to it
+pub struct Successor {
+    child: Child,
+    channel: OwnedFd,
+}
+
+impl Successor {
+    // Start the new process and wait for it to load its config
+    pub fn spawn() -> Result<Successor> {
+        let (ours, theirs) = socketpair()?;
+        set_cloexec(theirs.as_raw_fd(), false)?;
+        // argv[0], not /proc/self/exe, so a binary replaced on disk is used
+        let mut args = env::args_os();
+        let program = args.next().unwrap_or_default();
+        info!("Starting {} to take over", program.to_string_lossy());
+        let child = Command::new(program)
+            .args(args)
+            .env(ENV, theirs.as_raw_fd().to_string())
+            .spawn()?;
+        drop(theirs);
+        let mut successor = Successor {
+            child,
+            channel: ours,
+        };
+        if let Err(e) = successor.expect("hello") {
+            successor.abandon();
+            return Err(e);
+        }
+        Ok(successor)
+    }
+
+    pub fn send_tun(&self, fd: RawFd) -> Result<()> {
+        send(&self.channel, b"tun\n", Some(fd))
+    }
+
+    // Every socket registered through `claim`
+    pub fn send_sockets(&self) -> Result<()> {
+        for (name, fd) in SOCKETS.lock().unwrap().iter() {
+            send(
+                &self.channel,
+                format!("socket {}\n", name).as_bytes(),
+                Some(*fd),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn send_session(&self, state: &Options, fd: RawFd, pending: &[u8]) -> Result<()> {
+        send(
+            &self.channel,
+            format!("session{}\n", state).as_bytes(),
+            Some(fd),
+        )?;
+        for chunk in pending.chunks(CHUNK) {
+            send(&self.channel, &[b"data\n", chunk].concat(), None)?;
+        }
+        Ok(())
+    }
+
+    // Tell the new process that is everything and wait for it to take over
+    pub fn finish(&mut self) -> Result<()> {
+        send(&self.channel, b"end\n", None)?;
+        self.expect("ready")
+    }
+
+    // Stop a new process that failed to take over
+    pub fn abandon(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+
+    fn expect(&mut self, kind: &str) -> Result<()> {
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        socket::setsockopt(
+            self.channel.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &libc::timeval {
+                tv_sec: STARTUP_TIMEOUT.as_secs() as libc::time_t,
+                tv_usec: 0,
+            },
+        )?;
+        let (n, _) = recv(&self.channel, &mut buf).map_err(|e| {
+            VpnError::Transport(io::Error::new(
+                e.kind(),
+                format!("no {} from the new process: {}", kind, e),
+            ))
+        })?;
+        match message(&buf[..n]) {
+            (k, _, _) if k == kind => Ok(()),
+            _ => Err(VpnError::Config(format!(
+                "the new process did not take over (expected {})",
+                kind
+            ))),
+        }
+    }
+}
+
+// New process side: what the previous process handed over
+pub struct Inherited {
+    channel: OwnedFd,
+    pub tun: Option<OwnedFd>,
+    // Session state, connection and bytes read but not yet decoded
+    pub sessions: Vec<(Options, OwnedFd, Vec<u8>)>,
+}
+
+// Receive the handover if this process was started by an upgrade
+pub fn receive() -> Result<Option<Inherited>> {
+    let Ok(fd) = env::var(ENV) else {
+        return Ok(None);
+    };
+    // Not for an upgrade started from this process
+    env::remove_var(ENV);
+    let fd: RawFd = fd
+        .parse()
+        .map_err(|_| VpnError::Config(format!("Invalid {}: {}", ENV, fd)))?;
+    set_cloexec(fd, true)?;
+    let channel = unsafe { OwnedFd::from_raw_fd(fd) };
+    info!("Taking over from the previous process");
+    send(&channel, b"hello\n", None)?;
+
+    let mut inherited = Inherited {
+        channel,
+        tun: None,
+        sessions: Vec::new(),
+    };
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let (n, fd) = recv(&inherited.channel, &mut buf)?;
+        if n == 0 {
+            return Err(VpnError::Config(
+                "the previous process went away during the handover".into(),
+            ));
+        }
+        match (message(&buf[..n]), fd) {
+            (("tun", _, _), Some(fd)) => inherited.tun = Some(fd),
+            (("socket", name, _), Some(fd)) => {
+                INHERITED.lock().unwrap().push((name.to_string(), fd))
+            }
+            (("session", state, _), Some(fd)) => {
+                let state = Options::parse(state.split(' '))?;
+                inherited.sessions.push((state, fd, Vec::new()));
+            }
+            (("data", _, payload), None) => match inherited.sessions.last_mut() {
+                Some((_, _, pending)) => pending.extend_from_slice(payload),
+                None => warn!("Handover data without a session"),
+            },
+            (("end", _, _), None) => break,
+            ((kind, _, _), _) => warn!("Unexpected handover message {}", kind),
+        }
+    }
+    info!(
+        "Received {} sessions from the previous process",
+        inherited.sessions.len()
+    );
+    Ok(Some(inherited))
+}
+
+impl Inherited {
+    // Tell the previous process to exit. Sockets not claimed by now are
+    // no longer configured and get closed.
+    pub fn ready(self) -> Result<()> {
+        INHERITED.lock().unwrap().clear();
+        send(&self.channel, b"ready\n", None)
+    }
+}
+
+// Split a message into kind, text and payload
+fn message(buf: &[u8]) -> (&str, &str, &[u8]) {
+    let end = buf.iter().position(|&b| b == b'\n').unwrap_or(buf.len());
+    let line = std::str::from_utf8(&buf[..end]).unwrap_or("");
+    let payload = buf.get(end + 1..).unwrap_or(&[]);
+    let (kind, text) = line.split_once(' ').unwrap_or((line, ""));
+    (kind, text, payload)
+}
+
+fn socketpair() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as libc::c_int; 2];
+    let res = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// One message, with `fd` attached (SCM_RIGHTS) if given
+fn send(channel: &OwnedFd, data: &[u8], fd: Option<RawFd>) -> Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+    if unsafe { libc::sendmsg(channel.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// One message and the descriptor attached to it, if any
+fn recv(channel: &OwnedFd, buf: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let n = unsafe { libc::recvmsg(channel.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fd = None;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let raw = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+            fd = Some(OwnedFd::from_raw_fd(raw));
+        }
+    }
+    Ok((n as usize, fd))
+}
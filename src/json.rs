@@ -0,0 +1,75 @@
+use std::fmt::Display;
+
+// Just enough JSON writing for the status file and `ctl <command> --json`,
+// without pulling in serde. Objects keep their fields in the order given.
+#[derive(Debug, Default)]
+pub struct Object {
+    out: String,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    pub fn str(self, key: &str, value: &str) -> Object {
+        self.raw(key, &string(value))
+    }
+
+    // A number, or anything else that displays as valid JSON
+    pub fn num(self, key: &str, value: impl Display) -> Object {
+        self.raw(key, &value.to_string())
+    }
+
+    pub fn bool(self, key: &str, value: bool) -> Object {
+        self.num(key, value)
+    }
+
+    pub fn opt_str(self, key: &str, value: Option<&str>) -> Object {
+        match value {
+            Some(value) => self.str(key, value),
+            None => self.raw(key, "null"),
+        }
+    }
+
+    pub fn opt_num(self, key: &str, value: Option<impl Display>) -> Object {
+        match value {
+            Some(value) => self.num(key, value),
+            None => self.raw(key, "null"),
+        }
+    }
+
+    // A value that already is JSON, e.g. a nested object or an array
+    pub fn raw(mut self, key: &str, json: &str) -> Object {
+        if !self.out.is_empty() {
+            self.out.push(',');
+        }
+        self.out += &string(key);
+        self.out.push(':');
+        self.out += json;
+        self
+    }
+
+    pub fn build(self) -> String {
+        format!("{{{}}}", self.out)
+    }
+}
+
+// An array of values that already are JSON
+pub fn array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+pub fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
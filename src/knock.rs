@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use crate::crypto::{self, Hmac, DIGEST_LEN};
+use crate::handoff;
+
+// Port knocking in front of the TCP listeners (`knock = 7001,7002,7003`).
+//
+// A client first sends one UDP datagram to each knock port, in order.
+// Once a source address has completed the sequence it may open
+// connections for OPEN_WINDOW; anything else is closed right after
+// accept, before a byte is read or written.
+//
+// Without `knock_key` the sequence is a shared secret sent in the clear:
+// it keeps scanners and drive-by probes away, but whoever sees one knock
+// can repeat it. With `knock_key` every datagram is signed, which makes a
+// one-port sequence single-packet authorization: it carries the sender's
+// clock, a random nonce and an HMAC-SHA256 under the key over those and
+// the port. The server takes only datagrams within KNOCK_SKEW of its own
+// clock whose MAC it has not seen before, so a captured knock cannot be
+// sent again, and one for another port does not count.
+
+// Time allowed to complete the sequence
+const KNOCK_WINDOW: Duration = Duration::from_secs(10);
+// How long a source may connect after knocking
+const OPEN_WINDOW: Duration = Duration::from_secs(30);
+// Pause between knocks so they arrive in order
+const KNOCK_GAP: Duration = Duration::from_millis(50);
+// How far the clock in a signed knock may be off from ours
+const KNOCK_SKEW: u64 = 30;
+// Clock, nonce and MAC
+const NONCE_LEN: usize = 16;
+const SIGNED_LEN: usize = 8 + NONCE_LEN + DIGEST_LEN;
+
+#[derive(Debug)]
+pub struct KnockGate {
+    sequence: Vec<u16>,
+    // knock_key, when knocks must be signed
+    key: Option<Vec<u8>>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Next index expected from each source, and when it started knocking
+    progress: HashMap<IpAddr, (usize, Instant)>,
+    // Sources that completed the sequence, until when they may connect
+    open: HashMap<IpAddr, Instant>,
+    // MACs of signed knocks taken, until their clock is too old to pass
+    seen: HashMap<[u8; DIGEST_LEN], u64>,
+}
+
+impl KnockGate {
+    fn new(sequence: Vec<u16>, key: Option<&str>) -> KnockGate {
+        KnockGate {
+            sequence,
+            key: key.map(|key| key.as_bytes().to_vec()),
+            state: Mutex::default(),
+        }
+    }
+
+    // Bind the knock ports (IPv4 and IPv6) and start watching them
+    pub fn spawn(sequence: Vec<u16>, key: Option<&str>) -> io::Result<Arc<KnockGate>> {
+        let gate = Arc::new(KnockGate::new(sequence.clone(), key));
+        for (index, port) in sequence.into_iter().enumerate() {
+            let socket = handoff::claim(&format!("knock {}", port), || {
+                UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+            })?;
+            let gate = gate.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                loop {
+                    match socket.recv_from(&mut buf) {
+                        Ok((n, from)) => gate.receive(&buf[..n], from.ip(), index, unix_time()),
+                        Err(e) => warn!("Knock port {} failed: {}", port, e),
+                    }
+                }
+            });
+        }
+        info!(
+            "Port knocking enabled ({} ports, {})",
+            gate.sequence.len(),
+            if gate.key.is_some() {
+                "signed"
+            } else {
+                "unsigned"
+            }
+        );
+        Ok(gate)
+    }
+
+    // A datagram on knock port `index`, at `now` seconds since the epoch
+    fn receive(&self, datagram: &[u8], from: IpAddr, index: usize, now: u64) {
+        let from = from.to_canonical();
+        if let Some(key) = &self.key {
+            if let Err(reason) = self.verify(key, datagram, self.sequence[index], now) {
+                debug!("Ignoring knock from {}: {}", from, reason);
+                return;
+            }
+        }
+        self.knock(from, index);
+    }
+
+    // Check a signed knock for `port` and remember its MAC
+    fn verify(&self, key: &[u8], datagram: &[u8], port: u16, now: u64) -> Result<(), &str> {
+        if datagram.len() != SIGNED_LEN {
+            return Err("not signed");
+        }
+        let (signed, mac) = datagram.split_at(SIGNED_LEN - DIGEST_LEN);
+        if !crypto::equal(&sign(key, port, signed), mac) {
+            return Err("bad signature");
+        }
+        let time = u64::from_be_bytes(signed[..8].try_into().unwrap());
+        if time.abs_diff(now) > KNOCK_SKEW {
+            return Err("clock too far off");
+        }
+        let mut state = self.state.lock().unwrap();
+        state.seen.retain(|_, &mut t| t.abs_diff(now) <= KNOCK_SKEW);
+        if state.seen.insert(mac.try_into().unwrap(), time).is_some() {
+            return Err("replayed");
+        }
+        Ok(())
+    }
+
+    fn knock(&self, ip: IpAddr, index: usize) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        // Forget sources that gave up or whose window has passed
+        state
+            .progress
+            .retain(|_, &mut (_, started)| now - started < KNOCK_WINDOW);
+        state.open.retain(|_, until| *until > now);
+        let expected = state.progress.get(&ip).map_or(0, |&(next, _)| next);
+        if index != expected {
+            // Out of order: start over, unless this was a first knock
+            state.progress.remove(&ip);
+            if index == 0 {
+                state.progress.insert(ip, (1, now));
+            }
+            return;
+        }
+        if index + 1 < self.sequence.len() {
+            let started = match state.progress.get(&ip) {
+                Some(&(_, started)) if index > 0 => started,
+                _ => now,
+            };
+            state.progress.insert(ip, (index + 1, started));
+            return;
+        }
+        state.progress.remove(&ip);
+        state.open.insert(ip, now + OPEN_WINDOW);
+        info!(
+            "{} knocked; accepting its connections for {}s",
+            ip,
+            OPEN_WINDOW.as_secs()
+        );
+    }
+
+    // Whether a connection from `ip` may proceed
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let state = self.state.lock().unwrap();
+        state
+            .open
+            .get(&ip)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+// MAC of a signed knock on `port` over its clock and nonce
+fn sign(key: &[u8], port: u16, signed: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut mac = Hmac::new(key);
+    mac.update(b"knock");
+    mac.update(&port.to_be_bytes());
+    mac.update(signed);
+    mac.finish()
+}
+
+// The datagram sent to `port`: signed with `key` if given
+fn datagram(key: Option<&str>, port: u16, now: u64) -> io::Result<Vec<u8>> {
+    let Some(key) = key else {
+        return Ok(b"knock".to_vec());
+    };
+    let mut out = now.to_be_bytes().to_vec();
+    out.extend_from_slice(&crypto::random::<NONCE_LEN>()?);
+    let mac = sign(key.as_bytes(), port, &out);
+    out.extend_from_slice(&mac);
+    Ok(out)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Client side: knock on the server's ports in order, from `bind_addr` if
+// set, signing each knock with `key` if given
+pub fn knock(
+    server: IpAddr,
+    sequence: &[u16],
+    key: Option<&str>,
+    bind_addr: Option<IpAddr>,
+) -> io::Result<()> {
+    let local = bind_addr.unwrap_or(match server {
+        IpAddr::V4(_) => IpAddr::V4(0.into()),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    debug!("Knocking on {} ports {:?}", server, sequence);
+    for &port in sequence {
+        socket.send_to(
+            &datagram(key, port, unix_time())?,
+            SocketAddr::new(server, port),
+        )?;
+        thread::sleep(KNOCK_GAP);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_800_000_000;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4([192, 0, 2, last].into())
+    }
+
+    fn gate(key: Option<&str>) -> KnockGate {
+        KnockGate::new(vec![7001], key)
+    }
+
+    #[test]
+    fn signed_knock_opens_for_its_sender() {
+        let gate = gate(Some("sesame"));
+        gate.receive(&datagram(Some("sesame"), 7001, NOW).unwrap(), ip(1), 0, NOW);
+        assert!(gate.allows(ip(1)));
+        assert!(!gate.allows(ip(2)));
+    }
+
+    #[test]
+    fn replayed_knock_is_ignored() {
+        let gate = gate(Some("sesame"));
+        let knock = datagram(Some("sesame"), 7001, NOW).unwrap();
+        gate.receive(&knock, ip(1), 0, NOW);
+        gate.receive(&knock, ip(2), 0, NOW + 1);
+        assert!(gate.allows(ip(1)));
+        assert!(!gate.allows(ip(2)));
+    }
+
+    #[test]
+    fn forged_stale_and_unsigned_knocks_are_ignored() {
+        let gate = gate(Some("sesame"));
+        let cases = [
+            datagram(Some("guess"), 7001, NOW).unwrap(),
+            datagram(Some("sesame"), 7002, NOW).unwrap(),
+            datagram(Some("sesame"), 7001, NOW - KNOCK_SKEW - 1).unwrap(),
+            datagram(Some("sesame"), 7001, NOW + KNOCK_SKEW + 1).unwrap(),
+            datagram(None, 7001, NOW).unwrap(),
+        ];
+        for (i, knock) in cases.iter().enumerate() {
+            gate.receive(knock, ip(i as u8), 0, NOW);
+            assert!(!gate.allows(ip(i as u8)), "case {}", i);
+        }
+        // A flipped bit anywhere breaks the signature
+        let mut knock = datagram(Some("sesame"), 7001, NOW).unwrap();
+        knock[3] ^= 1;
+        gate.receive(&knock, ip(9), 0, NOW);
+        assert!(!gate.allows(ip(9)));
+    }
+
+    #[test]
+    fn unsigned_gate_takes_any_datagram() {
+        let gate = gate(None);
+        gate.receive(b"knock", ip(1), 0, NOW);
+        assert!(gate.allows(ip(1)));
+    }
+}
@@ -0,0 +1,56 @@
+use log::{log, log_enabled};
+
+pub mod affinity;
+pub mod ban;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod crypto;
+pub mod dns;
+pub mod error;
+pub mod exec_auth;
+pub mod expose;
+pub mod filter;
+pub mod forward;
+pub mod greeting;
+pub mod handoff;
+pub mod json;
+pub mod knock;
+pub mod logging;
+pub mod mux;
+pub mod packet;
+pub mod plugin;
+pub mod portmap;
+pub mod protocol;
+pub mod queue;
+pub mod quota;
+pub mod relay;
+pub mod reload;
+pub mod sandbox;
+pub mod secret;
+pub mod selftest;
+pub mod server;
+pub mod session;
+pub mod socket;
+pub mod state;
+pub mod status;
+pub mod trace;
+pub mod transport;
+pub mod tun;
+pub mod watchdog;
+pub mod web;
+
+pub use error::{Result, VpnError};
+
+// Simple hex dump function; see logging::set_dump
+pub(crate) fn hexdump(data: &[u8]) {
+    let Some(level) = logging::dump_level() else {
+        return;
+    };
+    if !log_enabled!(level) {
+        return;
+    }
+    for chunk in data.chunks(16) {
+        log!(level, "  {:02X?}", chunk);
+    }
+}
@@ -0,0 +1,132 @@
+use std::cell::Cell;
+use std::env;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use log::{Level, LevelFilter};
+
+// Highest level the logger lets through, which `set_level` cannot exceed
+static CEILING: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
+// Packet dumps: DUMP_AUTO (with debug logging), DUMP_ON or DUMP_OFF
+static DUMP: AtomicU8 = AtomicU8::new(DUMP_AUTO);
+const DUMP_AUTO: u8 = 0;
+const DUMP_ON: u8 = 1;
+const DUMP_OFF: u8 = 2;
+
+thread_local! {
+    // Session whose work the current thread is doing
+    static SESSION: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// Log lines written on behalf of a session carry its id, the one
+// `ctl clients` lists, after the module:
+//
+//   [2026-10-15T08:51:23Z INFO  vpn::server] [s12] Session 12 for 10.9.0.2 ended.
+//
+// so the lines of one client can be picked out of a busy server's log with
+// `grep '\[s12\]'`. The id belongs to the thread; threads started with
+// `spawn` inherit it.
+pub fn init(level: Option<LevelFilter>) {
+    // With a level the logger accepts everything and the global max level
+    // does the filtering, so it can be changed on reload or with `ctl
+    // log-level`. A RUST_LOG that is just a level counts as one; only
+    // per-module filters are left to env_logger.
+    let level = level.or_else(|| match env::var("RUST_LOG") {
+        Ok(filters) => filters.parse().ok(),
+        Err(_) => Some(LevelFilter::Error),
+    });
+    let mut builder = match level {
+        Some(_) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(LevelFilter::Trace);
+            builder
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    builder.format(|buf, record| {
+        write!(
+            buf,
+            "[{} {:<5} {}] ",
+            buf.timestamp_seconds(),
+            buf.default_styled_level(record.level()),
+            record.target()
+        )?;
+        if let Some(id) = session() {
+            write!(buf, "[s{}] ", id)?;
+        }
+        writeln!(buf, "{}", record.args())
+    });
+    let logger = builder.build();
+    let ceiling = logger.filter();
+    CEILING.store(ceiling as usize, Ordering::Relaxed);
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level.unwrap_or(ceiling));
+    }
+}
+
+// Change the level at run time, as far as RUST_LOG module filters allow
+pub fn set_level(level: LevelFilter) -> Result<(), String> {
+    let ceiling = LevelFilter::iter()
+        .nth(CEILING.load(Ordering::Relaxed))
+        .unwrap_or(LevelFilter::Trace);
+    if level > ceiling {
+        return Err(format!(
+            "RUST_LOG filters by module and allows at most {}; use log_level instead",
+            ceiling
+        ));
+    }
+    log::set_max_level(level);
+    Ok(())
+}
+
+// Switch packet dumps on or off regardless of the level, or back to
+// following debug logging with None
+pub fn set_dump(on: Option<bool>) {
+    let dump = match on {
+        None => DUMP_AUTO,
+        Some(true) => DUMP_ON,
+        Some(false) => DUMP_OFF,
+    };
+    DUMP.store(dump, Ordering::Relaxed);
+}
+
+pub fn dump() -> Option<bool> {
+    match DUMP.load(Ordering::Relaxed) {
+        DUMP_ON => Some(true),
+        DUMP_OFF => Some(false),
+        _ => None,
+    }
+}
+
+// The level packet dumps are logged at, None when they are off. Switched
+// on they go out at info, so they show without all of debug.
+pub(crate) fn dump_level() -> Option<Level> {
+    match dump() {
+        Some(true) => Some(Level::Info),
+        Some(false) => None,
+        None => Some(Level::Debug),
+    }
+}
+
+// Tag the log lines of the calling thread with session `id`
+pub fn set_session(id: Option<u64>) {
+    SESSION.with(|session| session.set(id));
+}
+
+pub fn session() -> Option<u64> {
+    SESSION.with(Cell::get)
+}
+
+// thread::spawn for work of the current session, keeping its tag
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let id = session();
+    thread::spawn(move || {
+        set_session(id);
+        f()
+    })
+}
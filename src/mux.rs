@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::error::{Result, VpnError};
+use crate::queue::SendQueue;
+
+// Messages on the control channel of a multiplexed connection (`mux=1`).
+//
+// Each control frame holds one line of text. A request is
+// `<VERB> <token> [args]` and is answered with `REPLY <token> <text>`;
+// the token only has to be unique among the sender's open requests.
+// Either side may send PING (answered with `pong`) and STATS (answered with
+// its counters for this connection as key=value pairs); the client sends
+// OPEN for forwarded connections (see the forward module). The server also
+// sends `PUSH <options>` when a reload changes the client's [client]
+// section, and `CLOSE <REASON>` (e.g. CLOSE QUOTA_EXCEEDED) before it ends
+// a session on its own; neither has a token or a reply.
+//
+// Unknown verbs are answered with `REPLY <token> error unknown request`, so
+// new requests can be added without breaking older peers.
+
+// How long a request waits for its reply
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    Request {
+        verb: &'a str,
+        token: u64,
+        args: &'a str,
+    },
+    Reply {
+        token: u64,
+        text: &'a str,
+    },
+    // Options in handshake reply form, e.g. ` route=10.1.0.0/16`
+    Push(&'a str),
+    // Why the server is ending the session, e.g. QUOTA_EXCEEDED
+    Close(&'a str),
+}
+
+impl<'a> Message<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Message<'a>> {
+        let text = std::str::from_utf8(frame).ok()?;
+        if let Some(options) = text.strip_prefix("PUSH") {
+            return Some(Message::Push(options));
+        }
+        if let Some(reason) = text.strip_prefix("CLOSE ") {
+            return Some(Message::Close(reason));
+        }
+        let (verb, rest) = text.split_once(' ')?;
+        let (token, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        let token = token.parse().ok()?;
+        Some(match verb {
+            "REPLY" => Message::Reply { token, text: args },
+            _ => Message::Request { verb, token, args },
+        })
+    }
+}
+
+pub fn reply(token: u64, text: &str) -> Vec<u8> {
+    format!("REPLY {} {}", token, text).into_bytes()
+}
+
+// The answer to a request from the peer; `stats` is only called for STATS
+pub fn answer(verb: &str, token: u64, stats: impl FnOnce() -> String) -> Vec<u8> {
+    match verb {
+        "PING" => reply(token, "pong"),
+        "STATS" => reply(token, &stats()),
+        _ => {
+            debug!("Unknown control request {}", verb);
+            reply(token, "error unknown request")
+        }
+    }
+}
+
+// Requests this side sends over the control channel, waiting for replies.
+// Replies are handed over by whoever reads the connection (see resolve).
+#[derive(Debug)]
+pub struct Requests {
+    queue: Arc<SendQueue>,
+    // Whether there is a connection with a control channel to send on
+    connected: AtomicBool,
+    next_token: AtomicU64,
+    pending: Mutex<HashMap<u64, Sender<String>>>,
+}
+
+impl Requests {
+    pub fn new(queue: Arc<SendQueue>) -> Requests {
+        Requests {
+            queue,
+            connected: AtomicBool::new(false),
+            next_token: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    // Send `verb` with `args` and wait for the reply; returns it with the
+    // round-trip time
+    pub fn request(&self, verb: &str, args: &str) -> Result<(String, Duration)> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(VpnError::Transport(io::ErrorKind::NotConnected.into()));
+        }
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(token, tx);
+        let started = Instant::now();
+        let mut message = format!("{} {}", verb, token);
+        if !args.is_empty() {
+            message = format!("{} {}", message, args);
+        }
+        let result = if self.queue.push_control(message.into_bytes()) {
+            rx.recv_timeout(REQUEST_TIMEOUT).map_err(|_| {
+                VpnError::Transport(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no reply to {}", verb),
+                ))
+            })
+        } else {
+            Err(VpnError::Transport(io::ErrorKind::NotConnected.into()))
+        };
+        self.pending.lock().unwrap().remove(&token);
+        result.map(|text| (text, started.elapsed()))
+    }
+
+    // Hand a reply to the request waiting for it
+    pub fn resolve(&self, token: u64, text: &str) {
+        match self.pending.lock().unwrap().remove(&token) {
+            Some(tx) => {
+                tx.send(text.to_string()).ok();
+            }
+            None => debug!("Reply to unknown or expired request {}", token),
+        }
+    }
+}
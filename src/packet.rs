@@ -0,0 +1,433 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Helpers for looking into the IP packets carried by the tunnel
+
+pub fn ip_version(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|b| b >> 4)
+}
+
+// Source address of an IPv4 or IPv6 packet
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+    match ip_version(packet)? {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 12))),
+        6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 8))),
+        _ => None,
+    }
+}
+
+// Destination address of an IPv4 or IPv6 packet
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match ip_version(packet)? {
+        4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 16))),
+        6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 24))),
+        _ => None,
+    }
+}
+
+// DSCP of an IPv4 or IPv6 packet, as the upper six bits of a TOS byte
+pub fn dscp(packet: &[u8]) -> Option<u8> {
+    let class = match ip_version(packet)? {
+        4 if packet.len() >= 20 => packet[1],
+        6 if packet.len() >= 40 => (packet[0] << 4) | (packet[1] >> 4),
+        _ => return None,
+    };
+    Some(class & 0xfc)
+}
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+pub const PROTO_ICMPV6: u8 = 58;
+
+// Protocol number and transport header/payload of an IPv4 or IPv6 packet.
+// IPv6 extension headers are not followed.
+pub fn transport(packet: &[u8]) -> Option<(u8, &[u8])> {
+    match ip_version(packet)? {
+        4 if packet.len() >= 20 => {
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            if header_len < 20 {
+                return None;
+            }
+            Some((packet[9], packet.get(header_len..)?))
+        }
+        6 if packet.len() >= 40 => Some((packet[6], &packet[40..])),
+        _ => None,
+    }
+}
+
+// Source and destination port of a TCP or UDP packet
+pub fn ports(packet: &[u8]) -> Option<(u16, u16)> {
+    match transport(packet)? {
+        (PROTO_TCP | PROTO_UDP, l4) if l4.len() >= 4 => Some((
+            u16::from_be_bytes([l4[0], l4[1]]),
+            u16::from_be_bytes([l4[2], l4[3]]),
+        )),
+        _ => None,
+    }
+}
+
+// Packets up to this size are interactive: TCP ACKs, keystrokes, pings
+const INTERACTIVE_LEN: usize = 128;
+
+// Whether a packet should skip ahead of bulk traffic: small packets and DNS
+pub fn is_interactive(packet: &[u8]) -> bool {
+    packet.len() <= INTERACTIVE_LEN
+        || matches!(ports(packet), Some((src, dst)) if src == 53 || dst == 53)
+}
+
+// Lower the MSS option of a TCP SYN so the connection's segments fit in
+// `mtu`. Returns true if the packet was changed.
+pub fn clamp_mss(packet: &mut [u8], mtu: usize) -> bool {
+    let (ip_len, overhead) = match ip_version(packet) {
+        // Only unfragmented packets: later fragments have no TCP header, and
+        // the checksum of a first one covers bytes we do not have (MF or a
+        // fragment offset)
+        Some(4)
+            if packet.len() >= 20 && u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff == 0 =>
+        {
+            ((packet[0] & 0x0f) as usize * 4, 40)
+        }
+        Some(6) if packet.len() >= 40 => (40, 60),
+        _ => return false,
+    };
+    if transport(packet).map(|(proto, _)| proto) != Some(PROTO_TCP) || packet.len() < ip_len + 20 {
+        return false;
+    }
+    let max_mss = mtu.saturating_sub(overhead).min(u16::MAX as usize) as u16;
+
+    let tcp = &mut packet[ip_len..];
+    if tcp[13] & 0x02 == 0 {
+        return false;
+    }
+    let options_end = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+    let mut changed = false;
+    let mut i = 20;
+    while i < options_end {
+        match tcp[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = *tcp.get(i + 1).unwrap_or(&0) as usize;
+                if len < 2 || i + len > options_end {
+                    break;
+                }
+                if kind == 2 && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss > max_mss {
+                        tcp[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                        changed = true;
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+    if changed {
+        update_tcp_checksum(packet, ip_len);
+    }
+    changed
+}
+
+// Recompute the TCP checksum, including the IPv4/IPv6 pseudo header
+fn update_tcp_checksum(packet: &mut [u8], ip_len: usize) {
+    packet[ip_len + 16] = 0;
+    packet[ip_len + 17] = 0;
+    let sum = transport_checksum(packet, ip_len, PROTO_TCP);
+    packet[ip_len + 16..ip_len + 18].copy_from_slice(&sum.to_be_bytes());
+}
+
+// Checksum of the transport part of a packet with the IPv4/IPv6 pseudo
+// header; the checksum field itself must be zero
+fn transport_checksum(packet: &[u8], ip_len: usize, proto: u8) -> u16 {
+    let len = packet.len() - ip_len;
+    if ip_version(packet) == Some(4) {
+        checksum(&[
+            &packet[12..20],
+            &[0, proto],
+            &(len as u16).to_be_bytes(),
+            &packet[ip_len..],
+        ])
+    } else {
+        checksum(&[
+            &packet[8..40],
+            &(len as u32).to_be_bytes(),
+            &[0, 0, 0, proto],
+            &packet[ip_len..],
+        ])
+    }
+}
+
+// Internet checksum over several parts; all but the last must have an even
+// length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for bytes in parts {
+        for chunk in bytes.chunks(2) {
+            let word = match chunk {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// IPv4/IPv6 header for `len` bytes of `proto` from `source` to
+// `destination`; None if the addresses are of different families
+fn ip_header(source: IpAddr, destination: IpAddr, proto: u8, len: usize) -> Option<Vec<u8>> {
+    match (source, destination) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = vec![0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(20 + len as u16).to_be_bytes());
+            header[8] = 64;
+            header[9] = proto;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let sum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            Some(header)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = vec![0u8; 40];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+            header[6] = proto;
+            header[7] = 64;
+            header[8..24].copy_from_slice(&src.octets());
+            header[24..40].copy_from_slice(&dst.octets());
+            Some(header)
+        }
+        _ => None,
+    }
+}
+
+// ICMP/ICMPv6 echo request from `source` to `destination`; None if the
+// addresses are of different families
+pub fn echo_request(source: IpAddr, destination: IpAddr, ident: u16, seq: u16) -> Option<Vec<u8>> {
+    let (proto, kind) = match source {
+        IpAddr::V4(_) => (PROTO_ICMP, 8),
+        IpAddr::V6(_) => (PROTO_ICMPV6, 128),
+    };
+    let mut packet = ip_header(source, destination, proto, 8)?;
+    let ip_len = packet.len();
+    packet.extend_from_slice(&[kind, 0, 0, 0]);
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    let sum = match kind {
+        8 => checksum(&[&packet[ip_len..]]),
+        _ => transport_checksum(&packet, ip_len, PROTO_ICMPV6),
+    };
+    packet[ip_len + 2..ip_len + 4].copy_from_slice(&sum.to_be_bytes());
+    Some(packet)
+}
+
+// UDP datagram carrying `payload` from `source` to `destination`; None if
+// the addresses are of different families or the payload is too large
+pub fn udp(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let len = 8 + payload.len();
+    if len > u16::MAX as usize - 20 {
+        return None;
+    }
+    let mut packet = ip_header(source.ip(), destination.ip(), PROTO_UDP, len)?;
+    let ip_len = packet.len();
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    // Zero means no checksum, so a sum of zero is sent as its complement
+    let sum = match transport_checksum(&packet, ip_len, PROTO_UDP) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    packet[ip_len + 6..ip_len + 8].copy_from_slice(&sum.to_be_bytes());
+    Some(packet)
+}
+
+// Source, destination and payload of a UDP datagram
+pub fn udp_payload(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let udp = match transport(packet)? {
+        (PROTO_UDP, udp) if udp.len() >= 8 => udp,
+        _ => return None,
+    };
+    let (from, to) = ports(packet)?;
+    let len = (u16::from_be_bytes([udp[4], udp[5]]) as usize).clamp(8, udp.len());
+    Some((
+        SocketAddr::new(source(packet)?, from),
+        SocketAddr::new(destination(packet)?, to),
+        &udp[8..len],
+    ))
+}
+
+// Source, identifier and sequence number of an ICMP/ICMPv6 echo reply
+pub fn echo_reply(packet: &[u8]) -> Option<(IpAddr, u16, u16)> {
+    let icmp = match transport(packet)? {
+        (PROTO_ICMP, icmp) if icmp.first() == Some(&0) => icmp,
+        (PROTO_ICMPV6, icmp) if icmp.first() == Some(&129) => icmp,
+        _ => return None,
+    };
+    if icmp.len() < 8 {
+        return None;
+    }
+    Some((
+        source(packet)?,
+        u16::from_be_bytes([icmp[4], icmp[5]]),
+        u16::from_be_bytes([icmp[6], icmp[7]]),
+    ))
+}
+
+fn ipv4_at(packet: &[u8], offset: usize) -> Ipv4Addr {
+    let b: [u8; 4] = packet[offset..offset + 4].try_into().unwrap();
+    Ipv4Addr::from(b)
+}
+
+fn ipv6_at(packet: &[u8], offset: usize) -> Ipv6Addr {
+    let b: [u8; 16] = packet[offset..offset + 16].try_into().unwrap();
+    Ipv6Addr::from(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V4: [IpAddr; 2] = [
+        IpAddr::V4(Ipv4Addr::new(10, 9, 0, 2)),
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+    ];
+    const V6: [IpAddr; 2] = [
+        IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)),
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+    ];
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+
+    // A TCP segment with `options` and a valid checksum
+    fn segment([source, destination]: [IpAddr; 2], flags: u8, options: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![0u8; 20];
+        tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+        tcp[12] = (options.len().div_ceil(4) as u8 + 5) << 4;
+        tcp[13] = flags;
+        tcp.extend_from_slice(options);
+        let mut packet = ip_header(source, destination, PROTO_TCP, tcp.len()).unwrap();
+        let ip_len = packet.len();
+        packet.extend_from_slice(&tcp);
+        update_tcp_checksum(&mut packet, ip_len);
+        packet
+    }
+
+    fn mss_option(mss: u16) -> [u8; 4] {
+        let [hi, lo] = mss.to_be_bytes();
+        [2, 4, hi, lo]
+    }
+
+    fn ip_len(packet: &[u8]) -> usize {
+        if ip_version(packet) == Some(4) {
+            20
+        } else {
+            40
+        }
+    }
+
+    fn mss(packet: &[u8]) -> u16 {
+        let i = ip_len(packet) + 22;
+        u16::from_be_bytes([packet[i], packet[i + 1]])
+    }
+
+    // Summing a segment with its checksum in place gives zero
+    fn checksum_ok(packet: &[u8]) -> bool {
+        transport_checksum(packet, ip_len(packet), PROTO_TCP) == 0
+    }
+
+    #[test]
+    fn syn_mss_above_the_limit_is_lowered() {
+        let mut packet = segment(V4, SYN, &mss_option(1460));
+        assert!(clamp_mss(&mut packet, 1400));
+        assert_eq!(mss(&packet), 1360);
+        assert!(checksum_ok(&packet));
+
+        let mut packet = segment(V4, SYN | ACK, &mss_option(1460));
+        assert!(clamp_mss(&mut packet, 1400));
+        assert_eq!(mss(&packet), 1360);
+        assert!(checksum_ok(&packet));
+    }
+
+    #[test]
+    fn syn_mss_below_the_limit_is_kept() {
+        let mut packet = segment(V4, SYN, &mss_option(1200));
+        let before = packet.clone();
+        assert!(!clamp_mss(&mut packet, 1400));
+        assert_eq!(packet, before);
+        assert!(checksum_ok(&packet));
+    }
+
+    #[test]
+    fn segments_other_than_syn_are_kept() {
+        let mut packet = segment(V4, ACK, &mss_option(1460));
+        let before = packet.clone();
+        assert!(!clamp_mss(&mut packet, 1400));
+        assert_eq!(packet, before);
+        assert!(checksum_ok(&packet));
+    }
+
+    #[test]
+    fn ipv6_syn_mss_is_lowered() {
+        let mut options = vec![1, 1];
+        options.extend_from_slice(&mss_option(1440));
+        options.extend_from_slice(&[1, 1]);
+        let mut packet = segment(V6, SYN, &options);
+        assert!(clamp_mss(&mut packet, 1400));
+        let i = ip_len(&packet) + 24;
+        assert_eq!(u16::from_be_bytes([packet[i], packet[i + 1]]), 1340);
+        assert!(checksum_ok(&packet));
+    }
+
+    #[test]
+    fn truncated_options_are_left_alone() {
+        // The MSS option runs past the end of the header
+        let mut packet = segment(V4, SYN, &[1, 1, 1, 2, 4, 0x05, 0xb4, 0]);
+        packet[20 + 12] = 6 << 4;
+        update_tcp_checksum(&mut packet, 20);
+        let before = packet.clone();
+        assert!(!clamp_mss(&mut packet, 1400));
+        assert_eq!(packet, before);
+        assert!(checksum_ok(&packet));
+
+        // The packet ends inside the option, or inside the TCP header
+        let mut packet = segment(V4, SYN, &mss_option(1460));
+        packet.truncate(20 + 22);
+        let before = packet.clone();
+        assert!(!clamp_mss(&mut packet, 1400));
+        assert_eq!(packet, before);
+        packet.truncate(20 + 14);
+        assert!(!clamp_mss(&mut packet, 1400));
+    }
+
+    #[test]
+    fn fragments_are_left_alone() {
+        // More fragments, then a fragment offset
+        for flags in [0x20, 0x00] {
+            let mut packet = segment(V4, SYN, &mss_option(1460));
+            packet[6] = flags;
+            packet[7] = if flags == 0 { 1 } else { 0 };
+            let before = packet.clone();
+            assert!(!clamp_mss(&mut packet, 1400));
+            assert_eq!(packet, before);
+            assert!(checksum_ok(&packet));
+        }
+    }
+
+    #[test]
+    fn ipv4_header_shorter_than_20_bytes_is_refused() {
+        let mut packet = segment(V4, SYN, &mss_option(1460));
+        packet[0] = 0x44;
+        assert_eq!(transport(&packet), None);
+        assert!(!clamp_mss(&mut packet, 1400));
+    }
+}
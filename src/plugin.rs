@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+
+use crate::protocol::Options;
+use crate::session::Session;
+use crate::transport::Peer;
+
+// Extension points for embedding the server as a library. Register
+// implementations on a VpnServer before calling run().
+
+// Which way a packet is travelling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Received from a client, about to be written to the TUN
+    FromClient,
+    // Read from the TUN, about to be queued for a client
+    ToClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+}
+
+// Observe, rewrite in place, or drop packets. Called on the forwarding
+// threads, so keep it cheap.
+pub trait PacketInspector: Send + Sync {
+    fn inspect(&self, session: &Session, direction: Direction, packet: &mut [u8]) -> Verdict;
+}
+
+// What a client presented in its handshake
+#[derive(Debug)]
+pub struct AuthRequest<'a> {
+    pub peer: &'a Peer,
+    // Tunnel address the client asked for
+    pub addr: IpAddr,
+    // key=value options of the handshake line (credentials, tokens, ...)
+    pub options: &'a Options,
+}
+
+// Decide whether a client may connect. Err carries the reason, which is
+// logged; the client only sees "ERR authentication failed".
+pub trait AuthBackend: Send + Sync {
+    fn authenticate(&self, request: &AuthRequest) -> Result<(), String>;
+}
+
+// Registered extensions. Every inspector must pass a packet and every auth
+// backend must accept a client.
+#[derive(Default)]
+pub struct Plugins {
+    inspectors: Vec<Box<dyn PacketInspector>>,
+    auth: Vec<Box<dyn AuthBackend>>,
+}
+
+impl Plugins {
+    pub fn add_inspector(&mut self, inspector: Box<dyn PacketInspector>) {
+        self.inspectors.push(inspector);
+    }
+
+    pub fn add_auth_backend(&mut self, backend: Box<dyn AuthBackend>) {
+        self.auth.push(backend);
+    }
+
+    pub fn inspect(&self, session: &Session, direction: Direction, packet: &mut [u8]) -> Verdict {
+        for inspector in &self.inspectors {
+            if inspector.inspect(session, direction, packet) == Verdict::Drop {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Pass
+    }
+
+    pub fn authenticate(&self, request: &AuthRequest) -> Result<(), String> {
+        for backend in &self.auth {
+            backend.authenticate(request)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::status::Status;
+
+// Port mapping on a home router with NAT-PMP (RFC 6886), which most
+// consumer routers speak alongside UPnP IGD. The mapping is renewed at
+// half its lifetime and simply expires when the server goes away.
+
+const NATPMP_PORT: u16 = 5351;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+// Responses echo the opcode with the top bit set
+const RESPONSE: u8 = 0x80;
+// Lifetime asked for, as recommended by the RFC
+const LIFETIME: u32 = 7200;
+// First retransmission timeout; doubled on each try
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const TRIES: u32 = 6;
+// Wait before trying again after the router did not answer or refused
+const RETRY: Duration = Duration::from_secs(60);
+
+// Keep mappings of `ports` (external = internal) on the router
+pub fn spawn(ports: Vec<u16>, gateway: Option<Ipv4Addr>, status: Arc<Status>) {
+    let gateway = match gateway.map(Ok).unwrap_or_else(default_gateway) {
+        Ok(gateway) => gateway,
+        Err(e) => {
+            warn!(
+                "Port mapping disabled: no gateway ({}); set upnp_gateway",
+                e
+            );
+            return;
+        }
+    };
+    info!("Requesting port mappings {:?} from {}", ports, gateway);
+    thread::spawn(move || loop {
+        let mut renew = Duration::from_secs(LIFETIME as u64 / 2);
+        for &port in &ports {
+            match map_tcp(gateway, port) {
+                Ok((external, lifetime)) => {
+                    let ip = external_address(gateway)
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_else(|_| "?".to_string());
+                    info!(
+                        "Router {} maps {}:{} to port {} for {}s",
+                        gateway, ip, external, port, lifetime
+                    );
+                    status.event(format!("Port mapping {}:{} -> {}", ip, external, port));
+                    renew = renew.min(Duration::from_secs(lifetime as u64 / 2).max(RETRY));
+                }
+                Err(e) => {
+                    warn!("Port mapping of {} on {} failed: {}", port, gateway, e);
+                    status.event(format!("Port mapping of {} failed: {}", port, e));
+                    renew = renew.min(RETRY);
+                }
+            }
+        }
+        thread::sleep(renew);
+    });
+}
+
+// Map TCP `port` to the same external port; returns the external port the
+// router chose and the granted lifetime
+fn map_tcp(gateway: Ipv4Addr, port: u16) -> io::Result<(u16, u32)> {
+    let mut request = [0u8; 12];
+    request[1] = OP_MAP_TCP;
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&port.to_be_bytes());
+    request[8..12].copy_from_slice(&LIFETIME.to_be_bytes());
+    let response = exchange(gateway, &request, 16)?;
+    let internal = u16::from_be_bytes([response[8], response[9]]);
+    if internal != port {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("response is for port {}", internal),
+        ));
+    }
+    let external = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+    Ok((external, lifetime))
+}
+
+fn external_address(gateway: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let response = exchange(gateway, &[0, OP_EXTERNAL_ADDRESS], 12)?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+// Send a request, retransmitting with backoff, and return a successful
+// response of at least `len` bytes
+fn exchange(gateway: Ipv4Addr, request: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((gateway, NATPMP_PORT))?;
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buf = [0u8; 16];
+    for _ in 0..TRIES {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            Ok(n) if n >= len && buf[0] == 0 && buf[1] == request[1] | RESPONSE => {
+                let result = u16::from_be_bytes([buf[2], buf[3]]);
+                if result != 0 {
+                    return Err(io::Error::other(result_text(result)));
+                }
+                return Ok(buf[..n].to_vec());
+            }
+            Ok(n) => debug!("Ignoring {}-byte NAT-PMP reply {:02x?}", n, &buf[..n]),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no NAT-PMP response (router does not support it?)",
+    ))
+}
+
+fn result_text(code: u16) -> String {
+    let reason = match code {
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    format!("router refused: {} ({})", reason, code)
+}
+
+// Gateway of the IPv4 default route, from /proc/net/route
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    let table = fs::read_to_string("/proc/net/route")?;
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        // Addresses are printed as the in-memory u32, i.e. network order
+        // bytes read in host order
+        if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+            if gateway != 0 {
+                return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no default route"))
+}
@@ -0,0 +1,758 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+use log::{debug, info, warn};
+
+use crate::error::{Result, VpnError};
+use crate::hexdump;
+
+// Longest handshake line we accept from a peer (including the newline)
+pub const MAX_LINE_LEN: usize = 1024;
+
+// Utility to read a line from a stream.
+// Reads byte by byte so nothing past the newline is consumed.
+pub fn read_line<R: Read>(stream: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            break;
+        }
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE_LEN {
+            return Err(VpnError::Handshake(format!(
+                "Line exceeds {} bytes",
+                MAX_LINE_LEN
+            )));
+        }
+    }
+    String::from_utf8(line).map_err(|_| VpnError::Handshake("Line is not valid UTF-8".into()))
+}
+
+// Write a line to a stream
+pub fn write_line<W: Write>(stream: &mut W, line: &str) -> Result<()> {
+    debug!("Sending line to TCP peer: {}", line.trim_end());
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+// Optional `key=value` fields that follow the first word of a handshake
+// line. Peers ignore keys they do not know, which keeps old and new
+// versions interoperable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options(Vec<(String, String)>);
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        let value = value.to_string();
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn parse<'a>(words: impl Iterator<Item = &'a str>) -> Result<Options> {
+        let mut options = Options::new();
+        for word in words.filter(|w| !w.is_empty()) {
+            let (key, value) = word
+                .split_once('=')
+                .filter(|(k, _)| !k.is_empty())
+                .ok_or_else(|| VpnError::Handshake(format!("Malformed option: {:?}", word)))?;
+            options.set(key, value);
+        }
+        Ok(options)
+    }
+}
+
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.0 {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+// First handshake message, sent by the client: "<ip>/<prefix>[ key=value...]\n"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeRequest {
+    pub addr: IpAddr,
+    pub prefix: u8,
+    pub options: Options,
+}
+
+impl HandshakeRequest {
+    pub fn new(addr: IpAddr, prefix: u8) -> HandshakeRequest {
+        HandshakeRequest {
+            addr,
+            prefix,
+            options: Options::new(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<HandshakeRequest> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut words = line.split(' ');
+        let cidr = words.next().unwrap_or("");
+        let (addr, prefix) = parse_cidr(cidr)
+            .ok_or_else(|| VpnError::Handshake(format!("Invalid client address: {:?}", cidr)))?;
+        let options = Options::parse(words)?;
+        Ok(HandshakeRequest {
+            addr,
+            prefix,
+            options,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}\n", self)
+    }
+}
+
+impl fmt::Display for HandshakeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}{}", self.addr, self.prefix, self.options)
+    }
+}
+
+// Server reply to a HandshakeRequest: "OK[ key=value...]\n" or "ERR <reason>\n"
+pub fn parse_handshake_response(line: &str) -> Result<Options> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if let Some(rest) = line.strip_prefix("OK") {
+        if rest.is_empty() || rest.starts_with(' ') {
+            return Options::parse(rest.split(' '));
+        }
+    }
+    match line.strip_prefix("ERR") {
+        Some(reason) => Err(VpnError::Rejected(reason.trim().to_string())),
+        // Banned, rate limited or missing a knock: the server just hangs up
+        None if line.is_empty() => Err(VpnError::Transport(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "server closed the connection during the handshake",
+        ))),
+        None => Err(VpnError::Incompatible(format!(
+            "Unexpected server response: {:?}",
+            line
+        ))),
+    }
+}
+
+// Parse "a.b.c.d/nn" (or an IPv6 equivalent) into address and prefix length
+pub fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = s.split_once('/')?;
+    let addr: IpAddr = addr.parse().ok()?;
+    let prefix: u8 = prefix.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+// Whether addr lies within net/prefix
+pub fn cidr_contains(net: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    match (net, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+// TUN MTU assumed for peers that do not announce one
+pub const DEFAULT_MTU: usize = 1500;
+
+// Largest MTU a TUN device accepts
+pub const MAX_MTU: usize = 65535;
+
+// MTU announced in a handshake line, or DEFAULT_MTU for peers that predate
+// the `mtu` option
+pub fn peer_mtu(options: &Options) -> usize {
+    options
+        .get("mtu")
+        .and_then(|v| v.parse().ok())
+        .filter(|&mtu| (68..=MAX_MTU).contains(&mtu))
+        .unwrap_or(DEFAULT_MTU)
+}
+
+// What the handshake settled on for one session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub framing: Framing,
+    // Largest packet either side may send; receive buffers are sized from it
+    pub mtu: usize,
+}
+
+// Frame format negotiated in the handshake.
+//
+// Version 1 prefixes each packet with a 2-byte big-endian length, which caps
+// frames at 65535 bytes. Version 2 uses a 4-byte length, bounded by max_len.
+// Peers that do not announce `frame=2` get version 1.
+//
+// On top of version 2, `mux=1` adds a channel byte after the length, so the
+// connection carries control messages (see the mux module) and forwarded
+// TCP connections (see the forward module) next to packets.
+//
+// Also on top of version 2, `pad=mtu` or `pad=buckets` pads every frame, so
+// the lengths on the wire say less about what is inside. A padded frame
+// starts with the 4-byte length of the payload, then the payload, then
+// zeros up to the padded length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub version: u8,
+    pub max_len: usize,
+    pub mux: bool,
+    pub pad: Padding,
+}
+
+// How frames are padded (`pad`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    // Every frame to max_len, which a client asking for this offers as its
+    // MTU plus PAD_LEN: all frames look alike, at the most bandwidth
+    Mtu,
+    // Frames to the next power of two, at least MIN_BUCKET bytes: past
+    // that, at most half of each frame is padding
+    Buckets,
+}
+
+impl Padding {
+    pub fn parse(name: &str) -> Option<Padding> {
+        match name {
+            "none" => Some(Padding::None),
+            "mtu" => Some(Padding::Mtu),
+            "buckets" => Some(Padding::Buckets),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Padding::None => "none",
+            Padding::Mtu => "mtu",
+            Padding::Buckets => "buckets",
+        }
+    }
+}
+
+// Bytes a padded frame spends on the payload length
+pub const PAD_LEN: usize = 4;
+// Smallest padded frame with pad=buckets
+const MIN_BUCKET: usize = 128;
+// Source of the padding written
+const ZEROS: [u8; 1024] = [0; 1024];
+
+// Channel of a multiplexed frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Data = 0,
+    Control = 1,
+    Stream = 2,
+}
+
+pub const MAX_FRAME_V1: usize = 0xFFFF;
+
+impl Framing {
+    pub const V1: Framing = Framing {
+        version: 1,
+        max_len: MAX_FRAME_V1,
+        mux: false,
+        pad: Padding::None,
+    };
+
+    pub fn v2(max_len: usize) -> Framing {
+        Framing {
+            version: 2,
+            max_len: max_len.min(u32::MAX as usize),
+            mux: false,
+            pad: Padding::None,
+        }
+    }
+
+    // The same framing with a control channel; needs version 2
+    pub fn muxed(self) -> Framing {
+        Framing {
+            mux: self.version >= 2,
+            ..self
+        }
+    }
+
+    // The same framing with padding; needs version 2
+    pub fn padded(self, pad: Padding) -> Framing {
+        Framing {
+            pad: if self.version >= 2 {
+                pad
+            } else {
+                Padding::None
+            },
+            ..self
+        }
+    }
+
+    // The same framing with frames no larger than a packet of the
+    // negotiated MTU needs, whatever max_frame was offered
+    pub fn capped(self, mtu: usize) -> Framing {
+        Framing {
+            max_len: self.max_len.min(mtu + PAD_LEN),
+            ..self
+        }
+    }
+
+    // Options a client sends to propose this framing
+    pub fn offer(&self, options: &mut Options) {
+        if self.version >= 2 {
+            options.set("frame", 2);
+            options.set("max_frame", self.max_len);
+        }
+        if self.mux {
+            options.set("mux", 1);
+        }
+        if self.pad != Padding::None {
+            options.set("pad", self.pad.name());
+        }
+    }
+
+    // Server side: pick the framing for a client's offer.
+    // The result is also what the server echoes back in its OK line.
+    pub fn negotiate(&self, offer: &Options, reply: &mut Options) -> Framing {
+        if self.version < 2 || offer.get("frame") != Some("2") {
+            return Framing::V1;
+        }
+        let max_len = offer
+            .get("max_frame")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(self.max_len)
+            .min(self.max_len);
+        let mut framing = Framing::v2(max_len);
+        if self.mux && offer.get("mux") == Some("1") {
+            framing = framing.muxed();
+        }
+        // Padding is the client's choice; a server pads if asked to
+        if let Some(pad) = offer.get("pad").and_then(Padding::parse) {
+            framing = framing.padded(pad);
+        }
+        framing.offer(reply);
+        framing
+    }
+
+    // Client side: framing the server accepted in its OK line
+    pub fn accepted(&self, reply: &Options) -> Result<Framing> {
+        if reply.get("frame") != Some("2") {
+            return Ok(Framing::V1);
+        }
+        let max_len: usize = reply
+            .get("max_frame")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| VpnError::Incompatible("frame=2 without valid max_frame".into()))?;
+        if self.version < 2 || max_len > self.max_len {
+            return Err(VpnError::Incompatible(format!(
+                "Server chose unsupported framing (frame=2 max_frame={})",
+                max_len
+            )));
+        }
+        let mut framing = Framing::v2(max_len);
+        match reply.get("mux") {
+            Some("1") if self.mux => framing = framing.muxed(),
+            Some("1") => {
+                return Err(VpnError::Incompatible(
+                    "Server chose mux=1, which was not offered".into(),
+                ))
+            }
+            _ => {}
+        }
+        match reply
+            .get("pad")
+            .map(|pad| Padding::parse(pad) == Some(self.pad))
+        {
+            Some(true) => Ok(framing.padded(self.pad)),
+            Some(false) => Err(VpnError::Incompatible(format!(
+                "Server chose pad={}, which was not offered",
+                reply.get("pad").unwrap_or_default()
+            ))),
+            None => {
+                if self.pad != Padding::None {
+                    warn!("Server does not support padding; frames are not padded.");
+                }
+                Ok(framing)
+            }
+        }
+    }
+
+    // Length of the body of a frame carrying `len` bytes of payload
+    fn padded_len(&self, len: usize) -> usize {
+        match self.pad {
+            Padding::None => len,
+            Padding::Mtu => self.max_len.max(len + PAD_LEN),
+            Padding::Buckets => (len + PAD_LEN)
+                .next_power_of_two()
+                .max(MIN_BUCKET)
+                .min(self.max_len)
+                .max(len + PAD_LEN),
+        }
+    }
+
+    pub fn header_len(&self) -> usize {
+        match (self.version, self.mux) {
+            (1, _) => 2,
+            (_, false) => 4,
+            (_, true) => 5,
+        }
+    }
+
+    // Send a packet with a length header (big-endian)
+    pub fn send<W: Write>(&self, stream: &mut W, packet: &[u8]) -> Result<()> {
+        self.send_on(stream, Channel::Data, packet)
+    }
+
+    // Send a frame on a channel; only Data without mux
+    pub fn send_on<W: Write>(&self, stream: &mut W, channel: Channel, packet: &[u8]) -> Result<()> {
+        if channel != Channel::Data && !self.mux {
+            return Err(VpnError::Framing(format!(
+                "{:?} frame on a connection without mux",
+                channel
+            )));
+        }
+        let length = self.padded_len(packet.len());
+        if length > self.max_len {
+            return Err(VpnError::Framing(format!(
+                "Packet too large: {} bytes (max {})",
+                packet.len(),
+                self.max_len.saturating_sub(length - packet.len())
+            )));
+        }
+        info!("Sending VPN packet of {} bytes to TCP peer.", packet.len());
+        debug!(
+            "VPN header: version {}, length = {} (0x{:04X})",
+            self.version, length, length
+        );
+        hexdump(packet);
+        if self.version >= 2 {
+            stream.write_all(&(length as u32).to_be_bytes())?;
+        } else {
+            stream.write_all(&(length as u16).to_be_bytes())?;
+        }
+        if self.mux {
+            stream.write_all(&[channel as u8])?;
+        }
+        if self.pad != Padding::None {
+            stream.write_all(&(packet.len() as u32).to_be_bytes())?;
+        }
+        stream.write_all(packet)?;
+        let mut filler = match self.pad {
+            Padding::None => 0,
+            _ => length - PAD_LEN - packet.len(),
+        };
+        while filler > 0 {
+            let n = filler.min(ZEROS.len());
+            stream.write_all(&ZEROS[..n])?;
+            filler -= n;
+        }
+        info!("Sent VPN packet ({} bytes) successfully.", packet.len());
+        Ok(())
+    }
+
+    // Receive a packet with a length header. Control and stream frames are
+    // skipped; use recv_frame to see them.
+    pub fn recv<R: Read>(&self, stream: &mut R, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.recv_frame(stream, buf)? {
+                (Channel::Data, n) => return Ok(n),
+                (channel, n) => debug!("Skipping {}-byte {:?} frame.", n, channel),
+            }
+        }
+    }
+
+    // Receive a frame and the channel it was sent on
+    pub fn recv_frame<R: Read>(&self, stream: &mut R, buf: &mut [u8]) -> Result<(Channel, usize)> {
+        let mut len_buf = [0u8; 5];
+        let header = &mut len_buf[..self.header_len()];
+        match stream.read_exact(header) {
+            Ok(_) => {}
+            Err(e) => {
+                info!("No more data or error while reading VPN packet length.");
+                return Err(e.into());
+            }
+        };
+        let length = if self.version >= 2 {
+            u32::from_be_bytes([len_buf[0], len_buf[1], len_buf[2], len_buf[3]]) as usize
+        } else {
+            u16::from_be_bytes([len_buf[0], len_buf[1]]) as usize
+        };
+        let channel = match (self.mux, len_buf[4]) {
+            (false, _) | (true, 0) => Channel::Data,
+            (true, 1) => Channel::Control,
+            (true, 2) => Channel::Stream,
+            (true, c) => return Err(VpnError::Framing(format!("Unknown channel {}", c))),
+        };
+        info!("Receiving VPN packet: expected length = {} bytes.", length);
+        if length > self.max_len {
+            return Err(VpnError::Framing(format!(
+                "Frame of {} bytes exceeds negotiated maximum {}",
+                length, self.max_len
+            )));
+        }
+        let (length, filler) = if self.pad != Padding::None {
+            let mut len_buf = [0u8; PAD_LEN];
+            if length < PAD_LEN {
+                return Err(VpnError::Framing(format!(
+                    "Padded frame of {} bytes has no payload length",
+                    length
+                )));
+            }
+            stream.read_exact(&mut len_buf)?;
+            let payload = u32::from_be_bytes(len_buf) as usize;
+            if payload > length - PAD_LEN {
+                return Err(VpnError::Framing(format!(
+                    "Payload of {} bytes in a padded frame of {}",
+                    payload, length
+                )));
+            }
+            (payload, length - PAD_LEN - payload)
+        } else {
+            (length, 0)
+        };
+        if length > buf.len() {
+            return Err(VpnError::Framing(format!(
+                "Packet too large for buffer: {} > {}",
+                length,
+                buf.len()
+            )));
+        }
+        stream.read_exact(&mut buf[..length])?;
+        let skipped = io::copy(&mut stream.by_ref().take(filler as u64), &mut io::sink())?;
+        if skipped < filler as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        debug!("Received {} bytes from TCP:", length);
+        hexdump(&buf[..length]);
+        info!("Received VPN packet ({} bytes) successfully.", length);
+        Ok((channel, length))
+    }
+}
+
+// Send a packet with a 2-byte header containing length (big-endian)
+pub fn send_vpn_packet<W: Write>(stream: &mut W, packet: &[u8]) -> Result<()> {
+    Framing::V1.send(stream, packet)
+}
+
+// Receive a packet with a 2-byte header containing length
+pub fn recv_vpn_packet<R: Read>(stream: &mut R, buf: &mut [u8]) -> Result<usize> {
+    Framing::V1.recv(stream, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A packet of `len` bytes that is not all one value, so an offset or a
+    // short read shows up in the comparison
+    fn packet(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn round_trip(framing: Framing, channel: Channel, sent: &[u8]) {
+        let mut wire = Vec::new();
+        framing.send_on(&mut wire, channel, sent).unwrap();
+        let mut reader = wire.as_slice();
+        let mut buf = vec![0u8; framing.max_len];
+        let (got, n) = framing.recv_frame(&mut reader, &mut buf).unwrap();
+        assert_eq!(got, channel);
+        assert_eq!(&buf[..n], sent);
+        assert!(reader.is_empty(), "{} bytes left over", reader.len());
+    }
+
+    // xorshift64*, so the inputs are varied but the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        // A word as it may appear in a handshake option
+        fn word(&mut self) -> String {
+            const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789_-.,:/";
+            let len = 1 + self.below(12);
+            (0..len)
+                .map(|_| CHARS[self.below(CHARS.len())] as char)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut rng = Rng(0x5eed_f4a3);
+        let framings = [
+            Framing::V1,
+            Framing::v2(1 << 20),
+            Framing::v2(1 << 20).muxed(),
+            Framing::v2(1 << 12).muxed().padded(Padding::Mtu),
+            Framing::v2(1 << 20).muxed().padded(Padding::Buckets),
+        ];
+        for _ in 0..200 {
+            let packets: Vec<Vec<u8>> = (0..1 + rng.below(4))
+                .map(|_| {
+                    let len = rng.below(3000);
+                    rng.bytes(len)
+                })
+                .collect();
+            for framing in framings {
+                // Back to back on one stream, as on a connection
+                let mut wire = Vec::new();
+                for packet in &packets {
+                    framing.send(&mut wire, packet).unwrap();
+                }
+                let mut reader = wire.as_slice();
+                let mut buf = vec![0u8; 3000];
+                for packet in &packets {
+                    let n = framing.recv(&mut reader, &mut buf).unwrap();
+                    assert_eq!(&buf[..n], &packet[..], "{:?}", framing);
+                }
+                assert!(reader.is_empty(), "{:?}", framing);
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_lines_round_trip() {
+        let mut rng = Rng(0x4a5d_0c71);
+        for _ in 0..500 {
+            let (addr, prefix) = if rng.below(2) == 0 {
+                let addr = IpAddr::from((rng.next() as u32).to_be_bytes());
+                (addr, rng.below(33) as u8)
+            } else {
+                let addr =
+                    IpAddr::from(((rng.next() as u128) << 64 | rng.next() as u128).to_be_bytes());
+                (addr, rng.below(129) as u8)
+            };
+            let mut request = HandshakeRequest::new(addr, prefix);
+            for _ in 0..rng.below(6) {
+                let key = rng.word();
+                let value = rng.word();
+                request.options.set(&key, value);
+            }
+            let line = request.encode();
+            assert!(line.len() <= MAX_LINE_LEN);
+            let read = read_line(&mut line.as_bytes()).unwrap();
+            assert_eq!(HandshakeRequest::parse(&read).unwrap(), request);
+
+            let reply = format!("OK{}\n", request.options);
+            let read = read_line(&mut reply.as_bytes()).unwrap();
+            assert_eq!(parse_handshake_response(&read).unwrap(), request.options);
+        }
+    }
+
+    #[test]
+    fn read_line_stops_at_max_len() {
+        let line = vec![b'a'; MAX_LINE_LEN * 2];
+        let mut reader = line.as_slice();
+        let err = read_line(&mut reader).unwrap_err();
+        assert!(matches!(err, VpnError::Handshake(_)), "{}", err);
+        // Nothing past the limit was consumed
+        assert_eq!(reader.len(), MAX_LINE_LEN);
+
+        let mut line = vec![b'a'; MAX_LINE_LEN - 1];
+        line.push(b'\n');
+        assert_eq!(read_line(&mut line.as_slice()).unwrap().len(), MAX_LINE_LEN);
+    }
+
+    #[test]
+    fn v2_carries_jumbo_packets() {
+        let framing = Framing::v2(MAX_MTU);
+        for len in [9000, MAX_MTU] {
+            round_trip(framing, Channel::Data, &packet(len));
+            round_trip(framing.muxed(), Channel::Data, &packet(len));
+            round_trip(framing.muxed(), Channel::Stream, &packet(len));
+        }
+    }
+
+    #[test]
+    fn v2_header_holds_lengths_past_v1() {
+        let mut wire = Vec::new();
+        Framing::v2(MAX_MTU)
+            .send(&mut wire, &packet(MAX_MTU))
+            .unwrap();
+        assert_eq!(wire[..4], (MAX_MTU as u32).to_be_bytes());
+        assert_eq!(wire.len(), 4 + MAX_MTU);
+    }
+
+    #[test]
+    fn frame_over_negotiated_max_is_rejected() {
+        // The peer was told 1500 but sends a 9000-byte frame anyway
+        let mut wire = Vec::new();
+        Framing::v2(9000).send(&mut wire, &packet(9000)).unwrap();
+        let mut buf = vec![0u8; MAX_MTU];
+        let err = Framing::v2(1500)
+            .recv_frame(&mut wire.as_slice(), &mut buf)
+            .unwrap_err();
+        assert!(matches!(err, VpnError::Framing(_)), "{}", err);
+    }
+
+    #[test]
+    fn frame_over_negotiated_mtu_is_rejected_unread() {
+        // Both sides offer a 64 KiB max_frame but settle on a 1400-byte MTU
+        let mut offer = Options::new();
+        Framing::v2(MAX_FRAME_V1).offer(&mut offer);
+        let mut reply = Options::new();
+        let framing = Framing::v2(MAX_FRAME_V1)
+            .negotiate(&offer, &mut reply)
+            .capped(1400);
+        assert_eq!(framing.max_len, 1400 + PAD_LEN);
+
+        let length = 1400 + PAD_LEN + 1;
+        let mut wire = (length as u32).to_be_bytes().to_vec();
+        wire.extend(packet(length));
+        let mut reader = wire.as_slice();
+        let mut buf = vec![0u8; MAX_FRAME_V1];
+        let err = framing.recv_frame(&mut reader, &mut buf).unwrap_err();
+        assert!(matches!(err, VpnError::Framing(_)), "{}", err);
+        assert_eq!(reader.len(), length, "body was read");
+    }
+
+    #[test]
+    fn send_refuses_packets_over_max() {
+        let mut wire = Vec::new();
+        let err = Framing::v2(1500)
+            .send(&mut wire, &packet(1501))
+            .unwrap_err();
+        assert!(matches!(err, VpnError::Framing(_)), "{}", err);
+        assert!(wire.is_empty());
+    }
+}
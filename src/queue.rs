@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use crate::config::Tuning;
+use crate::packet;
+use crate::protocol::Channel;
+
+// Outbound packets of one connection, in two classes. Interactive packets
+// (see packet::is_interactive) always go out before bulk ones, so a large
+// transfer filling the tunnel does not add its queueing delay to DNS
+// lookups, ACKs and keystrokes. Control messages (see the mux module) go
+// out before either. Frames of forwarded connections (see the forward
+// module) take turns with bulk packets; they are never dropped, so pushing
+// one waits for room instead.
+#[derive(Debug)]
+pub struct SendQueue {
+    // Packets waiting per class before new ones are dropped
+    depth: usize,
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    // Signalled when a stream frame has been taken
+    room: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    control: VecDeque<Vec<u8>>,
+    interactive: VecDeque<Vec<u8>>,
+    bulk: VecDeque<Vec<u8>>,
+    streams: VecDeque<Vec<u8>>,
+    // Whether a stream frame goes before the next bulk packet
+    stream_turn: bool,
+    closed: bool,
+}
+
+impl SendQueue {
+    pub fn new(depth: usize) -> SendQueue {
+        SendQueue {
+            depth,
+            queues: Mutex::default(),
+            ready: Condvar::new(),
+            room: Condvar::new(),
+        }
+    }
+
+    // Queue a packet; false if its class is full (or the queue closed) and
+    // the packet was dropped
+    pub fn push(&self, packet: &[u8]) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.closed {
+            return false;
+        }
+        let queue = if packet::is_interactive(packet) {
+            &mut queues.interactive
+        } else {
+            &mut queues.bulk
+        };
+        if queue.len() >= self.depth {
+            return false;
+        }
+        queue.push_back(packet.to_vec());
+        self.ready.notify_one();
+        true
+    }
+
+    // Queue a control message; false if the queue is full or closed
+    pub fn push_control(&self, message: Vec<u8>) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.closed || queues.control.len() >= self.depth {
+            return false;
+        }
+        queues.control.push_back(message);
+        self.ready.notify_one();
+        true
+    }
+
+    // Queue a frame of a forwarded connection, waiting while the queue is
+    // full; false once it is closed
+    pub fn push_stream(&self, frame: Vec<u8>) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        while !queues.closed && queues.streams.len() >= self.depth {
+            queues = self.room.wait(queues).unwrap();
+        }
+        if queues.closed {
+            return false;
+        }
+        queues.streams.push_back(frame);
+        self.ready.notify_one();
+        true
+    }
+
+    // Next frame to send and its channel, waiting for one; None once closed
+    pub fn pop(&self) -> Option<(Channel, Vec<u8>)> {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if queues.closed {
+                return None;
+            }
+            if let Some(frame) = queues.next() {
+                self.taken(&frame);
+                return Some(frame);
+            }
+            queues = self.ready.wait(queues).unwrap();
+        }
+    }
+
+    // Next frame to add to a write of `pending` bytes, waiting at most
+    // flush_interval; None means the write should go out now
+    pub fn pop_for_batch(&self, pending: usize, tuning: &Tuning) -> Option<(Channel, Vec<u8>)> {
+        if pending >= tuning.write_coalesce {
+            return None;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let deadline = Instant::now() + tuning.flush_interval;
+        loop {
+            if queues.closed {
+                return None;
+            }
+            if let Some(frame) = queues.next() {
+                self.taken(&frame);
+                return Some(frame);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            queues = self.ready.wait_timeout(queues, deadline - now).unwrap().0;
+        }
+    }
+
+    // Drop everything queued
+    pub fn clear(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.control.clear();
+        queues.interactive.clear();
+        queues.bulk.clear();
+        queues.streams.clear();
+        self.room.notify_all();
+    }
+
+    // Wake the sender and make it stop
+    pub fn close(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.closed = true;
+        queues.control.clear();
+        queues.interactive.clear();
+        queues.bulk.clear();
+        queues.streams.clear();
+        self.ready.notify_all();
+        self.room.notify_all();
+    }
+
+    fn taken(&self, frame: &(Channel, Vec<u8>)) {
+        if frame.0 == Channel::Stream {
+            self.room.notify_one();
+        }
+    }
+}
+
+impl Queues {
+    fn next(&mut self) -> Option<(Channel, Vec<u8>)> {
+        if let Some(message) = self.control.pop_front() {
+            return Some((Channel::Control, message));
+        }
+        if let Some(packet) = self.interactive.pop_front() {
+            return Some((Channel::Data, packet));
+        }
+        self.stream_turn = !self.stream_turn;
+        if self.stream_turn || self.bulk.is_empty() {
+            if let Some(frame) = self.streams.pop_front() {
+                return Some((Channel::Stream, frame));
+            }
+        }
+        self.bulk.pop_front().map(|packet| (Channel::Data, packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, RecvTimeoutError};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Too long to be interactive; the tag tells packets apart
+    fn bulk(tag: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 200];
+        packet[1] = tag;
+        packet
+    }
+
+    fn interactive(tag: u8) -> Vec<u8> {
+        vec![0, tag]
+    }
+
+    fn quick() -> Tuning {
+        Tuning {
+            flush_interval: Duration::from_millis(10),
+            ..Tuning::default()
+        }
+    }
+
+    #[test]
+    fn control_then_interactive_then_bulk_taking_turns_with_streams() {
+        let queue = SendQueue::new(8);
+        assert!(queue.push(&bulk(1)));
+        assert!(queue.push(&bulk(2)));
+        assert!(queue.push_stream(b"s1".to_vec()));
+        assert!(queue.push_stream(b"s2".to_vec()));
+        assert!(queue.push_stream(b"s3".to_vec()));
+        assert!(queue.push(&interactive(1)));
+        assert!(queue.push_control(b"c1".to_vec()));
+        assert!(queue.push(&interactive(2)));
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop_for_batch(0, &quick())).collect();
+        assert_eq!(
+            order,
+            [
+                (Channel::Control, b"c1".to_vec()),
+                (Channel::Data, interactive(1)),
+                (Channel::Data, interactive(2)),
+                (Channel::Stream, b"s1".to_vec()),
+                (Channel::Data, bulk(1)),
+                (Channel::Stream, b"s2".to_vec()),
+                (Channel::Data, bulk(2)),
+                (Channel::Stream, b"s3".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_classes_drop_new_packets() {
+        let queue = SendQueue::new(2);
+        assert!(queue.push(&bulk(1)));
+        assert!(queue.push(&bulk(2)));
+        assert!(!queue.push(&bulk(3)));
+        // The other classes have room of their own
+        assert!(queue.push(&interactive(1)));
+        assert!(queue.push(&interactive(2)));
+        assert!(!queue.push(&interactive(3)));
+        assert!(queue.push_control(b"c1".to_vec()));
+        assert!(queue.push_control(b"c2".to_vec()));
+        assert!(!queue.push_control(b"c3".to_vec()));
+
+        assert_eq!(queue.pop(), Some((Channel::Control, b"c1".to_vec())));
+        assert!(queue.push_control(b"c3".to_vec()));
+        queue.close();
+        assert!(!queue.push(&interactive(4)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn full_stream_queue_makes_push_stream_wait() {
+        let queue = Arc::new(SendQueue::new(1));
+        assert!(queue.push_stream(b"s1".to_vec()));
+
+        let (tx, rx) = mpsc::channel();
+        let pusher = queue.clone();
+        thread::spawn(move || tx.send(pusher.push_stream(b"s2".to_vec())).unwrap());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(200)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(queue.pop(), Some((Channel::Stream, b"s1".to_vec())));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+        assert_eq!(queue.pop(), Some((Channel::Stream, b"s2".to_vec())));
+
+        // Closing releases a waiting push, which then fails
+        assert!(queue.push_stream(b"s3".to_vec()));
+        let (tx, rx) = mpsc::channel();
+        let pusher = queue.clone();
+        thread::spawn(move || tx.send(pusher.push_stream(b"s4".to_vec())).unwrap());
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(200)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        queue.close();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(false));
+    }
+}
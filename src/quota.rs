@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Quota;
+use crate::session::Session;
+
+// What each client (by tunnel address) used today, for quota_bytes and
+// quota_time. Days are UTC days; usage of ended sessions is kept here and
+// a running session's counters are added when checking. A session running
+// across midnight counts towards the day it is checked on.
+// Sent to a muxed client whose quota is used up, shortly before its
+// session is closed
+pub const CLOSE: &[u8] = b"CLOSE QUOTA_EXCEEDED";
+
+#[derive(Debug, Default)]
+pub struct Quotas {
+    used: Mutex<HashMap<IpAddr, Usage>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    day: u64,
+    bytes: u64,
+    time: Duration,
+}
+
+impl Quotas {
+    pub fn new() -> Quotas {
+        Quotas::default()
+    }
+
+    // Whether `addr` has used up its quota, counting `live` if it has a
+    // session running
+    pub fn exceeded(&self, quota: Quota, addr: IpAddr, live: Option<&Session>) -> bool {
+        let live = live.map(|session| (session_bytes(session), session.started.elapsed()));
+        self.exceeded_on(today(), quota, addr, live)
+    }
+
+    fn exceeded_on(
+        &self,
+        day: u64,
+        quota: Quota,
+        addr: IpAddr,
+        live: Option<(u64, Duration)>,
+    ) -> bool {
+        if quota.is_unlimited() {
+            return false;
+        }
+        let mut usage = self.usage(day, addr);
+        if let Some((bytes, time)) = live {
+            usage.bytes += bytes;
+            usage.time += time;
+        }
+        (quota.bytes > 0 && usage.bytes >= quota.bytes)
+            || (!quota.time.is_zero() && usage.time >= quota.time)
+    }
+
+    // Add an ended session to its client's usage
+    pub fn record(&self, session: &Session) {
+        self.record_on(
+            today(),
+            session.addr,
+            session_bytes(session),
+            session.started.elapsed(),
+        );
+    }
+
+    fn record_on(&self, day: u64, addr: IpAddr, bytes: u64, time: Duration) {
+        let mut used = self.used.lock().unwrap();
+        let usage = used.entry(addr).or_default();
+        if usage.day != day {
+            *usage = Usage {
+                day,
+                ..Usage::default()
+            };
+        }
+        usage.bytes += bytes;
+        usage.time += time;
+        // Forget clients that have not been seen today
+        used.retain(|_, usage| usage.day == day);
+    }
+
+    fn usage(&self, day: u64, addr: IpAddr) -> Usage {
+        match self.used.lock().unwrap().get(&addr) {
+            Some(usage) if usage.day == day => *usage,
+            _ => Usage::default(),
+        }
+    }
+}
+
+fn session_bytes(session: &Session) -> u64 {
+    session.rx_bytes.load(Ordering::Relaxed) + session.tx_bytes.load(Ordering::Relaxed)
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Failure, VpnError};
+    use crate::mux::Message;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 9, 0, 2));
+    const DAY: u64 = 20000;
+
+    fn bytes(bytes: u64) -> Quota {
+        Quota {
+            bytes,
+            ..Quota::default()
+        }
+    }
+
+    #[test]
+    fn running_session_past_the_limit_is_exceeded() {
+        let quotas = Quotas::new();
+        let quota = bytes(1000);
+        quotas.record_on(DAY, CLIENT, 600, Duration::from_secs(10));
+        assert!(!quotas.exceeded_on(DAY, quota, CLIENT, None));
+        assert!(!quotas.exceeded_on(DAY, quota, CLIENT, Some((399, Duration::ZERO))));
+        assert!(quotas.exceeded_on(DAY, quota, CLIENT, Some((400, Duration::ZERO))));
+
+        let time = Quota {
+            time: Duration::from_secs(60),
+            ..Quota::default()
+        };
+        assert!(!quotas.exceeded_on(DAY, time, CLIENT, Some((0, Duration::from_secs(49)))));
+        assert!(quotas.exceeded_on(DAY, time, CLIENT, Some((0, Duration::from_secs(50)))));
+        // Unlimited, or another client
+        assert!(!quotas.exceeded_on(
+            DAY,
+            Quota::default(),
+            CLIENT,
+            Some((u64::MAX / 2, Duration::MAX / 2))
+        ));
+        assert!(!quotas.exceeded_on(DAY, quota, "10.9.0.3".parse().unwrap(), None));
+    }
+
+    // What the quota checker sends ends the client with its own exit code
+    #[test]
+    fn exceeded_session_is_closed_with_quota_exceeded() {
+        let Some(Message::Close(reason)) = Message::parse(CLOSE) else {
+            panic!("CLOSE does not parse");
+        };
+        assert_eq!(reason, "QUOTA_EXCEEDED");
+        assert_eq!(VpnError::closed(reason).failure(), Failure::QuotaExceeded);
+    }
+
+    #[test]
+    fn handshakes_are_allowed_again_the_next_day() {
+        let quotas = Quotas::new();
+        let quota = bytes(1000);
+        quotas.record_on(DAY, CLIENT, 1000, Duration::from_secs(10));
+        // What the handshake checks
+        assert!(quotas.exceeded_on(DAY, quota, CLIENT, None));
+        assert!(!quotas.exceeded_on(DAY + 1, quota, CLIENT, None));
+
+        // Usage starts over rather than adding to the old day's
+        quotas.record_on(DAY + 1, CLIENT, 500, Duration::from_secs(10));
+        assert!(!quotas.exceeded_on(DAY + 1, quota, CLIENT, None));
+        quotas.record_on(DAY + 1, CLIENT, 500, Duration::from_secs(10));
+        assert!(quotas.exceeded_on(DAY + 1, quota, CLIENT, None));
+    }
+}
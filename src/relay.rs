@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use crate::config::SharedConfig;
+use crate::control::{self, Context};
+use crate::error::{Result, VpnError};
+use crate::protocol::{read_line, write_line};
+use crate::status::Status;
+use crate::transport::{Listener, Stream};
+
+// Relay mode: no TUN, just pair up connections and copy bytes between them.
+//
+// A server that cannot be reached directly (or the relay host lacks TUN)
+// sets `relay = host:port` and dials out instead of listening; clients use
+// the relay as their server address. Both sides also set the same
+// `relay_id` and open the connection with
+//
+//   RELAY <id> <server|client>\n
+//
+// The relay answers `PAIRED\n` once a connection with the same id and the
+// other role is waiting, and from then on passes the stream through
+// untouched: the handshake and framing run end to end between the peers.
+// The id is the only thing that decides who is paired, so pick one that
+// is hard to guess.
+
+// How long a new connection may take to name its id
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a client waits for the server to be connected to the relay
+const CLIENT_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::Client => "client",
+        }
+    }
+
+    fn other(self) -> Role {
+        match self {
+            Role::Server => Role::Client,
+            Role::Client => Role::Server,
+        }
+    }
+}
+
+// Peer side: register with the relay and wait until paired. Clients give
+// up after CLIENT_WAIT; a server waits for as long as it takes.
+pub fn join(stream: &mut Stream, id: &str, role: Role) -> Result<()> {
+    write_line(stream, &format!("RELAY {} {}\n", id, role.name()))?;
+    if role == Role::Client {
+        stream.set_read_timeout(Some(CLIENT_WAIT))?;
+    }
+    let line = read_line(stream).map_err(|e| match e.io_kind() {
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            VpnError::Handshake("Timed out waiting for the server at the relay".into())
+        }
+        _ => e,
+    })?;
+    stream.set_read_timeout(None)?;
+    match line.trim_end() {
+        "PAIRED" => Ok(()),
+        "" => Err(VpnError::Handshake("Relay closed the connection".into())),
+        reply => Err(VpnError::Handshake(format!("Relay refused: {}", reply))),
+    }
+}
+
+// Connections waiting for a partner, by id and role
+type Waiting = Arc<Mutex<HashMap<(String, Role), VecDeque<Stream>>>>;
+
+pub fn relay_mode(config: SharedConfig) -> Result<()> {
+    info!("Starting relay mode.");
+    let status = Arc::new(Status::new("relay"));
+    let _control = control::start(Arc::new(Context {
+        config: config.clone(),
+        status: status.clone(),
+        sessions: None,
+        bans: None,
+        peer: None,
+        filter: None,
+    }));
+
+    let listen = config.read().unwrap().listen_addrs();
+    let mut listeners = Vec::new();
+    for addr in &listen {
+        let listener = Listener::bind(addr)?;
+        info!("Relay listening on {}", addr);
+        listeners.push(listener);
+    }
+    status.listener_bound.store(true, Ordering::Relaxed);
+
+    let waiting = Waiting::default();
+    let mut handles = Vec::new();
+    for listener in listeners {
+        let (waiting, status) = (waiting.clone(), status.clone());
+        handles.push(thread::spawn(move || loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Accept failed: {}", e);
+                    continue;
+                }
+            };
+            let (waiting, status) = (waiting.clone(), status.clone());
+            thread::spawn(move || {
+                if let Err(e) = pair(stream, &waiting, &status) {
+                    warn!("Relay connection failed: {}", e);
+                }
+            });
+        }));
+    }
+    for handle in handles {
+        handle.join().ok();
+    }
+    info!("Relay shutting down.");
+    Ok(())
+}
+
+// Read the RELAY line, then either wait for a partner or splice with one
+fn pair(mut stream: Stream, waiting: &Waiting, status: &Status) -> Result<()> {
+    let peer = stream.peer()?;
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let line = read_line(&mut stream)?;
+    stream.set_read_timeout(None)?;
+    let (id, role) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["RELAY", id, "server"] => (id.to_string(), Role::Server),
+        ["RELAY", id, "client"] => (id.to_string(), Role::Client),
+        _ => {
+            write_line(&mut stream, "ERR expected RELAY <id> <server|client>\n").ok();
+            return Err(VpnError::Handshake(format!(
+                "Invalid relay request from {}",
+                peer
+            )));
+        }
+    };
+    debug!("{} joined relay as {}", peer, role.name());
+
+    // Take the oldest waiting partner, forgetting connections that went away
+    let mut partner = None;
+    {
+        let mut waiting = waiting.lock().unwrap();
+        for queue in waiting.values_mut() {
+            queue.retain(|s| !s.is_closed());
+        }
+        waiting.retain(|_, queue| !queue.is_empty());
+        if let Some(queue) = waiting.get_mut(&(id.clone(), role.other())) {
+            while let Some(mut other) = queue.pop_front() {
+                if write_line(&mut other, "PAIRED\n").is_ok() {
+                    partner = Some(other);
+                    break;
+                }
+            }
+        }
+        if partner.is_none() {
+            info!("{} waiting at the relay as {}", peer, role.name());
+            waiting.entry((id, role)).or_default().push_back(stream);
+            return Ok(());
+        }
+    }
+    let partner = partner.unwrap();
+    write_line(&mut stream, "PAIRED\n")?;
+
+    let label = format!("{} <-> {}", peer, partner.peer()?);
+    info!("Relaying {}", label);
+    status.event(format!("Relaying {}", label));
+    splice(stream, partner)?;
+    info!("Relay {} ended", label);
+    status.event(format!("Relay {} ended", label));
+    Ok(())
+}
+
+// Copy both ways until either side closes, then close both
+fn splice(a: Stream, b: Stream) -> Result<()> {
+    let (mut a_reader, mut b_writer) = (a.try_clone()?, b.try_clone()?);
+    let forward = thread::spawn(move || {
+        io::copy(&mut a_reader, &mut b_writer).ok();
+        a_reader.shutdown();
+        b_writer.shutdown();
+    });
+    let (mut b_reader, mut a_writer) = (b, a);
+    io::copy(&mut b_reader, &mut a_writer).ok();
+    b_reader.shutdown();
+    a_writer.shutdown();
+    forward.join().ok();
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use log::{error, info};
+use nix::libc;
+
+use crate::config::{Config, SharedConfig};
+use crate::error::{Result, VpnError};
+use crate::handoff;
+
+// Bumped on every successful reload so long-running loops can notice
+pub static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// Block SIGHUP (reload) and SIGUSR2 (upgrade, see handoff) and handle
+// them on a dedicated thread.
+// Must be called before any other thread is spawned so they inherit the mask.
+pub fn spawn_signal_handler(config: SharedConfig) -> Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) != 0 {
+            return Err(VpnError::Config("Failed to block SIGHUP".into()));
+        }
+        set
+    };
+
+    thread::spawn(move || loop {
+        let mut sig: libc::c_int = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            error!("sigwait failed; SIGHUP reload disabled.");
+            break;
+        }
+        if sig == libc::SIGUSR2 {
+            info!("Received SIGUSR2, handing over to a new process.");
+            match handoff::upgrade() {
+                Ok(()) => std::process::exit(0),
+                Err(e) => error!("Upgrade failed: {}", e),
+            }
+            continue;
+        }
+        info!("Received SIGHUP, reloading configuration.");
+        if let Err(e) = reload(&config) {
+            error!("Reload failed, keeping current configuration: {}", e);
+        }
+    });
+    Ok(())
+}
+
+// Re-read the config file and apply the settings that can change at runtime.
+// Only log_level, allow and the [client] sections are reloaded; everything
+// else needs a restart.
+pub fn reload(config: &SharedConfig) -> Result<()> {
+    let path = config
+        .read()
+        .unwrap()
+        .path
+        .clone()
+        .ok_or_else(|| VpnError::Config("No config file to reload".into()))?;
+    let mut new = Config::load(&path)?;
+    new.apply_env()?;
+
+    let mut current = config.write().unwrap();
+    if let Some(level) = new.log_level {
+        log::set_max_level(level);
+        info!("Log level set to {}.", level);
+    }
+    current.log_level = new.log_level;
+    current.allow = new.allow;
+    current.clients = new.clients;
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    info!("Configuration reloaded from {}.", path.display());
+    Ok(())
+}
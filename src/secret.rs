@@ -0,0 +1,243 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::OnceLock;
+
+use nix::libc;
+
+use crate::crypto::{self, Hmac, DIGEST_LEN};
+
+// Secrets sealed with a passphrase (`vpn secret seal`), for config values
+// written as `sealed:<hex>`; see config::secret.
+//
+// PBKDF2-HMAC-SHA256 over the passphrase and a random salt gives two keys.
+// The secret is XORed with HMAC-SHA256(first key, block counter) and
+// followed by an HMAC-SHA256 under the second key over everything before
+// it, so a wrong passphrase or an altered value is refused rather than
+// turned into garbage. The hex holds the rounds (4 bytes), the salt, the
+// ciphertext and the tag.
+
+pub const PREFIX: &str = "sealed:";
+// Where the passphrase comes from when not asked for on the terminal
+pub const PASSPHRASE_ENV: &str = "RUST_VPN_PASSPHRASE";
+const ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = 4 + SALT_LEN;
+
+// `vpn secret seal`: read a secret (from the terminal, or stdin when that
+// is not one) and print it sealed. `vpn secret unseal [value]`: print what
+// a sealed value holds. Returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let result = match args.first().map(String::as_str) {
+        Some("seal") => seal_input(),
+        Some("unseal") => unseal_input(args.get(1)),
+        _ => {
+            eprintln!("secret: expected seal or unseal");
+            return 2;
+        }
+    };
+    match result {
+        Ok(out) => {
+            println!("{}", out);
+            0
+        }
+        Err(e) => {
+            eprintln!("secret: {}", e);
+            1
+        }
+    }
+}
+
+fn seal_input() -> Result<String, String> {
+    let secret = input("Secret: ")?;
+    // Asked twice, since a typo here is only found when the secret is needed
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = ask("Passphrase: ").map_err(|e| e.to_string())?;
+            if ask("Passphrase again: ").map_err(|e| e.to_string())? != passphrase {
+                return Err("the passphrases differ".into());
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err("empty passphrase".into());
+    }
+    seal(&secret, &passphrase).map_err(|e| e.to_string())
+}
+
+fn unseal_input(value: Option<&String>) -> Result<String, String> {
+    let value = match value {
+        Some(value) => value.clone(),
+        None => input("Sealed value: ")?,
+    };
+    unseal(value.trim(), &passphrase()?)
+}
+
+// A value from the terminal without echo, or all of stdin without its
+// trailing newline
+fn input(prompt: &str) -> Result<String, String> {
+    if io::stdin().is_terminal() {
+        return ask(prompt).map_err(|e| e.to_string());
+    }
+    let mut value = String::new();
+    io::stdin()
+        .read_to_string(&mut value)
+        .map_err(|e| e.to_string())?;
+    Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Seal `secret` under `passphrase`, as a config value
+pub fn seal(secret: &str, passphrase: &str) -> io::Result<String> {
+    Ok(seal_with(secret, passphrase, ROUNDS, crypto::random()?))
+}
+
+fn seal_with(secret: &str, passphrase: &str, rounds: u32, salt: [u8; SALT_LEN]) -> String {
+    let mut out = rounds.to_be_bytes().to_vec();
+    out.extend_from_slice(&salt);
+    let (cipher, mac) = keys(passphrase, &salt, rounds);
+    let mut data = secret.as_bytes().to_vec();
+    apply_keystream(&cipher, &mut data);
+    out.extend_from_slice(&data);
+    let tag = crypto::hmac(&mac, &out);
+    out.extend_from_slice(&tag);
+    format!("{}{}", PREFIX, hex(&out))
+}
+
+// The secret in a `sealed:` value
+pub fn unseal(value: &str, passphrase: &str) -> Result<String, String> {
+    let sealed = value
+        .strip_prefix(PREFIX)
+        .and_then(unhex)
+        .filter(|sealed| sealed.len() >= HEADER_LEN + DIGEST_LEN)
+        .ok_or("malformed sealed value")?;
+    let rounds = u32::from_be_bytes(sealed[..4].try_into().unwrap());
+    if rounds == 0 || rounds > 10 * ROUNDS {
+        return Err(format!("sealed value asks for {} rounds", rounds));
+    }
+    let (body, tag) = sealed.split_at(sealed.len() - DIGEST_LEN);
+    let (cipher, mac) = keys(passphrase, &body[4..HEADER_LEN], rounds);
+    if !crypto::equal(&crypto::hmac(&mac, body), tag) {
+        return Err("wrong passphrase, or the sealed value was altered".into());
+    }
+    let mut data = body[HEADER_LEN..].to_vec();
+    apply_keystream(&cipher, &mut data);
+    String::from_utf8(data).map_err(|_| "sealed secret is not text".into())
+}
+
+// Cipher and MAC keys for a passphrase and salt
+fn keys(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0u8; 64];
+    crypto::pbkdf2(passphrase.as_bytes(), salt, rounds, &mut out);
+    let (cipher, mac) = out.split_at(32);
+    (cipher.try_into().unwrap(), mac.try_into().unwrap())
+}
+
+fn apply_keystream(key: &[u8], data: &mut [u8]) {
+    let prf = Hmac::new(key);
+    for (counter, chunk) in data.chunks_mut(DIGEST_LEN).enumerate() {
+        let mut block = prf.clone();
+        block.update(&(counter as u64).to_be_bytes());
+        for (b, k) in chunk.iter_mut().zip(block.finish()) {
+            *b ^= k;
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+// The passphrase for sealed config values: RUST_VPN_PASSPHRASE, or else
+// asked for on the terminal once per process (a SIGHUP reload reuses it)
+pub fn passphrase() -> Result<String, String> {
+    static PASSPHRASE: OnceLock<String> = OnceLock::new();
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase.clone());
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => ask("Passphrase: ").map_err(|e| {
+            format!(
+                "sealed value needs a passphrase ({} or a terminal): {}",
+                PASSPHRASE_ENV, e
+            )
+        })?,
+    };
+    Ok(PASSPHRASE.get_or_init(|| passphrase).clone())
+}
+
+// Read a line from the terminal without echoing it
+pub fn ask(prompt: &str) -> io::Result<String> {
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut quiet = saved;
+    quiet.c_lflag &= !libc::ECHO;
+    quiet.c_lflag |= libc::ECHONL;
+    (&tty).write_all(prompt.as_bytes())?;
+    if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Few rounds, so the tests do not spend seconds in PBKDF2
+    fn sealed(secret: &str) -> String {
+        seal_with(secret, "hunter2", 10, [7; SALT_LEN])
+    }
+
+    #[test]
+    fn sealed_secret_unseals() {
+        for secret in ["", "s3cret", &"long secret ".repeat(20)] {
+            assert_eq!(unseal(&sealed(secret), "hunter2").unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        assert!(unseal(&sealed("s3cret"), "hunter3").is_err());
+    }
+
+    #[test]
+    fn altered_value_is_refused() {
+        let value = sealed("s3cret");
+        for i in PREFIX.len()..value.len() {
+            let mut altered = value.clone().into_bytes();
+            altered[i] = if altered[i] == b'0' { b'1' } else { b'0' };
+            let altered = String::from_utf8(altered).unwrap();
+            assert!(unseal(&altered, "hunter2").is_err(), "digit {}", i);
+        }
+        assert!(unseal(&value[..value.len() - 2], "hunter2").is_err());
+        assert!(unseal("sealed:zz", "hunter2").is_err());
+    }
+
+    #[test]
+    fn same_secret_seals_differently_with_another_salt() {
+        let a = seal_with("s3cret", "hunter2", 10, [1; SALT_LEN]);
+        let b = seal_with("s3cret", "hunter2", 10, [2; SALT_LEN]);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,295 @@
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nix::libc;
+
+use crate::control;
+use crate::protocol::Framing;
+
+// `vpn selftest`: check that this build and machine can carry traffic,
+// before debugging a real deployment.
+//
+// As root, a server and a client are started from this binary, each in a
+// network namespace of its own so traffic between their TUNs cannot take
+// a shortcut through the host, and connected over a Unix socket. UDP
+// datagrams of several sizes then go from the client's tunnel address to
+// an echo on the server's and back.
+//
+// Without root (or without /dev/net/tun) it falls back to echo mode: the
+// same payloads go through the framing code over loopback TCP to an echo
+// thread. That checks the build, not the TUN setup.
+
+const SERVER_IP: &str = "10.99.0.1";
+const CLIENT_IP: &str = "10.99.0.2";
+const ECHO_PORT: u16 = 7;
+// Payloads sent; the largest fills a 1500-byte MTU
+const SIZES: [usize; 6] = [1, 64, 512, 1024, 1400, 1472];
+// How long the client may take to get a session
+const CONNECT_WAIT: Duration = Duration::from_secs(10);
+// How long to wait for each echo
+const ECHO_WAIT: Duration = Duration::from_secs(2);
+
+// Run the test and return the exit code: 0 when it passed
+pub fn run() -> i32 {
+    let privileged = unsafe { libc::geteuid() } == 0 && Path::new("/dev/net/tun").exists();
+    let result = if privileged {
+        println!("selftest: running a server and a client in network namespaces");
+        tunnel()
+    } else {
+        println!("selftest: not root or no /dev/net/tun; testing the framing only (echo mode)");
+        echo()
+    };
+    match result {
+        Ok(()) => {
+            println!("selftest: PASS");
+            0
+        }
+        Err(e) => {
+            println!("selftest: FAIL: {}", e);
+            1
+        }
+    }
+}
+
+fn tunnel() -> io::Result<()> {
+    let dir = env::temp_dir().join(format!("vpn-selftest-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let result = Instances::start(&dir).and_then(|instances| {
+        let result = instances
+            .wait_connected()
+            .and_then(|()| instances.exchange());
+        if result.is_err() {
+            instances.show_logs();
+        }
+        result
+    });
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+// The server and client under test; killed when dropped
+struct Instances {
+    server: Child,
+    client: Child,
+    dir: PathBuf,
+}
+
+impl Instances {
+    fn start(dir: &Path) -> io::Result<Instances> {
+        let socket = format!("unix:{}", dir.join("vpn.sock").display());
+        let mut server = spawn(
+            dir,
+            "server",
+            &[&socket, "0", &format!("{}/24", SERVER_IP), "vpnst0"],
+        )?;
+        println!("selftest: server started (pid {})", server.id());
+        // The client gives up if the socket is not there yet
+        let deadline = Instant::now() + CONNECT_WAIT;
+        while !dir.join("vpn.sock").exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let args = [
+            &socket,
+            "0",
+            &format!("{}/24", CLIENT_IP),
+            "vpnst1",
+            "--reconnect",
+            "0",
+        ];
+        let client = match spawn(dir, "client", &args) {
+            Ok(client) => client,
+            Err(e) => {
+                server.kill().ok();
+                server.wait().ok();
+                return Err(e);
+            }
+        };
+        println!("selftest: client started (pid {})", client.id());
+        Ok(Instances {
+            server,
+            client,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    // Until the client reports a healthy session
+    fn wait_connected(&self) -> io::Result<()> {
+        let ctl = self.dir.join("client.ctl");
+        let deadline = Instant::now() + CONNECT_WAIT;
+        while Instant::now() < deadline {
+            let reply = control::request(&ctl, "health").unwrap_or_default();
+            if reply.starts_with("health: 0 ") {
+                println!("selftest: client connected");
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no session within {}s", CONNECT_WAIT.as_secs()),
+        ))
+    }
+
+    // Echo SIZES through the tunnel
+    fn exchange(&self) -> io::Result<()> {
+        let echo = in_netns(self.server.id(), || {
+            let socket = UdpSocket::bind((SERVER_IP, ECHO_PORT))?;
+            socket.set_read_timeout(Some(ECHO_WAIT))?;
+            let mut buf = [0u8; 2048];
+            for _ in SIZES {
+                let (n, from) = socket.recv_from(&mut buf)?;
+                socket.send_to(&buf[..n], from)?;
+            }
+            Ok(())
+        });
+        let sender = in_netns(self.client.id(), || {
+            let socket = UdpSocket::bind((CLIENT_IP, 0))?;
+            socket.connect((SERVER_IP, ECHO_PORT))?;
+            socket.set_read_timeout(Some(ECHO_WAIT))?;
+            // Give the echo time to bind
+            thread::sleep(Duration::from_millis(200));
+            let mut buf = [0u8; 2048];
+            for size in SIZES {
+                let payload = pattern(size);
+                socket.send(&payload)?;
+                let n = socket.recv(&mut buf).map_err(|e| {
+                    io::Error::new(e.kind(), format!("no echo of {} bytes: {}", size, e))
+                })?;
+                if buf[..n] != payload[..] {
+                    return Err(io::Error::other(format!("echo of {} bytes differs", size)));
+                }
+            }
+            Ok(())
+        });
+        let sent = sender
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("sender panicked")));
+        let echoed = echo
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("echo panicked")));
+        sent?;
+        echoed?;
+        println!(
+            "selftest: {} datagrams of {} to {} bytes echoed through the tunnel",
+            SIZES.len(),
+            SIZES[0],
+            SIZES[SIZES.len() - 1]
+        );
+        Ok(())
+    }
+
+    fn show_logs(&self) {
+        for name in ["server", "client"] {
+            let log = fs::read_to_string(self.dir.join(format!("{}.log", name)));
+            println!("--- {} log ---", name);
+            print!("{}", log.unwrap_or_default());
+        }
+    }
+}
+
+impl Drop for Instances {
+    fn drop(&mut self) {
+        for child in [&mut self.client, &mut self.server] {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+// Start this binary in `mode` in a new network namespace, logging to
+// <mode>.log in `dir`. Settings from the environment are left out so only
+// the arguments apply.
+fn spawn(dir: &Path, mode: &str, args: &[&str]) -> io::Result<Child> {
+    let log = File::create(dir.join(format!("{}.log", mode)))?;
+    let ctl = dir.join(format!("{}.ctl", mode));
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg(mode)
+        .args(args)
+        .arg("--ctl-socket")
+        .arg(&ctl)
+        .env("RUST_LOG", "info")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    for (key, _) in env::vars().filter(|(key, _)| key.starts_with("RUST_VPN_")) {
+        command.env_remove(key);
+    }
+    unsafe {
+        command.pre_exec(|| {
+            if libc::unshare(libc::CLONE_NEWNET) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+// Run `f` on a thread of its own inside the network namespace of `pid`
+fn in_netns<F>(pid: u32, f: F) -> JoinHandle<io::Result<()>>
+where
+    F: FnOnce() -> io::Result<()> + Send + 'static,
+{
+    thread::spawn(move || {
+        let ns = File::open(format!("/proc/{}/ns/net", pid))?;
+        if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        f()
+    })
+}
+
+// Echo mode: frame each payload over loopback TCP and check what comes back
+fn echo() -> io::Result<()> {
+    let framing = Framing::v2(SIZES[SIZES.len() - 1]);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr: SocketAddr = listener.local_addr()?;
+    let echo = thread::spawn(move || -> crate::Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let mut buf = [0u8; 2048];
+        for _ in SIZES {
+            let n = framing.recv(&mut stream, &mut buf)?;
+            framing.send(&mut stream, &buf[..n])?;
+        }
+        Ok(())
+    });
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(ECHO_WAIT))?;
+    let mut buf = [0u8; 2048];
+    for size in SIZES {
+        let payload = pattern(size);
+        framing
+            .send(&mut stream, &payload)
+            .map_err(io::Error::other)?;
+        let n = framing
+            .recv(&mut stream, &mut buf)
+            .map_err(io::Error::other)?;
+        if buf[..n] != payload[..] {
+            return Err(io::Error::other(format!("echo of {} bytes differs", size)));
+        }
+    }
+    echo.join()
+        .unwrap_or_else(|_| Err(io::Error::other("echo panicked").into()))
+        .map_err(io::Error::other)?;
+    println!(
+        "selftest: {} frames of {} to {} bytes echoed over loopback TCP",
+        SIZES.len(),
+        SIZES[0],
+        SIZES[SIZES.len() - 1]
+    );
+    Ok(())
+}
+
+// Payload of `size` bytes that shows reordering or corruption
+fn pattern(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 + size) as u8).collect()
+}
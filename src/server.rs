@@ -0,0 +1,1046 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Chain, Cursor, Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::fd::IntoRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::affinity;
+use crate::ban::BanList;
+use crate::config::{Config, SharedConfig};
+use crate::control::{self, Context};
+use crate::error::{Result, VpnError};
+use crate::exec_auth::ExecAuth;
+use crate::expose::Exposed;
+use crate::filter::Filter;
+use crate::forward;
+use crate::greeting::{self, Opening};
+use crate::handoff::{self, Inherited, Successor};
+use crate::knock::KnockGate;
+use crate::logging;
+use crate::mux::{self, Message};
+use crate::packet;
+use crate::plugin::{AuthBackend, AuthRequest, Direction, PacketInspector, Plugins, Verdict};
+use crate::portmap;
+use crate::protocol::{peer_mtu, write_line, Channel, HandshakeRequest, Negotiated, Options};
+use crate::quota::{self, Quotas};
+use crate::relay::{self, Role};
+use crate::reload;
+use crate::sandbox;
+use crate::session::{Session, SessionManager};
+use crate::status::Status;
+use crate::transport::{Listener, Peer, Stream, UNIX_PREFIX};
+use crate::tun::TunDevice;
+
+// Wait before reconnecting to a relay that could not be reached
+const RELAY_RETRY: Duration = Duration::from_secs(5);
+// How often an idle session checks whether to stop for a handoff
+const FREEZE_POLL: Duration = Duration::from_millis(250);
+// How long a handoff waits for sessions to stop between frames
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+// How often running sessions are checked against their quota
+const QUOTA_CHECK: Duration = Duration::from_secs(1);
+// How long a muxed client told its quota is used up has to disconnect
+const QUOTA_GRACE: Duration = Duration::from_secs(5);
+// How long the rest of a frame may take to arrive once it has started
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+// How long connecting to the web server behind a shared port may take
+const SHARE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Pushed for ip6_default_route. Two halves are more specific than ::/0, so
+// they win over the client's default route without replacing it.
+const IP6_DEFAULT_ROUTES: &str = "::/1,8000::/1";
+
+pub fn server_mode(config: SharedConfig) -> Result<()> {
+    let mut server = VpnServer::new(config.clone());
+    if let Some(program) = config.read().unwrap().auth_exec.clone() {
+        server.add_auth_backend(Box::new(ExecAuth::new(program)));
+    }
+    server.run()
+}
+
+// The server as a library: register extensions, then run()
+pub struct VpnServer {
+    config: SharedConfig,
+    plugins: Plugins,
+}
+
+impl VpnServer {
+    pub fn new(config: SharedConfig) -> VpnServer {
+        VpnServer {
+            config,
+            plugins: Plugins::default(),
+        }
+    }
+
+    pub fn add_inspector(&mut self, inspector: Box<dyn PacketInspector>) -> &mut VpnServer {
+        self.plugins.add_inspector(inspector);
+        self
+    }
+
+    pub fn add_auth_backend(&mut self, backend: Box<dyn AuthBackend>) -> &mut VpnServer {
+        self.plugins.add_auth_backend(backend);
+        self
+    }
+
+    pub fn run(self) -> Result<()> {
+        info!("Starting server mode.");
+        let (bans, filter) = {
+            let c = self.config.read().unwrap();
+            let bans = BanList::new(
+                c.handshake_rate,
+                c.ban_after,
+                Duration::from_secs(c.ban_time),
+            );
+            (bans, Filter::new(&c.block))
+        };
+        let server = Arc::new(Server {
+            config: self.config,
+            status: Arc::new(Status::new("server")),
+            sessions: Arc::new(SessionManager::new()),
+            bans: Arc::new(bans),
+            filter: Arc::new(filter),
+            quotas: Quotas::new(),
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            plugins: self.plugins,
+        });
+        run(server)
+    }
+}
+
+// State shared by the listener, TUN reader and session threads
+struct Server {
+    config: SharedConfig,
+    status: Arc<Status>,
+    sessions: Arc<SessionManager>,
+    bans: Arc<BanList>,
+    filter: Arc<Filter>,
+    quotas: Quotas,
+    // Connections that have not completed their handshake yet
+    handshakes: Arc<AtomicUsize>,
+    plugins: Plugins,
+}
+
+impl Server {
+    // Count a new connection as half-open, unless max_handshakes already are
+    fn begin_handshake(&self) -> Option<Handshaking> {
+        let max = self.config.read().unwrap().max_handshakes;
+        let handshaking = Handshaking::new(&self.handshakes);
+        if max > 0 && self.handshakes.load(Ordering::SeqCst) > max {
+            return None;
+        }
+        Some(handshaking)
+    }
+
+    // Let a new connection start its handshake, or close it at once when
+    // max_handshakes are already in progress
+    fn admit(&self, stream: &Stream, peer: &Peer) -> Option<Handshaking> {
+        let handshaking = self.begin_handshake();
+        if handshaking.is_none() {
+            warn!(
+                "Closing connection from {}: too many handshakes in progress",
+                peer
+            );
+            stream.shutdown();
+        }
+        handshaking
+    }
+
+    // A client's handshake was rejected
+    fn failure(&self, peer: &Peer) {
+        if let Some(ip) = peer.ip() {
+            self.bans.failure(ip);
+        }
+    }
+}
+
+fn run(server: Arc<Server>) -> Result<()> {
+    // Started by an upgrade: take over from the previous process first, so
+    // the sockets below are inherited rather than bound again
+    let mut inherited = handoff::receive()?;
+    let config = &server.config;
+    if let Some(fd) = inherited.as_mut().and_then(|i| i.tun.take()) {
+        config.write().unwrap().tun_fd = Some(fd.into_raw_fd());
+    }
+    let (listen, mtu, stdio, upnp, relay) = {
+        let c = config.read().unwrap();
+        (
+            c.listen_addrs(),
+            c.mtu,
+            c.stdio,
+            c.upnp.then_some(c.upnp_gateway),
+            c.relay.clone().zip(c.relay_id.clone()),
+        )
+    };
+    let status = &server.status;
+    let _control = control::start(Arc::new(Context {
+        config: config.clone(),
+        status: status.clone(),
+        sessions: Some(server.sessions.clone()),
+        bans: Some(server.bans.clone()),
+        peer: None,
+        filter: Some(server.filter.clone()),
+    }));
+
+    let (tun, _) = config.read().unwrap().open_tun(mtu)?;
+    status.tun_up.store(true, Ordering::Relaxed);
+
+    if stdio {
+        return serve_stdio(tun, server.clone(), mtu);
+    }
+    if let Some(relay) = relay {
+        return serve_relay(relay, tun, server.clone(), mtu);
+    }
+
+    // Bind everything up front so a bad address fails startup
+    let mut listeners = Vec::new();
+    for addr in &listen {
+        let listener = Listener::bind(addr)?;
+        info!("Server listening on {}", addr);
+        listeners.push(listener);
+    }
+    status.listener_bound.store(true, Ordering::Relaxed);
+    if let Some(gateway) = upnp {
+        let ports = listen
+            .iter()
+            .filter(|addr| !addr.starts_with(UNIX_PREFIX))
+            .filter_map(|addr| addr.rsplit_once(':')?.1.parse().ok())
+            .collect();
+        portmap::spawn(ports, gateway, status.clone());
+    }
+    let (knock, knock_key) = {
+        let c = config.read().unwrap();
+        (c.knock.clone(), c.knock_key.clone())
+    };
+    let gate = if knock.is_empty() {
+        None
+    } else {
+        Some(KnockGate::spawn(knock, knock_key.as_deref())?)
+    };
+
+    spawn_tun_reader(tun.try_clone()?, server.clone(), mtu);
+    spawn_quota_checker(server.clone());
+    if let Some(inherited) = inherited {
+        resume(inherited, &tun, &server)?;
+    }
+    {
+        let (server, tun) = (server.clone(), tun.try_clone()?);
+        handoff::enable(move || upgrade(&server, &tun));
+    }
+
+    let mut handles = Vec::new();
+    for listener in listeners {
+        let tun = tun.try_clone()?;
+        let (server, gate) = (server.clone(), gate.clone());
+        handles.push(thread::spawn(move || {
+            accept_loop(listener, tun, server, gate)
+        }));
+    }
+    if config.read().unwrap().sandbox {
+        sandbox::apply()?;
+    }
+    for handle in handles {
+        handle.join().ok();
+    }
+    info!("Server shutting down.");
+    Ok(())
+}
+
+// One session over stdin/stdout, e.g. when started by `ssh host vpn server
+// --stdio on ...`; the server exits when it ends
+fn serve_stdio<T: TunDevice>(tun: T, server: Arc<Server>, mtu: usize) -> Result<()> {
+    info!("Serving one session over stdin/stdout.");
+    let stream = Stream::stdio()?;
+    server.status.listener_bound.store(true, Ordering::Relaxed);
+    spawn_tun_reader(tun.try_clone()?, server.clone(), mtu);
+    spawn_quota_checker(server.clone());
+    if server.config.read().unwrap().sandbox {
+        sandbox::apply()?;
+    }
+    let peer = stream.peer()?;
+    let handshaking = Handshaking::new(&server.handshakes);
+    handle_client(stream, peer, tun, &server, handshaking)?;
+    info!("Server shutting down.");
+    Ok(())
+}
+
+// Clients reach us through a relay: keep one connection waiting there
+// and start a session each time the relay pairs it with a client
+fn serve_relay<T: TunDevice>(
+    (relay, id): (String, String),
+    tun: T,
+    server: Arc<Server>,
+    mtu: usize,
+) -> Result<()> {
+    spawn_tun_reader(tun.try_clone()?, server.clone(), mtu);
+    spawn_quota_checker(server.clone());
+    if server.config.read().unwrap().sandbox {
+        sandbox::apply()?;
+    }
+    loop {
+        let mut stream = match Stream::connect(&relay) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Cannot reach relay {}: {}", relay, e);
+                server.status.listener_bound.store(false, Ordering::Relaxed);
+                thread::sleep(RELAY_RETRY);
+                continue;
+            }
+        };
+        server.status.listener_bound.store(true, Ordering::Relaxed);
+        info!("Waiting for a client at relay {}", relay);
+        match relay::join(&mut stream, &id, Role::Server) {
+            Ok(()) => {
+                info!("Client paired through relay {}", relay);
+                spawn_client(stream, &tun, &server);
+            }
+            Err(e) => {
+                error!("Relay {} failed: {}", relay, e);
+                server.status.listener_bound.store(false, Ordering::Relaxed);
+                thread::sleep(RELAY_RETRY);
+            }
+        }
+    }
+}
+
+fn accept_loop<T: TunDevice>(
+    listener: Listener,
+    tun: T,
+    server: Arc<Server>,
+    gate: Option<Arc<KnockGate>>,
+) {
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        if handoff::freezing() {
+            debug!("Closing new connection: handing off to a new process");
+            stream.shutdown();
+            continue;
+        }
+        // Local (Unix socket) clients need not knock and are not limited
+        if let Ok(Some(ip)) = stream.peer().map(|p| p.ip()) {
+            if gate.as_ref().is_some_and(|gate| !gate.allows(ip)) {
+                debug!("Closing connection from {}: no knock", ip);
+                stream.shutdown();
+                continue;
+            }
+            if !server.bans.admit(ip) {
+                debug!("Closing connection from {}: banned or over rate", ip);
+                stream.shutdown();
+                continue;
+            }
+        }
+        spawn_client(stream, &tun, &server);
+    }
+}
+
+// Keeps a connection counted in Server::handshakes until dropped
+struct Handshaking(Arc<AtomicUsize>);
+
+impl Handshaking {
+    fn new(count: &Arc<AtomicUsize>) -> Handshaking {
+        count.fetch_add(1, Ordering::SeqCst);
+        Handshaking(count.clone())
+    }
+}
+
+impl Drop for Handshaking {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Run a session with a newly connected client on its own thread
+fn spawn_client<T: TunDevice>(stream: Stream, tun: &T, server: &Arc<Server>) {
+    let peer = match stream.peer() {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    // Connections that never finish their handshake must not pile up
+    let Some(handshaking) = server.admit(&stream, &peer) else {
+        return;
+    };
+    info!("Client connected from: {}", peer);
+    if let Some(keepalive) = server.config.read().unwrap().kernel_keepalive() {
+        if let Err(e) = stream.set_keepalive(&keepalive) {
+            warn!("Cannot enable TCP keepalive for {}: {}", peer, e);
+        }
+    }
+    let tun = match tun.try_clone() {
+        Ok(tun) => tun,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let server = server.clone();
+    thread::spawn(move || {
+        if let Err(e) = handle_client(stream, peer.clone(), tun, &server, handshaking) {
+            error!("Session with {} failed: {}", peer, e);
+            server.status.event(format!("{} failed: {}", peer, e));
+        }
+    });
+}
+
+// Handshake with one client, then forward Client -> Server -> TUN until it disconnects
+fn handle_client<T: TunDevice>(
+    mut stream: Stream,
+    peer: Peer,
+    tun: T,
+    server: &Server,
+    handshaking: Handshaking,
+) -> Result<()> {
+    let (config, status, sessions) = (&server.config, &server.status, &server.sessions);
+    // A relay or stdio server runs its sessions one after another here
+    logging::set_session(None);
+    info!("Starting handshake with client...");
+    // The whole line, not each read, has to arrive in time
+    let (timeout, share, sni) = {
+        let c = config.read().unwrap();
+        (c.handshake_timeout, c.share.clone(), c.sni.clone())
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let opening = greeting::accept(&mut stream, deadline, share.is_some(), sni.as_deref());
+    let line = match opening {
+        Ok(Opening::Handshake(line)) => line,
+        Ok(Opening::Foreign(read)) => {
+            drop(handshaking);
+            return pass_on(stream, &peer, share.as_deref().unwrap_or_default(), &read);
+        }
+        Err(e) if e.io_kind() == Some(io::ErrorKind::TimedOut) => {
+            return Err(VpnError::Handshake(format!(
+                "No handshake from {} within {}s",
+                peer, timeout
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+    let request = match HandshakeRequest::parse(&line) {
+        Ok(request) => request,
+        Err(e) => {
+            write_line(&mut stream, "ERR invalid address\n").ok();
+            server.failure(&peer);
+            return Err(e);
+        }
+    };
+    // Options may carry credentials, so only the address is logged
+    info!("Client requested IP: {}/{}", request.addr, request.prefix);
+    if !config.read().unwrap().is_allowed(request.addr) {
+        write_line(&mut stream, "ERR address not allowed\n").ok();
+        server.failure(&peer);
+        return Err(VpnError::Handshake(format!(
+            "Client address {} not allowed",
+            request.addr
+        )));
+    }
+    let auth = AuthRequest {
+        peer: &peer,
+        addr: request.addr,
+        options: &request.options,
+    };
+    if let Err(reason) = server.plugins.authenticate(&auth) {
+        write_line(&mut stream, "ERR authentication failed\n").ok();
+        server.failure(&peer);
+        return Err(VpnError::Handshake(format!(
+            "Client {} ({}) rejected: {}",
+            request.addr, peer, reason
+        )));
+    }
+    let (max_clients, quota) = {
+        let c = config.read().unwrap();
+        (c.max_clients, c.quota(request.addr))
+    };
+    if server.quotas.exceeded(quota, request.addr, None) {
+        write_line(&mut stream, "ERR quota exceeded\n").ok();
+        return Err(VpnError::Handshake(format!(
+            "Client {} turned away: quota exceeded",
+            request.addr
+        )));
+    }
+    if max_clients > 0 && sessions.len() >= max_clients {
+        write_line(&mut stream, "ERR server full\n").ok();
+        return Err(VpnError::Handshake(format!(
+            "Client {} turned away: {} sessions already",
+            request.addr, max_clients
+        )));
+    }
+    let mut reply = Options::new();
+    let (negotiated, tuning, addr6) = {
+        let c = config.read().unwrap();
+        let framing = c.framing().negotiate(&request.options, &mut reply);
+        let mut mtu = peer_mtu(&request.options).min(c.mtu);
+        // Per-client settings from its [client] section; a pushed MTU can
+        // only lower the negotiated one
+        for (key, value) in c.pushed(request.addr).iter() {
+            match key {
+                "mtu" => mtu = mtu.min(value.parse().unwrap_or(mtu)),
+                _ => reply.set(key, value),
+            }
+        }
+        reply.set("mtu", mtu);
+        let framing = framing.capped(mtu);
+        framing.offer(&mut reply);
+        let addr6 = c.client_ip6(request.addr);
+        if let Some((addr6, prefix)) = addr6 {
+            reply.set("ip6", format!("{}/{}", addr6, prefix));
+            if c.ip6_default_route {
+                let routes = match reply.get("route") {
+                    Some(routes) => format!("{},{}", routes, IP6_DEFAULT_ROUTES),
+                    None => IP6_DEFAULT_ROUTES.to_string(),
+                };
+                reply.set("route", routes);
+            }
+        }
+        (
+            Negotiated { framing, mtu },
+            c.tuning,
+            addr6.map(|(addr6, _)| addr6),
+        )
+    };
+    let registered = sessions.register(
+        request.addr,
+        addr6,
+        peer,
+        negotiated,
+        stream.try_clone()?,
+        tuning,
+    );
+    let session = match registered {
+        Some(session) => session,
+        None => {
+            write_line(&mut stream, "ERR address in use\n").ok();
+            return Err(VpnError::Handshake(format!(
+                "Client address {} already in use",
+                request.addr
+            )));
+        }
+    };
+    logging::set_session(Some(session.id));
+
+    // Keep the kernel from handing us packets this client cannot take
+    let server_mtu = config.read().unwrap().mtu;
+    if session.mtu < server_mtu && tun.features().peer_mtu {
+        for addr in [Some(session.addr), session.addr6].into_iter().flatten() {
+            if let Err(e) = tun.set_peer_mtu(addr, session.mtu) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    // Failing from here on must still unregister the session
+    if let Err(e) = write_line(&mut stream, &format!("OK{}\n", reply)) {
+        end_session(&session, &tun, server);
+        return Err(e);
+    }
+    drop(handshaking);
+    info!(
+        "Handshake complete. Session {} for {} started.",
+        session.id, session.addr
+    );
+    status.event(format!(
+        "Session {} for {} from {} started",
+        session.id, session.addr, session.peer
+    ));
+    run_session(session, stream, Vec::new(), tun, server)
+}
+
+// A connection on a shared port that is not from a client: hand it to the
+// web server, starting with the bytes already read, and copy both ways
+fn pass_on(mut stream: Stream, peer: &Peer, backend: &str, read: &[u8]) -> Result<()> {
+    debug!("Passing connection from {} on to {}", peer, backend);
+    let addr = backend
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| VpnError::Config(format!("share: {} did not resolve", backend)))?;
+    let mut web = TcpStream::connect_timeout(&addr, SHARE_CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(None)?;
+    web.write_all(read)?;
+    let (mut from, mut to) = (stream.try_clone()?, web.try_clone()?);
+    let upstream = thread::spawn(move || {
+        io::copy(&mut from, &mut to).ok();
+        to.shutdown(Shutdown::Write).ok();
+    });
+    io::copy(&mut web, &mut stream).ok();
+    stream.shutdown();
+    upstream.join().ok();
+    Ok(())
+}
+
+// Forward Client -> Server -> TUN until the client disconnects, starting
+// with `pending` bytes already read from the connection
+fn run_session<T: TunDevice>(
+    session: Arc<Session>,
+    mut stream: Stream,
+    pending: Vec<u8>,
+    mut tun: T,
+    server: &Server,
+) -> Result<()> {
+    let (tos, mssfix, read_buffer, cpu, exposed) = {
+        let c = server.config.read().unwrap();
+        let cpu = affinity::for_session(&c.cpus, session.id);
+        (
+            c.tos,
+            c.mssfix,
+            c.tuning.read_buffer,
+            cpu,
+            c.exposed(session.addr),
+        )
+    };
+    // Between frames the loop only reads once data is there, so this only
+    // ends a connection that stalls within a frame
+    let writer = match stream
+        .set_read_timeout(Some(FRAME_TIMEOUT))
+        .and_then(|_| stream.try_clone())
+    {
+        Ok(writer) => writer,
+        Err(e) => {
+            end_session(&session, &tun, server);
+            return Err(e.into());
+        }
+    };
+    // The sender inherits the CPU
+    if let Some(cpu) = cpu {
+        if let Err(e) = affinity::pin(cpu) {
+            warn!("Cannot pin session {} to CPU {}: {}", session.id, cpu, e);
+        }
+    }
+    session.spawn_sender(writer, tos, mssfix);
+    server
+        .status
+        .session_established
+        .store(true, Ordering::Relaxed);
+    // Open for as long as this process serves the session
+    let exposed = Exposed::start(session.addr, &exposed);
+    let mut reader = BufReader::with_capacity(read_buffer, Cursor::new(pending).chain(&mut stream));
+    if let Some(pending) = forward_from_client(&mut reader, &mut tun, server, &session) {
+        info!(
+            "Session {} for {} stopped for handoff.",
+            session.id, session.addr
+        );
+        // Free the ports for the new process before it resumes the session
+        drop(exposed);
+        session.hand_off(pending);
+        return Ok(());
+    }
+    drop(exposed);
+    end_session(&session, &tun, server);
+    Ok(())
+}
+
+fn end_session<T: TunDevice>(session: &Session, tun: &T, server: &Server) {
+    let sessions = &server.sessions;
+    sessions.remove(session);
+    session.close();
+    server.quotas.record(session);
+    if session.mtu < server.config.read().unwrap().mtu && tun.features().peer_mtu {
+        for addr in [Some(session.addr), session.addr6].into_iter().flatten() {
+            tun.clear_peer_mtu(addr).ok();
+        }
+    }
+    server
+        .status
+        .session_established
+        .store(!sessions.is_empty(), Ordering::Relaxed);
+    info!("Session {} for {} ended.", session.id, session.addr);
+    server.status.event(format!(
+        "Session {} for {} ended after {}s",
+        session.id,
+        session.addr,
+        session.started.elapsed().as_secs()
+    ));
+}
+
+// Reads from a session's connection, after any bytes handed over with it
+type SessionReader<'a> = BufReader<Chain<Cursor<Vec<u8>>, &'a mut Stream>>;
+
+// Bytes read from the connection but not yet decoded
+fn read_ahead(reader: &SessionReader) -> Vec<u8> {
+    let (handed_over, _) = reader.get_ref().get_ref();
+    let mut pending = reader.buffer().to_vec();
+    pending.extend_from_slice(&handed_over.get_ref()[handed_over.position() as usize..]);
+    pending
+}
+
+fn has_read_ahead(reader: &SessionReader) -> bool {
+    let (handed_over, _) = reader.get_ref().get_ref();
+    !reader.buffer().is_empty() || handed_over.position() < handed_over.get_ref().len() as u64
+}
+
+// Main: Client -> Server -> TUN. Returns the bytes read ahead if the
+// session was stopped for a handoff, None once it has ended.
+fn forward_from_client<T: TunDevice>(
+    reader: &mut SessionReader,
+    tun: &mut T,
+    server: &Server,
+    session: &Arc<Session>,
+) -> Option<Vec<u8>> {
+    info!("Client->TUN forwarding loop started.");
+    let mut buf = vec![0u8; session.mtu];
+    let mut generation = reload::GENERATION.load(Ordering::SeqCst);
+    let pushed_now = |config: &Config| config.pushed(session.addr);
+    let mut pushed = pushed_now(&server.config.read().unwrap());
+    loop {
+        // Between frames: stop here for a handoff, and only block in a read
+        // once there is something to read so a handoff is noticed
+        if handoff::freezing() {
+            return Some(read_ahead(reader));
+        }
+        if !has_read_ahead(reader) && !session.wait_readable(FREEZE_POLL) {
+            continue;
+        }
+
+        // Re-check the ACL for this client after a reload, and push its
+        // options again if its [client] section changed
+        let current = reload::GENERATION.load(Ordering::SeqCst);
+        if current != generation {
+            generation = current;
+            let config = server.config.read().unwrap();
+            if !config.is_allowed(session.addr) {
+                info!(
+                    "Client {} no longer allowed after reload. Disconnecting.",
+                    session.addr
+                );
+                session.close();
+                break;
+            }
+            // Only additions take effect; the client does not undo options
+            let options = pushed_now(&config);
+            if options != pushed && session.framing.mux {
+                info!("Pushing updated options to {}", session.addr);
+                session.send_control(format!("PUSH{}", options).into_bytes());
+            }
+            pushed = options;
+        }
+
+        let n = match session.framing.recv_frame(reader, &mut buf) {
+            Ok((Channel::Data, n)) => n,
+            Ok((Channel::Control, n)) => {
+                match Message::parse(&buf[..n]) {
+                    Some(Message::Request {
+                        verb: "OPEN",
+                        token,
+                        args,
+                    }) => open_forward(server, session, token, args),
+                    Some(Message::Request { verb, token, .. }) => {
+                        session.send_control(mux::answer(verb, token, || session.stats()))
+                    }
+                    _ => debug!("Ignoring control message from {}", session.addr),
+                }
+                continue;
+            }
+            Ok((Channel::Stream, n)) => {
+                session.rx_packets.fetch_add(1, Ordering::Relaxed);
+                session.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                session.streams.deliver(&buf[..n]);
+                continue;
+            }
+            Err(e) if e.io_kind() == Some(io::ErrorKind::WouldBlock) => {
+                error!(
+                    "{} sent part of a frame and nothing for {}s. Disconnecting.",
+                    session.addr,
+                    FRAME_TIMEOUT.as_secs()
+                );
+                break;
+            }
+            Err(e) => {
+                error!("Error receiving from client: {}", e);
+                break;
+            }
+        };
+
+        if n == 0 {
+            info!("Received zero-length packet. Possibly connection closed.");
+            break;
+        }
+        server.status.touch_rx();
+        session.record_rx(&buf[..n]);
+
+        let packet = &mut buf[..n];
+        if !server.filter.allows(packet)
+            || server
+                .plugins
+                .inspect(session, Direction::FromClient, packet)
+                == Verdict::Drop
+        {
+            continue;
+        }
+        if let Err(e) = tun.deliver(packet, &server.status.tun_up) {
+            error!("Error writing to TUN: {}", e);
+            break;
+        }
+    }
+    info!("Client->TUN forwarding loop ended.");
+    None
+}
+
+// Carry on with the sessions the previous process handed over, then let
+// it exit
+fn resume<T: TunDevice>(mut inherited: Inherited, tun: &T, server: &Arc<Server>) -> Result<()> {
+    let tuning = server.config.read().unwrap().tuning;
+    for (state, fd, pending) in mem::take(&mut inherited.sessions) {
+        let peer = Peer::parse(state.get("peer").unwrap_or(""));
+        let restored = Stream::from_fd(fd, &peer)
+            .map_err(VpnError::from)
+            .and_then(|stream| {
+                let session = server
+                    .sessions
+                    .restore(&state, stream.try_clone()?, tuning)?;
+                Ok((session, stream, tun.try_clone()?))
+            });
+        let (session, stream, tun) = match restored {
+            Ok(restored) => restored,
+            Err(e) => {
+                error!("Cannot resume session from {}: {}", peer, e);
+                continue;
+            }
+        };
+        info!("Resuming session {} for {}.", session.id, session.addr);
+        let server = server.clone();
+        thread::spawn(move || {
+            logging::set_session(Some(session.id));
+            if let Err(e) = run_session(session, stream, pending, tun, &server) {
+                error!("Resumed session with {} failed: {}", peer, e);
+            }
+        });
+    }
+    inherited.ready()
+}
+
+// `ctl upgrade` or SIGUSR2: hand the TUN device, the listening sockets and
+// the sessions to a new copy of this program. Returns once it has taken
+// over; the caller then exits.
+fn upgrade<T: TunDevice>(server: &Server, tun: &T) -> Result<()> {
+    if server.config.read().unwrap().sandbox {
+        return Err(VpnError::Config(
+            "upgrade needs sandbox off, which blocks starting a program".into(),
+        ));
+    }
+    let mut successor = Successor::spawn()?;
+    info!("Stopping sessions for the handoff.");
+    handoff::set_freezing(true);
+    let sessions = server.sessions.list();
+    for session in &sessions {
+        session.stop_sender();
+    }
+    let deadline = Instant::now() + FREEZE_TIMEOUT;
+    while Instant::now() < deadline && sessions.iter().any(|s| s.frozen().is_none()) {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let result = (|| -> Result<()> {
+        successor.send_tun(tun.raw_fd())?;
+        successor.send_sockets()?;
+        for session in &sessions {
+            match (session.frozen(), session.raw_fd()) {
+                (Some(pending), Some(fd)) => {
+                    successor.send_session(&session.state(), fd, &pending)?
+                }
+                _ => warn!(
+                    "Session {} for {} did not stop in time; its client will reconnect.",
+                    session.id, session.addr
+                ),
+            }
+        }
+        successor.finish()
+    })();
+    if let Err(e) = result {
+        successor.abandon();
+        error!("Handoff failed; carrying on: {}", e);
+        for session in &sessions {
+            if server
+                .sessions
+                .get(&session.addr)
+                .is_some_and(|s| s.id == session.id)
+            {
+                end_session(session, tun, server);
+            }
+        }
+        handoff::set_freezing(false);
+        return Err(e);
+    }
+    info!("The new process has taken over.");
+    Ok(())
+}
+
+// `OPEN <token> <id> <host:port>` from a client: connect to the target in
+// a thread of its own and carry the connection as stream `id`
+fn open_forward(server: &Server, session: &Arc<Session>, token: u64, args: &str) {
+    let request = args
+        .split_once(' ')
+        .and_then(|(id, target)| Some((id.parse::<u32>().ok()?, target.to_string())));
+    let Some((id, target)) = request else {
+        session.send_control(mux::reply(token, "error usage: OPEN <id> <host:port>"));
+        return;
+    };
+    let allow = server.config.read().unwrap().forward_allow.clone();
+    let session = session.clone();
+    logging::spawn(move || {
+        let added = forward::connect(&target, &allow)
+            .and_then(|stream| session.streams.add(id, stream).map_err(|e| e.to_string()));
+        if let Err(e) = added {
+            info!("Not forwarding for {}: {}", session.addr, e);
+            session.send_control(mux::reply(token, &format!("error {}", e)));
+            return;
+        }
+        info!(
+            "Forwarding connection {} of {} to {}",
+            id, session.addr, target
+        );
+        session.send_control(mux::reply(token, "ok"));
+        let sender = session.clone();
+        session
+            .streams
+            .start(id, move |frame| sender.send_stream(frame));
+    });
+}
+
+// Thread: end sessions of clients that used up their quota. Muxed clients
+// are told why with a CLOSE message and closed after QUOTA_GRACE; others
+// are disconnected right away.
+fn spawn_quota_checker(server: Arc<Server>) {
+    thread::spawn(move || {
+        let mut closing: HashMap<u64, Instant> = HashMap::new();
+        loop {
+            thread::sleep(QUOTA_CHECK);
+            let sessions = server.sessions.list();
+            closing.retain(|id, _| sessions.iter().any(|s| s.id == *id));
+            for session in sessions {
+                if let Some(told) = closing.get(&session.id) {
+                    if told.elapsed() >= QUOTA_GRACE {
+                        session.close();
+                    }
+                    continue;
+                }
+                let quota = server.config.read().unwrap().quota(session.addr);
+                if !server.quotas.exceeded(quota, session.addr, Some(&session)) {
+                    continue;
+                }
+                logging::set_session(Some(session.id));
+                info!(
+                    "{} has used up its quota; closing its session",
+                    session.addr
+                );
+                server
+                    .status
+                    .event(format!("Quota exceeded for {}", session.addr));
+                if session.framing.mux {
+                    session.send_control(quota::CLOSE.to_vec());
+                    closing.insert(session.id, Instant::now());
+                } else {
+                    session.close();
+                }
+            }
+            logging::set_session(None);
+        }
+    });
+}
+
+// Thread: TUN -> Server -> Client, routing each packet by destination address
+fn spawn_tun_reader<T: TunDevice>(mut tun: T, server: Arc<Server>, mtu: usize) {
+    thread::spawn(move || {
+        info!("TUN->Client forwarding thread started.");
+        let first = server.config.read().unwrap().cpus.first().copied();
+        if let Some(cpu) = first {
+            if let Err(e) = affinity::pin(cpu) {
+                warn!("Cannot pin the TUN reader to CPU {}: {}", cpu, e);
+            }
+        }
+        let mut buf = vec![0u8; mtu];
+        loop {
+            let n = match tun.next_packet(&mut buf, &server.status.tun_up) {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Error reading from TUN: {}", e);
+                    server.status.tun_up.store(false, Ordering::Relaxed);
+                    break;
+                }
+            };
+            let packet = &mut buf[..n];
+            let Some(dst) = packet::destination(packet) else {
+                debug!("Dropping non-IP packet of {} bytes from TUN.", n);
+                continue;
+            };
+            match server.sessions.get(&dst) {
+                Some(session) => {
+                    if server.filter.allows(packet)
+                        && server
+                            .plugins
+                            .inspect(&session, Direction::ToClient, packet)
+                            == Verdict::Pass
+                    {
+                        session.send(packet);
+                    }
+                }
+                None => debug!("No session for {}; dropping {} bytes.", dst, n),
+            }
+        }
+        info!("TUN->Client forwarding thread ended.");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::RwLock;
+
+    fn server(max_handshakes: usize) -> Server {
+        let config = Config {
+            max_handshakes,
+            ..Config::default()
+        };
+        Server {
+            config: Arc::new(RwLock::new(config)),
+            status: Arc::new(Status::new("server")),
+            sessions: Arc::new(SessionManager::new()),
+            bans: Arc::new(BanList::new(0, 0, Duration::ZERO)),
+            filter: Arc::new(Filter::new(&[])),
+            quotas: Quotas::new(),
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            plugins: Plugins::default(),
+        }
+    }
+
+    // A connection as the server sees it, and the client's end of it
+    fn connection() -> (Stream, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (Stream::Unix(ours, PathBuf::new()), theirs)
+    }
+
+    #[test]
+    fn connections_past_max_handshakes_are_closed_at_once() {
+        let server = server(4);
+        let peer = Peer::Unix(PathBuf::new());
+        let mut open = Vec::new();
+        for _ in 0..4 {
+            let (stream, client) = connection();
+            open.push((server.admit(&stream, &peer).unwrap(), stream, client));
+        }
+
+        let (stream, mut client) = connection();
+        assert!(server.admit(&stream, &peer).is_none());
+        assert_eq!(server.handshakes.load(Ordering::SeqCst), 4);
+        // The client sees the connection closed, not a server waiting on it
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let started = Instant::now();
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // A finished handshake makes room for the next connection
+        open.pop();
+        let (stream, _client) = connection();
+        assert!(server.admit(&stream, &peer).is_some());
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::fd::RawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::config::{MssFix, Tos, Tuning};
+use crate::error::{Result, VpnError};
+use crate::forward::Streams;
+use crate::json::{self, Object};
+use crate::logging;
+use crate::packet;
+use crate::protocol::{Channel, Framing, Negotiated, Options, Padding};
+use crate::queue::SendQueue;
+use crate::trace::Tracer;
+use crate::transport::{Marking, Peer, Stream};
+
+// One connected client
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    // Tunnel address the client requested in the handshake
+    pub addr: IpAddr,
+    // IPv6 address it was given next to that (see Config::client_ip6)
+    pub addr6: Option<IpAddr>,
+    pub peer: Peer,
+    pub started: Instant,
+    pub framing: Framing,
+    pub mtu: usize,
+    tuning: Tuning,
+    // Kept for shutting the connection down; the sender has its own handle
+    stream: Stream,
+    queue: SendQueue,
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    // Packets dropped because the send queue was full
+    pub tx_dropped: AtomicU64,
+    // Set once the sender thread has exited
+    sender_stopped: AtomicBool,
+    // Bytes read but not yet decoded when the session was stopped for a
+    // handoff (see handoff)
+    handed_off: Mutex<Option<Vec<u8>>>,
+    // Watchers from `ctl trace`
+    tracer: Tracer,
+    // Connections forwarded for the client
+    pub streams: Arc<Streams>,
+}
+
+impl Session {
+    // Queue a packet from the TUN for this client
+    pub fn send(&self, packet: &[u8]) {
+        if packet.len() > self.mtu {
+            debug!(
+                "Dropping {}-byte packet for {}: above session MTU {}.",
+                packet.len(),
+                self.addr,
+                self.mtu
+            );
+            self.tracer.packet("drop", packet);
+            return;
+        }
+        if self.queue.push(packet) {
+            self.tracer.packet("tx", packet);
+        } else {
+            self.tx_dropped.fetch_add(1, Ordering::Relaxed);
+            self.tracer.packet("drop", packet);
+        }
+    }
+
+    // Queue a control message for this client; ignored without mux
+    pub fn send_control(&self, message: Vec<u8>) {
+        if !self.framing.mux {
+            return;
+        }
+        if !self.queue.push_control(message) {
+            debug!("Control queue for {} full; dropping message.", self.addr);
+        }
+    }
+
+    // Queue a frame of a forwarded connection, waiting for room; false once
+    // the session is closing
+    pub fn send_stream(&self, frame: Vec<u8>) -> bool {
+        self.framing.mux && self.queue.push_stream(frame)
+    }
+
+    // Counters as key=value pairs, answering a STATS request
+    pub fn stats(&self) -> String {
+        format!(
+            "uptime={} rx_packets={} rx_bytes={} tx_packets={} tx_bytes={} tx_dropped={}",
+            self.started.elapsed().as_secs(),
+            self.rx_packets.load(Ordering::Relaxed),
+            self.rx_bytes.load(Ordering::Relaxed),
+            self.tx_packets.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.tx_dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    // Start the thread that drains the queue into the connection. Called
+    // once the handshake reply is out, so packets never overtake it.
+    pub fn spawn_sender(self: &Arc<Self>, mut writer: Stream, tos: Tos, mssfix: MssFix) {
+        let session = self.clone();
+        let mss_limit = mssfix.limit(self.mtu);
+        logging::spawn(move || {
+            let mut marking = Marking::new(tos);
+            let mut batch = Vec::new();
+            while let Some(mut frame) = session.queue.pop() {
+                // Gather frames into one write while more are queued
+                let result = loop {
+                    let (channel, mut packet) = frame;
+                    if channel == Channel::Data {
+                        if let Some(mtu) = mss_limit {
+                            packet::clamp_mss(&mut packet, mtu);
+                        }
+                        if marking.changes(&packet) && !batch.is_empty() {
+                            if let Err(e) = writer.write_all(&batch) {
+                                break Err(e.into());
+                            }
+                            batch.clear();
+                        }
+                        marking.mark(&writer, &packet);
+                    }
+                    if let Err(e) = session.framing.send_on(&mut batch, channel, &packet) {
+                        break Err(e);
+                    }
+                    if channel != Channel::Control {
+                        session.tx_packets.fetch_add(1, Ordering::Relaxed);
+                        session
+                            .tx_bytes
+                            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+                    }
+                    match session.queue.pop_for_batch(batch.len(), &session.tuning) {
+                        Some(next) => frame = next,
+                        None => break writer.write_all(&batch).map_err(VpnError::from),
+                    }
+                };
+                batch.clear();
+                if let Err(e) = result {
+                    error!("Error sending to client {}: {}", session.addr, e);
+                    session.close();
+                    break;
+                }
+            }
+            session.sender_stopped.store(true, Ordering::SeqCst);
+        });
+    }
+
+    // Account for a packet received from this client
+    pub fn record_rx(&self, packet: &[u8]) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        self.tracer.packet("rx", packet);
+    }
+
+    // Summaries of this session's packets from now on (see trace)
+    pub fn watch(&self) -> Receiver<String> {
+        self.tracer.watch()
+    }
+
+    // Disconnect the client; its receive loop ends on the next read and the
+    // sender thread exits
+    pub fn close(&self) {
+        self.queue.close();
+        self.stream.shutdown();
+        self.streams.close_all();
+    }
+
+    // Handoff: let the sender finish its current write and exit, leaving
+    // the connection open
+    pub fn stop_sender(&self) {
+        self.queue.close();
+    }
+
+    // Handoff: the receive loop stopped between two frames with `pending`
+    // bytes read ahead
+    pub fn hand_off(&self, pending: Vec<u8>) {
+        *self.handed_off.lock().unwrap() = Some(pending);
+    }
+
+    // Bytes read ahead, once both directions have stopped for a handoff
+    pub fn frozen(&self) -> Option<Vec<u8>> {
+        if !self.sender_stopped.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.handed_off.lock().unwrap().clone()
+    }
+
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.stream.raw_fd()
+    }
+
+    // Wait up to `timeout` for the client to send something
+    pub fn wait_readable(&self, timeout: Duration) -> bool {
+        self.stream.wait_readable(timeout)
+    }
+
+    // What a new process needs to carry on with this session (see
+    // SessionManager::restore)
+    pub fn state(&self) -> Options {
+        let mut state = Options::new();
+        state.set("id", self.id);
+        state.set("addr", self.addr);
+        if let Some(addr6) = self.addr6 {
+            state.set("addr6", addr6);
+        }
+        state.set("peer", &self.peer);
+        state.set("uptime", self.started.elapsed().as_secs());
+        state.set("frame", self.framing.version);
+        state.set("max_frame", self.framing.max_len);
+        state.set("mux", self.framing.mux as u8);
+        state.set("pad", self.framing.pad.name());
+        state.set("mtu", self.mtu);
+        for (key, counter) in self.counters() {
+            state.set(key, counter.load(Ordering::Relaxed));
+        }
+        state
+    }
+
+    fn counters(&self) -> [(&'static str, &AtomicU64); 5] {
+        [
+            ("rx_packets", &self.rx_packets),
+            ("rx_bytes", &self.rx_bytes),
+            ("tx_packets", &self.tx_packets),
+            ("tx_bytes", &self.tx_bytes),
+            ("tx_dropped", &self.tx_dropped),
+        ]
+    }
+}
+
+// All sessions of a server, keyed by tunnel address, and those with an
+// IPv6 address by that too.
+// Sessions accepted on any listener end up here.
+#[derive(Debug, Default)]
+pub struct SessionManager {
+    sessions: RwLock<HashMap<IpAddr, Arc<Session>>>,
+    by_addr6: RwLock<HashMap<IpAddr, Arc<Session>>>,
+    next_id: AtomicU64,
+}
+
+impl SessionManager {
+    pub fn new() -> SessionManager {
+        SessionManager::default()
+    }
+
+    // Register a new session; None if an address is already taken
+    pub fn register(
+        &self,
+        addr: IpAddr,
+        addr6: Option<IpAddr>,
+        peer: Peer,
+        negotiated: Negotiated,
+        stream: Stream,
+        tuning: Tuning,
+    ) -> Option<Arc<Session>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Session {
+            id,
+            addr,
+            addr6,
+            peer,
+            started: Instant::now(),
+            framing: negotiated.framing,
+            mtu: negotiated.mtu,
+            tuning,
+            stream,
+            queue: SendQueue::new(tuning.queue_depth),
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tx_dropped: AtomicU64::new(0),
+            sender_stopped: AtomicBool::new(false),
+            handed_off: Mutex::new(None),
+            tracer: Tracer::new(),
+            streams: Arc::new(Streams::new(negotiated.mtu)),
+        };
+        self.insert(session)
+    }
+
+    // Register a session handed over by a previous process, from
+    // Session::state and its connection
+    pub fn restore(&self, state: &Options, stream: Stream, tuning: Tuning) -> Result<Arc<Session>> {
+        let get = |key: &str| {
+            state
+                .get(key)
+                .ok_or_else(|| VpnError::Config(format!("Session state without {}", key)))
+        };
+        fn number<T: FromStr>(key: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .map_err(|_| VpnError::Config(format!("Invalid session {}: {}", key, value)))
+        }
+        let mut framing = match number::<u8>("frame", get("frame")?)? {
+            1 => Framing::V1,
+            _ => Framing::v2(number("max_frame", get("max_frame")?)?),
+        };
+        if get("mux")? == "1" {
+            framing = framing.muxed();
+        }
+        // Missing in the state of processes from before padding
+        if let Some(pad) = state.get("pad") {
+            let pad = Padding::parse(pad)
+                .ok_or_else(|| VpnError::Config(format!("Invalid session pad: {}", pad)))?;
+            framing = framing.padded(pad);
+        }
+        let uptime = Duration::from_secs(number("uptime", get("uptime")?)?);
+        let mtu = number("mtu", get("mtu")?)?;
+        let session = Session {
+            id: number("id", get("id")?)?,
+            addr: number("addr", get("addr")?)?,
+            addr6: state
+                .get("addr6")
+                .map(|addr6| number("addr6", addr6))
+                .transpose()?,
+            peer: Peer::parse(get("peer")?),
+            started: Instant::now()
+                .checked_sub(uptime)
+                .unwrap_or_else(Instant::now),
+            framing,
+            mtu,
+            tuning,
+            stream,
+            queue: SendQueue::new(tuning.queue_depth),
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tx_dropped: AtomicU64::new(0),
+            sender_stopped: AtomicBool::new(false),
+            handed_off: Mutex::new(None),
+            tracer: Tracer::new(),
+            streams: Arc::new(Streams::new(mtu)),
+        };
+        for (key, counter) in session.counters() {
+            counter.store(number(key, get(key)?)?, Ordering::Relaxed);
+        }
+        self.next_id.fetch_max(session.id, Ordering::Relaxed);
+        let addr = session.addr;
+        self.insert(session)
+            .ok_or_else(|| VpnError::Config(format!("Session for {} restored twice", addr)))
+    }
+
+    // None if an address is already taken
+    fn insert(&self, session: Session) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.write().unwrap();
+        let mut by_addr6 = self.by_addr6.write().unwrap();
+        if sessions.contains_key(&session.addr)
+            || session
+                .addr6
+                .is_some_and(|addr6| by_addr6.contains_key(&addr6))
+        {
+            return None;
+        }
+        let session = Arc::new(session);
+        sessions.insert(session.addr, session.clone());
+        if let Some(addr6) = session.addr6 {
+            by_addr6.insert(addr6, session.clone());
+        }
+        Some(session)
+    }
+
+    pub fn remove(&self, session: &Session) {
+        let mut sessions = self.sessions.write().unwrap();
+        let mut by_addr6 = self.by_addr6.write().unwrap();
+        if sessions.get(&session.addr).map(|s| s.id) == Some(session.id) {
+            sessions.remove(&session.addr);
+        }
+        if let Some(addr6) = session.addr6 {
+            if by_addr6.get(&addr6).map(|s| s.id) == Some(session.id) {
+                by_addr6.remove(&addr6);
+            }
+        }
+    }
+
+    // Session owning a tunnel address, IPv6 ones included
+    pub fn get(&self, addr: &IpAddr) -> Option<Arc<Session>> {
+        if let Some(session) = self.sessions.read().unwrap().get(addr) {
+            return Some(session.clone());
+        }
+        self.by_addr6.read().unwrap().get(addr).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut list: Vec<_> = self.sessions.read().unwrap().values().cloned().collect();
+        list.sort_by_key(|s| s.id);
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Human-readable table for `ctl clients`
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for s in self.list() {
+            let addr6 = s.addr6.map(|a| format!(" addr6={}", a)).unwrap_or_default();
+            writeln!(
+                out,
+                "{} addr={}{} peer={} uptime={}s rx={}/{}B tx={}/{}B drop={}",
+                s.id,
+                s.addr,
+                addr6,
+                s.peer,
+                s.started.elapsed().as_secs(),
+                s.rx_packets.load(Ordering::Relaxed),
+                s.rx_bytes.load(Ordering::Relaxed),
+                s.tx_packets.load(Ordering::Relaxed),
+                s.tx_bytes.load(Ordering::Relaxed),
+                s.tx_dropped.load(Ordering::Relaxed),
+            )
+            .unwrap();
+        }
+        if out.is_empty() {
+            out.push_str("no clients\n");
+        }
+        out
+    }
+
+    // The same as a JSON array, one object per session
+    pub fn json(&self) -> String {
+        json::array(self.list().iter().map(|s| {
+            let mut object = Object::new()
+                .num("id", s.id)
+                .str("addr", &s.addr.to_string())
+                .opt_str("addr6", s.addr6.map(|a| a.to_string()).as_deref())
+                .str("peer", &s.peer.to_string())
+                .num("uptime", s.started.elapsed().as_secs());
+            for (key, counter) in s.counters() {
+                object = object.num(key, counter.load(Ordering::Relaxed));
+            }
+            object.build()
+        }))
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+use nix::libc;
+
+// Convert a SocketAddr into a sockaddr_storage for raw libc calls
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            #[cfg(not(target_os = "linux"))]
+            {
+                sin.sin_len = mem::size_of::<libc::sockaddr_in>() as u8;
+            }
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            #[cfg(not(target_os = "linux"))]
+            {
+                sin6.sin6_len = mem::size_of::<libc::sockaddr_in6>() as u8;
+            }
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+pub fn setsockopt<T>(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+// Kernel keepalive for the outer connection: probes after `idle` without
+// traffic, every `interval`, and gives up after `count` unanswered. On
+// Linux the same bound applies to data left unacknowledged
+// (TCP_USER_TIMEOUT), which keepalives do not cover, so a connection to a
+// rebooted server or through lost NAT state errors out instead of hanging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Keepalive {
+    // How long a dead peer can go unnoticed
+    pub fn bound(&self) -> Duration {
+        self.idle + self.interval * self.count
+    }
+}
+
+pub fn set_keepalive(fd: RawFd, keepalive: &Keepalive) -> io::Result<()> {
+    let secs = |d: Duration| d.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+    setsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_KEEPALIVE,
+        &(1 as libc::c_int),
+    )?;
+    #[cfg(target_vendor = "apple")]
+    let idle = libc::TCP_KEEPALIVE;
+    #[cfg(not(target_vendor = "apple"))]
+    let idle = libc::TCP_KEEPIDLE;
+    setsockopt(fd, libc::IPPROTO_TCP, idle, &secs(keepalive.idle))?;
+    setsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        &secs(keepalive.interval),
+    )?;
+    let count = keepalive.count.clamp(1, i32::MAX as u32) as libc::c_int;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, &count)?;
+    #[cfg(target_os = "linux")]
+    {
+        let ms = keepalive.bound().as_millis().min(u32::MAX as u128) as libc::c_uint;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, &ms)?;
+    }
+    Ok(())
+}
+
+// Connect a TCP socket, optionally pinned to an interface (SO_BINDTODEVICE)
+// and/or a local source address, so the outer connection leaves through a
+// chosen uplink.
+pub fn connect_tcp(
+    addr: &SocketAddr,
+    bind_dev: Option<&str>,
+    bind_addr: Option<IpAddr>,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    if bind_dev.is_none() && bind_addr.is_none() {
+        return TcpStream::connect_timeout(addr, timeout);
+    }
+
+    let family = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = check(unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    #[cfg(not(target_os = "linux"))]
+    if let Some(dev) = bind_dev {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("bind_dev ({}) is only supported on Linux", dev),
+        ));
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(dev) = bind_dev {
+        let res = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                dev.as_ptr() as *const libc::c_void,
+                dev.len() as libc::socklen_t,
+            )
+        };
+        check(res)?;
+    }
+    if let Some(ip) = bind_addr {
+        if ip.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bind address {} does not match family of {}", ip, addr),
+            ));
+        }
+        let (local, len) = to_sockaddr(&SocketAddr::new(ip, 0));
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &local as *const _ as *const libc::sockaddr,
+                len,
+            )
+        })?;
+    }
+
+    // Linux applies SO_SNDTIMEO to a blocking connect()
+    let tv = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDTIMEO, &tv)?;
+    let (remote, len) = to_sockaddr(addr);
+    check(unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            &remote as *const _ as *const libc::sockaddr,
+            len,
+        )
+    })?;
+
+    let stream = TcpStream::from(fd);
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
@@ -0,0 +1,255 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use crate::json::Object;
+use crate::status::Status;
+
+// How often the status file is rewritten while nothing changes, so its
+// uptime and last_rx stay current
+const REFRESH: Duration = Duration::from_secs(5);
+// How long state_exec may take before it is killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(20);
+
+// Connection state of the client, for tray applets and scripts.
+//
+// `status_file = /path` keeps a JSON object describing the current state in
+// that file, e.g.
+//
+//   {"state":"connected","server":"198.51.100.7:443","address":"10.9.0.2/24",
+//    "since":1760515200,"uptime":42,"last_rx":0,"pid":1234,"updated":1760515242}
+//
+// `since` and `updated` are Unix times; `uptime` and `last_rx` are seconds
+// (last_rx is null before anything arrived in this session). The file is
+// replaced atomically on every change and every REFRESH while connected. A
+// client that exits on its own leaves "stopped"; one that is killed leaves
+// its last state, recognizable by a stale `updated` and a `pid` that is gone.
+//
+// `state_exec = /path/to/program` runs the program on every transition with
+// VPN_STATE, VPN_PREVIOUS_STATE, VPN_SERVER and VPN_ADDR in its
+// environment. Runs are serialized in transition order, and a run taking
+// longer than HOOK_TIMEOUT is killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Connecting,
+    Connected,
+    // Waiting to retry after a failed attempt or a lost session
+    Reconnecting,
+    Stopped,
+}
+
+impl State {
+    pub fn label(self) -> &'static str {
+        match self {
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::Reconnecting => "reconnecting",
+            State::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Current {
+    state: State,
+    // Server of the current or last session
+    server: Option<String>,
+    since: SystemTime,
+    connected_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    file: Option<PathBuf>,
+    address: String,
+    status: Arc<Status>,
+    current: Mutex<Current>,
+}
+
+// A transition handed to the state_exec thread
+type Transition = (State, State, Option<String>);
+
+#[derive(Debug)]
+pub struct StateReporter {
+    shared: Arc<Shared>,
+    hook: Option<(Sender<Transition>, JoinHandle<()>)>,
+}
+
+impl StateReporter {
+    // `address` is the tunnel address the client asks for
+    pub fn new(
+        file: Option<PathBuf>,
+        program: Option<String>,
+        address: &str,
+        status: Arc<Status>,
+    ) -> StateReporter {
+        let shared = Arc::new(Shared {
+            file,
+            address: address.to_string(),
+            status,
+            current: Mutex::new(Current {
+                state: State::Connecting,
+                server: None,
+                since: SystemTime::now(),
+                connected_at: None,
+            }),
+        });
+        if shared.file.is_some() {
+            shared.write();
+            let weak = Arc::downgrade(&shared);
+            thread::spawn(move || refresh(weak));
+        }
+        let hook = program.map(|program| {
+            let (tx, rx) = mpsc::channel();
+            let address = shared.address.clone();
+            let handle = thread::spawn(move || run_hooks(&program, &address, rx));
+            (tx, handle)
+        });
+        StateReporter { shared, hook }
+    }
+
+    // Record a transition; `server` is only given when connected
+    pub fn set(&self, state: State, server: Option<String>) {
+        let previous = {
+            let mut current = self.shared.current.lock().unwrap();
+            if current.state == state && (server.is_none() || current.server == server) {
+                return;
+            }
+            let previous = current.state;
+            current.state = state;
+            current.since = SystemTime::now();
+            current.connected_at = (state == State::Connected).then(Instant::now);
+            if server.is_some() {
+                current.server = server;
+            }
+            previous
+        };
+        debug!("Client state {} -> {}", previous.label(), state.label());
+        self.shared.write();
+        if let Some((tx, _)) = &self.hook {
+            let server = self.shared.current.lock().unwrap().server.clone();
+            tx.send((previous, state, server)).ok();
+        }
+    }
+}
+
+impl Drop for StateReporter {
+    // Report the client as stopped and let state_exec see it before exit
+    fn drop(&mut self) {
+        self.set(State::Stopped, None);
+        if let Some((tx, handle)) = self.hook.take() {
+            drop(tx);
+            handle.join().ok();
+        }
+    }
+}
+
+impl Shared {
+    fn write(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        if let Err(e) = replace(path, &self.json()) {
+            warn!("Cannot write status file {}: {}", path.display(), e);
+        }
+    }
+
+    fn json(&self) -> String {
+        let current = self.current.lock().unwrap();
+        let connected = current.connected_at.map(|at| at.elapsed());
+        let uptime = connected.map_or(0, |d| d.as_secs());
+        // Only frames of the current session count
+        let last_rx = match (self.status.last_rx_age(), connected) {
+            (Some(age), Some(connected)) if age <= connected => Some(age.as_secs()),
+            _ => None,
+        };
+        let object = Object::new()
+            .str("state", current.state.label())
+            .opt_str("server", current.server.as_deref())
+            .str("address", &self.address)
+            .num("since", unix_time(current.since))
+            .num("uptime", uptime)
+            .opt_num("last_rx", last_rx)
+            .num("pid", std::process::id())
+            .num("updated", unix_time(SystemTime::now()));
+        object.build() + "\n"
+    }
+}
+
+// Thread: keep the status file current until the reporter goes away
+fn refresh(shared: Weak<Shared>) {
+    loop {
+        thread::sleep(REFRESH);
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        if shared.current.lock().unwrap().state == State::Connected {
+            shared.write();
+        }
+    }
+}
+
+// Thread: run state_exec for each transition, one at a time
+fn run_hooks(program: &str, address: &str, transitions: Receiver<Transition>) {
+    for (previous, state, server) in transitions {
+        info!("Running {} for state {}", program, state.label());
+        let child = Command::new(program)
+            .env("VPN_STATE", state.label())
+            .env("VPN_PREVIOUS_STATE", previous.label())
+            .env("VPN_SERVER", server.unwrap_or_default())
+            .env("VPN_ADDR", address)
+            .stdin(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Cannot run {}: {}", program, e);
+                continue;
+            }
+        };
+        let started = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => break,
+                Ok(Some(status)) => {
+                    warn!("{} failed ({})", program, status);
+                    break;
+                }
+                Ok(None) if started.elapsed() < HOOK_TIMEOUT => thread::sleep(POLL),
+                Ok(None) => {
+                    warn!(
+                        "{} did not finish within {}s",
+                        program,
+                        HOOK_TIMEOUT.as_secs()
+                    );
+                    child.kill().ok();
+                    child.wait().ok();
+                    break;
+                }
+                Err(e) => {
+                    warn!("Waiting for {} failed: {}", program, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Write next to the file and rename over it so readers never see half of it
+fn replace(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+fn unix_time(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
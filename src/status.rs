@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::json::Object;
+
+// Connection events kept for the web dashboard
+const MAX_EVENTS: usize = 50;
+
+// Runtime state of a server or client, shared with the control socket and
+// the health endpoint.
+#[derive(Debug)]
+pub struct Status {
+    pub role: &'static str,
+    pub tun_up: AtomicBool,
+    pub listener_bound: AtomicBool,
+    pub session_established: AtomicBool,
+    started: Instant,
+    // Milliseconds since `started` of the last frame from the peer (0 = never)
+    last_rx_ms: AtomicU64,
+    // Recent connection events, oldest first
+    events: Mutex<VecDeque<(Instant, String)>>,
+}
+
+// Health check result; `code` doubles as the `ctl health` exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy = 0,
+    TunDown = 2,
+    ListenerDown = 3,
+    NoSession = 4,
+}
+
+impl Health {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::TunDown => "tun-down",
+            Health::ListenerDown => "listener-down",
+            Health::NoSession => "no-session",
+        }
+    }
+}
+
+impl Status {
+    pub fn new(role: &'static str) -> Status {
+        Status {
+            role,
+            tun_up: AtomicBool::new(false),
+            listener_bound: AtomicBool::new(false),
+            session_established: AtomicBool::new(false),
+            started: Instant::now(),
+            last_rx_ms: AtomicU64::new(0),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        self.role == "server"
+    }
+
+    // The relay has no TUN and no session of its own; it only listens
+    pub fn is_relay(&self) -> bool {
+        self.role == "relay"
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Record that a frame arrived from the peer
+    pub fn touch_rx(&self) {
+        let ms = self.started.elapsed().as_millis() as u64;
+        self.last_rx_ms.store(ms.max(1), Ordering::Relaxed);
+    }
+
+    // Time since the last frame from the peer
+    pub fn last_rx_age(&self) -> Option<Duration> {
+        match self.last_rx_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(
+                self.started
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(ms)),
+            ),
+        }
+    }
+
+    // Remember a connection event (session started, ended, rejected)
+    pub fn event(&self, message: String) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back((Instant::now(), message));
+    }
+
+    // Recent events with their age, newest first
+    pub fn recent_events(&self) -> Vec<(Duration, String)> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .rev()
+            .map(|(at, message)| (at.elapsed(), message.clone()))
+            .collect()
+    }
+
+    pub fn health(&self) -> Health {
+        if self.is_relay() {
+            if self.listener_bound.load(Ordering::Relaxed) {
+                Health::Healthy
+            } else {
+                Health::ListenerDown
+            }
+        } else if !self.tun_up.load(Ordering::Relaxed) {
+            Health::TunDown
+        } else if self.is_server() && !self.listener_bound.load(Ordering::Relaxed) {
+            Health::ListenerDown
+        } else if !self.is_server() && !self.session_established.load(Ordering::Relaxed) {
+            Health::NoSession
+        } else {
+            Health::Healthy
+        }
+    }
+
+    // Human-readable health report, one `key: value` per line
+    pub fn health_report(&self) -> String {
+        let health = self.health();
+        let mut out = format!("health: {} {}\n", health.code(), health.label());
+        out += &format!("role: {}\n", self.role);
+        if self.is_relay() {
+            out += &format!(
+                "listener: {}\n",
+                up_down(self.listener_bound.load(Ordering::Relaxed))
+            );
+            out += &format!("uptime: {}s\n", self.uptime().as_secs());
+            return out;
+        }
+        out += &format!("tun: {}\n", up_down(self.tun_up.load(Ordering::Relaxed)));
+        if self.is_server() {
+            out += &format!(
+                "listener: {}\n",
+                up_down(self.listener_bound.load(Ordering::Relaxed))
+            );
+        }
+        out += &format!(
+            "session: {}\n",
+            up_down(self.session_established.load(Ordering::Relaxed))
+        );
+        match self.last_rx_age() {
+            Some(age) => out += &format!("last_rx: {}s ago\n", age.as_secs()),
+            None => out += "last_rx: never\n",
+        }
+        out += &format!("uptime: {}s\n", self.uptime().as_secs());
+        out
+    }
+
+    // The same as JSON; what does not apply to the role is left out
+    pub fn health_json(&self) -> String {
+        let health = self.health();
+        let mut object = Object::new()
+            .str("health", health.label())
+            .num("code", health.code())
+            .str("role", self.role);
+        if !self.is_relay() {
+            object = object.bool("tun", self.tun_up.load(Ordering::Relaxed));
+        }
+        if self.is_server() || self.is_relay() {
+            object = object.bool("listener", self.listener_bound.load(Ordering::Relaxed));
+        }
+        if !self.is_relay() {
+            object = object
+                .bool("session", self.session_established.load(Ordering::Relaxed))
+                .opt_num("last_rx", self.last_rx_age().map(|age| age.as_secs()));
+        }
+        object.num("uptime", self.uptime().as_secs()).build()
+    }
+}
+
+fn up_down(up: bool) -> &'static str {
+    if up {
+        "up"
+    } else {
+        "down"
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::packet;
+
+// Lines buffered per watcher; a watcher that falls further behind misses
+// lines rather than slowing down the session
+const BACKLOG: usize = 1024;
+
+// Live packet summaries of one session for `ctl trace`.
+//
+// Watching costs an atomic load per packet while nobody watches. Each
+// packet becomes one line such as
+//
+//   08:51:23.415 rx 10.9.0.2:40312 > 10.9.0.1:9999 udp 128
+//
+// where rx is from the client, tx is to the client and drop is a packet
+// for the client that did not fit in its queue or MTU.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    active: AtomicBool,
+    watchers: Mutex<Vec<SyncSender<String>>>,
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer::default()
+    }
+
+    // Start watching; ends when the receiver is dropped
+    pub fn watch(&self) -> Receiver<String> {
+        let (tx, rx) = mpsc::sync_channel(BACKLOG);
+        self.watchers.lock().unwrap().push(tx);
+        self.active.store(true, Ordering::Relaxed);
+        rx
+    }
+
+    pub fn packet(&self, direction: &str, packet: &[u8]) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let line = summary(direction, packet);
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|tx| {
+            !matches!(
+                tx.try_send(line.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+        if watchers.is_empty() {
+            self.active.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+// One line describing a packet: time (UTC), direction, addresses and ports,
+// protocol and length
+pub fn summary(direction: &str, packet: &[u8]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() % 86400;
+    let mut line = format!(
+        "{:02}:{:02}:{:02}.{:03} {}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis(),
+        direction
+    );
+    let (Some(src), Some(dst)) = (packet::source(packet), packet::destination(packet)) else {
+        write!(line, " non-IP {}", packet.len()).ok();
+        return line;
+    };
+    match packet::ports(packet) {
+        Some((sport, dport)) if src.is_ipv6() => {
+            write!(line, " [{}]:{} > [{}]:{}", src, sport, dst, dport).ok()
+        }
+        Some((sport, dport)) => write!(line, " {}:{} > {}:{}", src, sport, dst, dport).ok(),
+        None => write!(line, " {} > {}", src, dst).ok(),
+    };
+    match packet::transport(packet) {
+        Some((packet::PROTO_TCP, tcp)) => {
+            line += " tcp";
+            if let Some(&flags) = tcp.get(13) {
+                line += " [";
+                for (bit, name) in [
+                    (0x02, 'S'),
+                    (0x01, 'F'),
+                    (0x04, 'R'),
+                    (0x08, 'P'),
+                    (0x10, '.'),
+                ] {
+                    if flags & bit != 0 {
+                        line.push(name);
+                    }
+                }
+                line += "]";
+            }
+        }
+        Some((packet::PROTO_UDP, _)) => line += " udp",
+        Some((packet::PROTO_ICMP, _)) => line += " icmp",
+        Some((packet::PROTO_ICMPV6, _)) => line += " icmpv6",
+        Some((proto, _)) => write!(line, " proto {}", proto).unwrap(),
+        None => {}
+    }
+    write!(line, " {}", packet.len()).ok();
+    line
+}
@@ -0,0 +1,443 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use nix::libc;
+
+use crate::config::Tos;
+use crate::handoff;
+use crate::packet;
+use crate::socket;
+
+// Prefix marking a Unix socket path in `listen`, `servers` and `addr`
+pub const UNIX_PREFIX: &str = "unix:";
+
+// Byte stream a session runs over. The handshake and framing only need
+// Read + Write, so everything above this module is transport agnostic.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream, PathBuf),
+    Pipe(Pipe),
+}
+
+// Far end of a session, for logs and `ctl clients`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    // Path of the Unix socket the connection came in on (clients are unnamed)
+    Unix(PathBuf),
+    // stdin/stdout, or a command's pipes
+    Pipe(String),
+}
+
+impl Peer {
+    // Inverse of Display, for session state handed to a new process
+    pub fn parse(s: &str) -> Peer {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Peer::Unix(PathBuf::from(path));
+        }
+        match s.parse() {
+            Ok(addr) => Peer::Tcp(addr),
+            Err(_) => Peer::Pipe(s.to_string()),
+        }
+    }
+
+    // Network address of the peer, if it has one
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix(_) | Peer::Pipe(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            Peer::Pipe(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+// A pair of pipes: our stdin/stdout, or those of a child process such as
+// `ssh host vpn server --stdio on ...`
+#[derive(Debug)]
+pub struct Pipe {
+    input: File,
+    output: File,
+    // Killed on shutdown, which also unblocks readers and writers
+    child: Option<Arc<Mutex<Child>>>,
+}
+
+impl Stream {
+    // This process's stdin/stdout. Logging goes to stderr, so stdout is free.
+    pub fn stdio() -> io::Result<Stream> {
+        let input = io::stdin().as_fd().try_clone_to_owned()?;
+        let output = io::stdout().as_fd().try_clone_to_owned()?;
+        Ok(Stream::Pipe(Pipe {
+            input: input.into(),
+            output: output.into(),
+            child: None,
+        }))
+    }
+
+    // Run `sh -c command` and talk to it over its stdin/stdout
+    pub fn command(command: &str) -> io::Result<Stream> {
+        info!("Running transport command: {}", command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let output = OwnedFd::from(child.stdin.take().unwrap());
+        let input = OwnedFd::from(child.stdout.take().unwrap());
+        Ok(Stream::Pipe(Pipe {
+            input: input.into(),
+            output: output.into(),
+            child: Some(Arc::new(Mutex::new(child))),
+        }))
+    }
+
+    // Connect to "host:port" or "unix:/path"
+    pub fn connect(addr: &str) -> io::Result<Stream> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Stream::unix(Path::new(path)),
+            None => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+        }
+    }
+
+    pub fn unix(path: &Path) -> io::Result<Stream> {
+        Ok(Stream::Unix(UnixStream::connect(path)?, path.to_path_buf()))
+    }
+
+    // A connection handed over by a previous process (see handoff)
+    pub fn from_fd(fd: OwnedFd, peer: &Peer) -> io::Result<Stream> {
+        match peer {
+            Peer::Tcp(_) => Ok(Stream::Tcp(fd.into())),
+            Peer::Unix(path) => Ok(Stream::Unix(fd.into(), path.clone())),
+            Peer::Pipe(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "pipes cannot be handed over",
+            )),
+        }
+    }
+
+    // Socket descriptor; None for pipes
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match self {
+            Stream::Tcp(s) => Some(s.as_raw_fd()),
+            Stream::Unix(s, _) => Some(s.as_raw_fd()),
+            Stream::Pipe(_) => None,
+        }
+    }
+
+    // Wait up to `timeout` for something to read; pipes always report ready
+    pub fn wait_readable(&self, timeout: Duration) -> bool {
+        let Some(fd) = self.raw_fd() else {
+            return true;
+        };
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+    }
+
+    pub fn peer(&self) -> io::Result<Peer> {
+        match self {
+            Stream::Tcp(s) => Ok(Peer::Tcp(s.peer_addr()?)),
+            Stream::Unix(_, path) => Ok(Peer::Unix(path.clone())),
+            Stream::Pipe(p) if p.child.is_some() => Ok(Peer::Pipe("command".to_string())),
+            Stream::Pipe(_) => Ok(Peer::Pipe("stdio".to_string())),
+        }
+    }
+
+    // Second handle, so one thread can read while another writes
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => Ok(Stream::Tcp(s.try_clone()?)),
+            Stream::Unix(s, path) => Ok(Stream::Unix(s.try_clone()?, path.clone())),
+            Stream::Pipe(p) => Ok(Stream::Pipe(Pipe {
+                input: p.input.try_clone()?,
+                output: p.output.try_clone()?,
+                child: p.child.clone(),
+            })),
+        }
+    }
+
+    // Set the TOS byte (traffic class for IPv6) of outgoing packets.
+    // Only TCP connections leave the host; other transports ignore it.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        let Stream::Tcp(s) = self else {
+            return Ok(());
+        };
+        let (level, name) = if s.local_addr()?.is_ipv4() {
+            (libc::IPPROTO_IP, libc::IP_TOS)
+        } else {
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+        };
+        socket::setsockopt(s.as_raw_fd(), level, name, &(tos as libc::c_int))
+    }
+
+    // Kernel keepalive (see socket::Keepalive); only TCP connections can be
+    // half-open without us noticing
+    pub fn set_keepalive(&self, keepalive: &socket::Keepalive) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => socket::set_keepalive(s.as_raw_fd(), keepalive),
+            Stream::Unix(..) | Stream::Pipe(_) => Ok(()),
+        }
+    }
+
+    // Reads that fail once `deadline` has passed, however slowly the data
+    // trickles in. Leaves a read timeout set on the stream.
+    pub fn until(&mut self, deadline: Instant) -> Deadline<'_> {
+        Deadline {
+            stream: self,
+            deadline,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Unix(s, _) => s.set_read_timeout(timeout),
+            Stream::Pipe(_) => Ok(()),
+        }
+    }
+
+    // Whether the other end has closed a connection we are not reading
+    // from yet. Looks at pending data without consuming it.
+    pub fn is_closed(&self) -> bool {
+        let Some(fd) = self.raw_fd() else {
+            return false;
+        };
+        let mut byte = 0u8;
+        let n = unsafe {
+            libc::recv(
+                fd,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        n == 0 || (n < 0 && io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock)
+    }
+
+    // End the connection so blocked reads and writes on every handle return.
+    // Our own stdin/stdout cannot be forced closed; the session then ends
+    // when the other side goes away.
+    pub fn shutdown(&self) {
+        match self {
+            Stream::Tcp(s) => {
+                s.shutdown(Shutdown::Both).ok();
+            }
+            Stream::Unix(s, _) => {
+                s.shutdown(Shutdown::Both).ok();
+            }
+            Stream::Pipe(p) => {
+                if let Some(child) = &p.child {
+                    let mut child = child.lock().unwrap();
+                    child.kill().ok();
+                    child.wait().ok();
+                }
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s, _) => s.read(buf),
+            Stream::Pipe(p) => p.input.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s, _) => s.write(buf),
+            Stream::Pipe(p) => p.output.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s, _) => s.flush(),
+            Stream::Pipe(p) => p.output.flush(),
+        }
+    }
+}
+
+// See Stream::until
+#[derive(Debug)]
+pub struct Deadline<'a> {
+    stream: &'a mut Stream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        match self.stream.read(buf) {
+            // What a socket read timeout looks like
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::ErrorKind::TimedOut.into()),
+            result => result,
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Tcp(stream)
+    }
+}
+
+// Applies the configured outer marking before each packet is sent,
+// touching the socket only when the value changes
+#[derive(Debug)]
+pub struct Marking {
+    tos: Tos,
+    current: Option<u8>,
+}
+
+impl Marking {
+    pub fn new(tos: Tos) -> Marking {
+        Marking { tos, current: None }
+    }
+
+    pub fn mark(&mut self, stream: &Stream, packet: &[u8]) {
+        let Some(wanted) = self.wanted(packet) else {
+            return;
+        };
+        if self.current == Some(wanted) {
+            return;
+        }
+        if let Err(e) = stream.set_tos(wanted) {
+            debug!("Cannot set TOS {:#04x}: {}", wanted, e);
+        }
+        self.current = Some(wanted);
+    }
+
+    // Whether sending `packet` would change the marking; anything gathered
+    // for one write must go out before that
+    pub fn changes(&self, packet: &[u8]) -> bool {
+        self.wanted(packet)
+            .is_some_and(|wanted| self.current != Some(wanted))
+    }
+
+    fn wanted(&self, packet: &[u8]) -> Option<u8> {
+        match self.tos {
+            Tos::Off => None,
+            Tos::Fixed(tos) => Some(tos),
+            Tos::Inherit => Some(packet::dscp(packet).unwrap_or(0)),
+        }
+    }
+}
+
+// A bound server socket: "host:port" for TCP or "unix:/path" for a Unix socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // Bind `addr`, or take it over from the process we are replacing
+    pub fn bind(addr: &str) -> io::Result<Listener> {
+        let name = format!("listen {}", addr);
+        let Some(path) = addr.strip_prefix(UNIX_PREFIX) else {
+            let listener = handoff::claim(&name, || TcpListener::bind(addr))?;
+            return Ok(Listener::Tcp(listener));
+        };
+        let path = Path::new(path);
+        let listener = handoff::claim(&name, || {
+            // Remove a stale socket left by a previous run, but never steal a live one
+            if path.exists() {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another instance", path.display()),
+                    ));
+                }
+                fs::remove_file(path).ok();
+            }
+            UnixListener::bind(path)
+        })?;
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => Ok(Stream::Tcp(l.accept()?.0)),
+            Listener::Unix(l, path) => Ok(Stream::Unix(l.accept()?.0, path.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::error::VpnError;
+    use crate::protocol::read_line;
+
+    fn pair() -> (Stream, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        (Stream::Unix(ours, PathBuf::new()), theirs)
+    }
+
+    #[test]
+    fn deadline_cuts_off_a_trickling_peer() {
+        let (mut stream, mut peer) = pair();
+        // One byte every 20ms never finishes a line, but every single read
+        // comes well within any per-read timeout
+        let trickle = thread::spawn(move || {
+            while peer.write_all(b"a").is_ok() {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let started = Instant::now();
+        let err = read_line(&mut stream.until(started + Duration::from_millis(200))).unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(
+            matches!(&err, VpnError::Transport(e) if e.kind() == io::ErrorKind::TimedOut),
+            "{}",
+            err
+        );
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+        stream.shutdown();
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn deadline_lets_a_prompt_line_through() {
+        let (mut stream, mut peer) = pair();
+        peer.write_all(b"10.9.0.2/24 frame=2\n").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let line = read_line(&mut stream.until(deadline)).unwrap();
+        assert_eq!(line, "10.9.0.2/24 frame=2\n");
+    }
+}
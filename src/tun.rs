@@ -0,0 +1,418 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use nix::libc;
+
+use crate::error::{Result, VpnError};
+use crate::hexdump;
+use crate::protocol::{cidr_contains, parse_cidr};
+
+// Device creation, addressing and packet I/O are platform specific; the
+// backends add their half of `impl TunInterface` (opening the device,
+// reading and writing packets) and the configuration functions that
+// `impl TunDevice` calls.
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+mod bsd;
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+use bsd as platform;
+#[cfg(target_os = "linux")]
+use linux as platform;
+use platform::routes;
+
+// What a backend can do besides moving packets, so callers can skip what
+// the platform lacks instead of failing at it every time. The last three
+// are what the platform's driver offers; no backend turns them on yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    // Host routes with their own MTU (set_peer_mtu)
+    pub peer_mtu: bool,
+    // Handing DNS servers to the system resolver (set_dns)
+    pub dns: bool,
+    // Several queues on one interface, each read by its own thread
+    pub multiqueue: bool,
+    // Checksum and segmentation offloads, with a header on every packet
+    pub offloads: bool,
+    // Keeping the interface after the last descriptor is closed
+    pub persist: bool,
+}
+
+// struct ifreq with the member of its union that an ioctl uses (flags,
+// MTU), padded to the full size
+#[repr(C)]
+struct Ifreq<T> {
+    ifr_name: [u8; libc::IFNAMSIZ],
+    ifr_ifru: T,
+    _pad: [u8; 64],
+}
+
+// What a failed read or write on the device means for the forwarding loops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    // Out of buffers, interrupted and the like: try again
+    Transient,
+    // The interface is down: wait for it to come back
+    Down,
+    // The device is gone or the descriptor broken: give up
+    Gone,
+}
+
+// How often a paused loop checks whether the interface is up again
+const LINK_POLL: Duration = Duration::from_secs(1);
+// Longest wait between retries of a failing read
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// A TUN device as the forwarding core sees it. Each platform backend
+// provides one (TunInterface, with the platform half in tun/<os>.rs), and
+// the server and client are written against this trait alone; what not
+// every platform can do is asked of features() first.
+pub trait TunDevice: Sized + Send + 'static {
+    fn name(&self) -> &str;
+
+    // Second handle on the same device, so one thread can read while another writes
+    fn try_clone(&self) -> Result<Self>;
+
+    // Descriptor of the device, for handing it to a new process
+    fn raw_fd(&self) -> RawFd;
+
+    fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    fn write_packet(&mut self, buf: &[u8]) -> Result<usize>;
+
+    // Whether the interface is administratively up
+    fn is_up(&self) -> Result<bool>;
+
+    fn mtu(&self) -> Result<usize>;
+
+    fn set_mtu(&self, mtu: usize) -> Result<()>;
+
+    // Give the interface its address and bring it up
+    fn set_addr(&self, cidr: &str) -> Result<()>;
+
+    // A further address, e.g. an IPv6 one next to the IPv4 of set_addr
+    fn add_address(&self, cidr: &str) -> Result<()>;
+
+    // Done with this handle; other clones keep the device open
+    fn close(self);
+
+    fn features(&self) -> Features;
+
+    // Host route for one peer with a smaller MTU than the device (needs
+    // features().peer_mtu)
+    fn set_peer_mtu(&self, addr: IpAddr, mtu: usize) -> Result<()>;
+
+    fn clear_peer_mtu(&self, addr: IpAddr) -> Result<()>;
+
+    // Route a network pushed by the server through the tunnel
+    fn add_route(&self, cidr: &str) -> Result<()>;
+
+    fn del_route(&self, cidr: &str) -> Result<()>;
+
+    // Use these DNS servers for lookups through the tunnel (needs
+    // features().dns)
+    fn set_dns(&self, servers: &[IpAddr]) -> Result<()>;
+
+    // The next packet for a forwarding loop. Transient errors (ENOBUFS,
+    // EINTR, ...) are retried with backoff, and while the interface is down
+    // the loop is paused until it is up again; `up` follows that for the
+    // health check. Errors returned mean the device is gone.
+    fn next_packet(&mut self, buf: &mut [u8], up: &AtomicBool) -> Result<usize> {
+        let mut failures = 0;
+        loop {
+            let fault = match self.read_packet(buf) {
+                Ok(n) if n > 0 => {
+                    if failures > 0 {
+                        info!("Reading from {} works again.", self.name());
+                    }
+                    return Ok(n);
+                }
+                // What some systems read from a downed interface
+                Ok(_) => Fault::Down,
+                Err(e) => match fault(&e) {
+                    Fault::Gone => return Err(e),
+                    Fault::Transient if failures == 0 => {
+                        warn!("{}; retrying", e);
+                        Fault::Transient
+                    }
+                    fault => fault,
+                },
+            };
+            if fault == Fault::Down && self.wait_up(up)? {
+                continue;
+            }
+            failures += 1;
+            thread::sleep(Duration::from_millis(1 << failures.min(10)).min(MAX_BACKOFF));
+        }
+    }
+
+    // Write a packet for a forwarding loop. Packets that cannot be written
+    // because the interface is down or short of buffers are dropped
+    // (false); errors returned mean the device is gone.
+    fn deliver(&mut self, packet: &[u8], up: &AtomicBool) -> Result<bool> {
+        match self.write_packet(packet) {
+            Ok(_) => {
+                if !up.swap(true, Ordering::Relaxed) {
+                    info!("{} is up again.", self.name());
+                }
+                Ok(true)
+            }
+            Err(e) => match fault(&e) {
+                Fault::Gone => Err(e),
+                Fault::Down => {
+                    if up.swap(false, Ordering::Relaxed) {
+                        warn!("{} is down; dropping packets for it.", self.name());
+                    }
+                    Ok(false)
+                }
+                Fault::Transient => {
+                    debug!("Dropping packet: {}", e);
+                    Ok(false)
+                }
+            },
+        }
+    }
+
+    // Wait until the interface is up; false if it was not down
+    fn wait_up(&self, up: &AtomicBool) -> Result<bool> {
+        if self.is_up()? {
+            return Ok(false);
+        }
+        up.store(false, Ordering::Relaxed);
+        warn!("{} is down; pausing until it is up again.", self.name());
+        while !self.is_up()? {
+            thread::sleep(LINK_POLL);
+        }
+        up.store(true, Ordering::Relaxed);
+        info!("{} is up again; resuming.", self.name());
+        Ok(true)
+    }
+}
+
+#[derive(Debug)]
+pub struct TunInterface {
+    file: File,
+    name: String,
+}
+
+impl TunDevice for TunInterface {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn try_clone(&self) -> Result<TunInterface> {
+        let file = self
+            .file
+            .try_clone()
+            .map_err(|e| VpnError::tun(format!("Failed to duplicate {}", self.name), e))?;
+        Ok(TunInterface {
+            file,
+            name: self.name.clone(),
+        })
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self
+            .recv(buf)
+            .map_err(|e| VpnError::tun(format!("Read from {} failed", self.name), e))?;
+        if n > 0 {
+            debug!("Read {} bytes from TUN {}:", n, self.name);
+            hexdump(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn write_packet(&mut self, buf: &[u8]) -> Result<usize> {
+        debug!("Writing {} bytes to TUN {}:", buf.len(), self.name);
+        hexdump(buf);
+        self.send(buf)
+            .map_err(|e| VpnError::tun(format!("Write to {} failed", self.name), e))
+    }
+
+    // IFF_UP of the interface flags
+    fn is_up(&self) -> Result<bool> {
+        let mut ifr = self.ifreq(0 as libc::c_short);
+        self.ioctl(libc::SIOCGIFFLAGS, &mut ifr, "flags")?;
+        Ok(ifr.ifr_ifru as libc::c_int & libc::IFF_UP != 0)
+    }
+
+    fn mtu(&self) -> Result<usize> {
+        let mut ifr = self.ifreq(0 as libc::c_int);
+        self.ioctl(libc::SIOCGIFMTU, &mut ifr, "MTU")?;
+        Ok(ifr.ifr_ifru as usize)
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<()> {
+        platform::set_mtu(&self.name, mtu)
+    }
+
+    fn set_addr(&self, cidr: &str) -> Result<()> {
+        platform::set_ip(&self.name, cidr)
+    }
+
+    fn add_address(&self, cidr: &str) -> Result<()> {
+        platform::add_address(&self.name, cidr)
+    }
+
+    fn close(self) {
+        debug!("Closing a handle on {}", self.name);
+    }
+
+    fn features(&self) -> Features {
+        platform::features()
+    }
+
+    fn set_peer_mtu(&self, addr: IpAddr, mtu: usize) -> Result<()> {
+        platform::set_peer_mtu(&self.name, addr, mtu)
+    }
+
+    fn clear_peer_mtu(&self, addr: IpAddr) -> Result<()> {
+        platform::clear_peer_mtu(&self.name, addr)
+    }
+
+    fn add_route(&self, cidr: &str) -> Result<()> {
+        platform::add_route(&self.name, cidr)
+    }
+
+    fn del_route(&self, cidr: &str) -> Result<()> {
+        platform::del_route(&self.name, cidr)
+    }
+
+    fn set_dns(&self, servers: &[IpAddr]) -> Result<()> {
+        platform::set_dns(&self.name, servers)
+    }
+}
+
+impl TunInterface {
+    // A struct ifreq naming this interface, with `value` in its union
+    fn ifreq<T>(&self, value: T) -> Ifreq<T> {
+        let mut ifr = Ifreq {
+            ifr_name: [0u8; libc::IFNAMSIZ],
+            ifr_ifru: value,
+            _pad: [0u8; 64],
+        };
+        let len = self.name.len().min(libc::IFNAMSIZ - 1);
+        ifr.ifr_name[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        ifr
+    }
+
+    // An interface ioctl (SIOCGIF*), which goes to a socket rather than to
+    // the device
+    fn ioctl<T>(&self, request: libc::c_ulong, ifr: &mut Ifreq<T>, what: &str) -> Result<()> {
+        let context = || format!("Cannot get the {} of {}", what, self.name);
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(VpnError::tun(context(), io::Error::last_os_error()));
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let res = unsafe { libc::ioctl(socket.as_raw_fd(), request as _, ifr as *mut _) };
+        if res < 0 {
+            return Err(VpnError::tun(context(), io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+fn fault(e: &VpnError) -> Fault {
+    let VpnError::Tun { source, .. } = e else {
+        return Fault::Gone;
+    };
+    match source.raw_os_error() {
+        Some(libc::EIO | libc::ENETDOWN) => Fault::Down,
+        Some(libc::EBADF | libc::ENODEV | libc::ENXIO) => Fault::Gone,
+        #[cfg(target_os = "linux")]
+        Some(libc::EBADFD) => Fault::Gone,
+        // A packet the kernel refuses (EINVAL for a malformed one) costs
+        // only that packet
+        _ => Fault::Transient,
+    }
+}
+
+// Run a configuration command (ip, ifconfig, route), mapping failures to a
+// TUN error
+fn run(program: &str, args: &[&str], context: &str) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| VpnError::tun(context, e))?;
+    if !status.success() {
+        return Err(VpnError::tun(
+            context,
+            std::io::Error::other(format!(
+                "{} {} exited with {}",
+                program,
+                args.join(" "),
+                status
+            )),
+        ));
+    }
+    Ok(())
+}
+
+// A route of the host, as far as telling whether the tunnel subnet
+// collides with a local network goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub net: IpAddr,
+    pub prefix: u8,
+    pub dev: String,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} on {}", self.net, self.prefix, self.dev)
+    }
+}
+
+// Check the subnet `cidr` about to be put on `dev` against the host's
+// routes. A tunnel subnet that overlaps a local network (the LAN, a
+// container bridge, another VPN) makes destinations in the overlap
+// unreachable one way or the other without a word; refuse to start unless
+// `allow` (`allow_overlap = true`), which only warns. Default routes do not
+// count, and where routes cannot be read the check is skipped.
+pub fn check_overlap(cidr: &str, dev: &str, allow: bool) -> Result<()> {
+    let Some((net, prefix)) = parse_cidr(cidr) else {
+        return Ok(());
+    };
+    let routes = match routes() {
+        Ok(routes) => routes,
+        Err(e) => {
+            debug!("Cannot check {} against the local routes: {}", cidr, e);
+            return Ok(());
+        }
+    };
+    let overlapping: Vec<String> = routes
+        .into_iter()
+        .filter(|r| r.prefix > 0 && r.dev != dev && r.dev != "lo")
+        .filter(|r| cidr_contains(net, prefix.min(r.prefix), r.net))
+        .map(|r| r.to_string())
+        .collect();
+    if overlapping.is_empty() {
+        return Ok(());
+    }
+    let overlapping = overlapping.join(", ");
+    if !allow {
+        return Err(VpnError::Config(format!(
+            "{} overlaps local routes ({}); choose another tun_ip, or set allow_overlap to start anyway",
+            cidr, overlapping
+        )));
+    }
+    warn!(
+        "{} overlaps local routes ({}); destinations in the overlap may be unreachable!",
+        cidr, overlapping
+    );
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, RawFd};
+
+use log::{debug, info};
+use nix::libc;
+
+use super::{run, Features, Route, TunInterface};
+use crate::error::{Result, VpnError};
+use crate::packet;
+use crate::protocol::parse_cidr;
+
+// BSD backend: /dev/tunN, configured through ifconfig(8) and route(8).
+//
+// Every packet read from or written to the device carries a 4-byte address
+// family header in network byte order. OpenBSD always uses it; on FreeBSD it
+// is switched on with TUNSIFHEAD so IPv6 works as well as IPv4.
+
+// _IOW('t', 96, int)
+#[cfg(target_os = "freebsd")]
+const TUNSIFHEAD: libc::c_ulong = 0x8004_7460;
+
+const AF_HEADER_LEN: usize = 4;
+
+impl TunInterface {
+    pub fn new(name: &str) -> Result<TunInterface> {
+        info!("Starting TUN interface creation: {}", name);
+        if !name.starts_with("tun") || name.len() >= libc::IFNAMSIZ {
+            return Err(VpnError::Config(format!(
+                "TUN name must be tunN (max {} bytes): {}",
+                libc::IFNAMSIZ - 1,
+                name
+            )));
+        }
+        let path = format!("/dev/{}", name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| VpnError::tun(format!("Failed to open {}", path), e))?;
+
+        #[cfg(target_os = "freebsd")]
+        {
+            let on: libc::c_int = 1;
+            let res = unsafe { libc::ioctl(file.as_raw_fd(), TUNSIFHEAD, &on as *const _) };
+            if res < 0 {
+                return Err(VpnError::tun(
+                    format!("TUNSIFHEAD failed for {}", name),
+                    io::Error::last_os_error(),
+                ));
+            }
+        }
+
+        info!("TUN interface {} opened successfully.", name);
+        Ok(TunInterface {
+            file,
+            name: name.to_string(),
+        })
+    }
+
+    // The interface name of an inherited descriptor cannot be recovered
+    // portably on the BSDs
+    pub fn from_fd(fd: RawFd) -> Result<TunInterface> {
+        Err(VpnError::Config(format!(
+            "tun_fd ({}) is only supported on Linux",
+            fd
+        )))
+    }
+
+    pub(super) fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut header = [0u8; AF_HEADER_LEN];
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        let n = unsafe { libc::readv(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as _) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize).saturating_sub(AF_HEADER_LEN))
+    }
+
+    pub(super) fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let af = match packet::ip_version(buf) {
+            Some(6) => libc::AF_INET6,
+            _ => libc::AF_INET,
+        };
+        let header = (af as u32).to_be_bytes();
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        let n = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as _) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize).saturating_sub(AF_HEADER_LEN))
+    }
+}
+
+// route(8) takes -mtu only on FreeBSD; see also set_dns
+pub(super) fn features() -> Features {
+    Features {
+        peer_mtu: cfg!(target_os = "freebsd"),
+        dns: false,
+        multiqueue: false,
+        offloads: false,
+        persist: false,
+    }
+}
+
+pub(super) fn set_ip(name: &str, cidr: &str) -> Result<()> {
+    info!("Setting IP {} on {}", cidr, name);
+    let (addr, prefix) = parse_cidr(cidr)
+        .ok_or_else(|| VpnError::Config(format!("Invalid tunnel address: {}", cidr)))?;
+    let local = addr.to_string();
+    let net = format!("{}/{}", network(addr, prefix), prefix);
+    // tun is point-to-point: give it our own address as the far end and
+    // route the tunnel network at the interface
+    match addr {
+        IpAddr::V4(_) => {
+            run_ifconfig(
+                &[name, "inet", &local, &local, "up"],
+                "Failed to set IP on TUN",
+            )?;
+            run_route(&["add", "-inet", "-net", &net, "-interface", &local])?;
+        }
+        IpAddr::V6(_) => {
+            let prefix = prefix.to_string();
+            run_ifconfig(
+                &[name, "inet6", &local, "prefixlen", &prefix, "up"],
+                "Failed to set IP on TUN",
+            )?;
+            run_route(&["add", "-inet6", "-net", &net, "-interface", &local])?;
+        }
+    }
+    info!("TUN interface {} is up with IP {}.", name, cidr);
+    Ok(())
+}
+
+// A further address, e.g. an IPv6 one next to the IPv4 of set_ip.
+// ifconfig adds IPv6 addresses rather than replacing them, so this is
+// set_ip for those.
+pub(super) fn add_address(name: &str, cidr: &str) -> Result<()> {
+    match parse_cidr(cidr) {
+        Some((IpAddr::V6(_), _)) => set_ip(name, cidr),
+        _ => Err(VpnError::Config(format!(
+            "Only IPv6 addresses can be added: {}",
+            cidr
+        ))),
+    }
+}
+
+pub(super) fn set_mtu(name: &str, mtu: usize) -> Result<()> {
+    info!("Setting MTU {} on {}", mtu, name);
+    run_ifconfig(&[name, "mtu", &mtu.to_string()], "Failed to set TUN MTU")
+}
+
+// FreeBSD's route(8) accepts an interface name as the -interface gateway
+#[cfg(target_os = "freebsd")]
+pub(super) fn set_peer_mtu(name: &str, addr: IpAddr, mtu: usize) -> Result<()> {
+    debug!("Route {} via {} with MTU {}", addr, name, mtu);
+    let host = addr.to_string();
+    let mtu = mtu.to_string();
+    run_route(&[
+        "add",
+        family(addr),
+        "-host",
+        &host,
+        "-interface",
+        name,
+        "-mtu",
+        &mtu,
+    ])
+}
+
+#[cfg(not(target_os = "freebsd"))]
+pub(super) fn set_peer_mtu(name: &str, addr: IpAddr, mtu: usize) -> Result<()> {
+    debug!("Route {} via {} with MTU {}", addr, name, mtu);
+    Err(VpnError::Config(format!(
+        "Per-client MTU routes are not supported on this platform ({} wants {})",
+        addr, mtu
+    )))
+}
+
+pub(super) fn clear_peer_mtu(_name: &str, addr: IpAddr) -> Result<()> {
+    run_route(&["delete", family(addr), "-host", &addr.to_string()])
+}
+
+// Route a network pushed by the server through the tunnel
+pub(super) fn add_route(name: &str, cidr: &str) -> Result<()> {
+    let (addr, _) =
+        parse_cidr(cidr).ok_or_else(|| VpnError::Config(format!("Invalid route: {}", cidr)))?;
+    run_route(&["add", family(addr), "-net", cidr, "-interface", name])
+}
+
+pub(super) fn del_route(name: &str, cidr: &str) -> Result<()> {
+    let (addr, _) =
+        parse_cidr(cidr).ok_or_else(|| VpnError::Config(format!("Invalid route: {}", cidr)))?;
+    run_route(&["delete", family(addr), "-net", cidr, "-interface", name])
+}
+
+// There is no common resolver interface to hand servers to on the BSDs
+pub(super) fn set_dns(_name: &str, servers: &[IpAddr]) -> Result<()> {
+    Err(VpnError::Config(format!(
+        "Setting DNS ({:?}) is not supported on this platform",
+        servers
+    )))
+}
+
+// There is no /proc to read the routing table from, and netstat(1) output
+// differs between the BSDs; the overlap check is skipped
+pub(super) fn routes() -> io::Result<Vec<Route>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading routes is not supported on this platform",
+    ))
+}
+
+fn family(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "-inet",
+        IpAddr::V6(_) => "-inet6",
+    }
+}
+
+// First address of the network `addr/prefix` belongs to
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(a) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4((u32::from(a) & mask).into())
+        }
+        IpAddr::V6(a) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(a) & mask).into())
+        }
+    }
+}
+
+fn run_ifconfig(args: &[&str], context: &str) -> Result<()> {
+    run("ifconfig", args, context)
+}
+
+fn run_route(args: &[&str]) -> Result<()> {
+    run("route", args, "Failed to update routing table")
+}
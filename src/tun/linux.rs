@@ -0,0 +1,258 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+
+use log::{debug, info};
+use nix::libc;
+
+use super::{run, Features, Ifreq, Route, TunInterface};
+use crate::error::{Result, VpnError};
+use crate::protocol::parse_cidr;
+
+// Linux backend: /dev/net/tun with IFF_NO_PI, configured through iproute2.
+
+impl TunInterface {
+    pub fn new(name: &str) -> Result<TunInterface> {
+        info!("Starting TUN interface creation: {}", name);
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+            .map_err(|e| VpnError::tun("Failed to open /dev/net/tun", e))?;
+
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(VpnError::Config(format!(
+                "TUN name too long: {} (max {} bytes)",
+                name,
+                libc::IFNAMSIZ - 1
+            )));
+        }
+        let mut ifr_name = [0u8; libc::IFNAMSIZ];
+        for (i, c) in name.bytes().enumerate() {
+            ifr_name[i] = c;
+        }
+
+        let flags: libc::c_short = (libc::IFF_TUN | libc::IFF_NO_PI) as i16;
+
+        let mut ifr = Ifreq {
+            ifr_name,
+            ifr_ifru: flags,
+            _pad: [0u8; 64],
+        };
+
+        let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut ifr as *mut _) };
+        if res < 0 {
+            return Err(VpnError::tun(
+                format!("TUNSETIFF failed for {}", name),
+                std::io::Error::last_os_error(),
+            ));
+        }
+
+        info!("TUN interface {} created successfully.", name);
+        Ok(TunInterface {
+            file: fd,
+            name: name.to_string(),
+        })
+    }
+
+    // Adopt a TUN descriptor opened and configured by someone else (a
+    // privileged launcher, a test harness). The device must be a TUN without
+    // packet info, as created by `new`.
+    pub fn from_fd(fd: RawFd) -> Result<TunInterface> {
+        info!("Using TUN interface from fd {}", fd);
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(VpnError::tun(
+                format!("fd {} is not open", fd),
+                std::io::Error::last_os_error(),
+            ));
+        }
+
+        let mut ifr = Ifreq {
+            ifr_name: [0u8; libc::IFNAMSIZ],
+            ifr_ifru: 0 as libc::c_short,
+            _pad: [0u8; 64],
+        };
+        let res = unsafe { libc::ioctl(fd, libc::TUNGETIFF, &mut ifr as *mut _) };
+        if res < 0 {
+            return Err(VpnError::tun(
+                format!("fd {} is not a TUN device (TUNGETIFF failed)", fd),
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let flags = ifr.ifr_ifru as libc::c_int;
+        if flags & libc::IFF_TUN == 0 || flags & libc::IFF_NO_PI == 0 {
+            return Err(VpnError::Config(format!(
+                "fd {} must be a TUN device opened with IFF_TUN | IFF_NO_PI",
+                fd
+            )));
+        }
+        let len = ifr.ifr_name.iter().position(|&b| b == 0).unwrap_or(0);
+        let name = String::from_utf8_lossy(&ifr.ifr_name[..len]).into_owned();
+
+        info!("Adopted TUN interface {} from fd {}.", name, fd);
+        Ok(TunInterface {
+            file: unsafe { File::from_raw_fd(fd) },
+            name,
+        })
+    }
+
+    pub(super) fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    pub(super) fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+}
+
+// IFF_MULTI_QUEUE, IFF_VNET_HDR and TUNSETPERSIST
+pub(super) fn features() -> Features {
+    Features {
+        peer_mtu: true,
+        dns: true,
+        multiqueue: true,
+        offloads: true,
+        persist: true,
+    }
+}
+
+pub(super) fn set_ip(name: &str, cidr: &str) -> Result<()> {
+    info!("Setting IP {} on {}", cidr, name);
+    run_ip(
+        &["addr", "add", cidr, "dev", name],
+        "Failed to set IP on TUN",
+    )?;
+    run_ip(&["link", "set", "dev", name, "up"], "Failed to set TUN up")?;
+    info!("TUN interface {} is up with IP {}.", name, cidr);
+    Ok(())
+}
+
+// A further address, e.g. an IPv6 one next to the IPv4 of set_ip; adding
+// it again is fine
+pub(super) fn add_address(name: &str, cidr: &str) -> Result<()> {
+    info!("Adding address {} on {}", cidr, name);
+    parse_cidr(cidr)
+        .ok_or_else(|| VpnError::Config(format!("Invalid tunnel address: {}", cidr)))?;
+    run_ip(
+        &["addr", "replace", cidr, "dev", name],
+        "Failed to add address on TUN",
+    )
+}
+
+pub(super) fn set_mtu(name: &str, mtu: usize) -> Result<()> {
+    info!("Setting MTU {} on {}", mtu, name);
+    run_ip(
+        &["link", "set", "dev", name, "mtu", &mtu.to_string()],
+        "Failed to set TUN MTU",
+    )
+}
+
+// Host route for one peer with a smaller MTU than the device, so the
+// kernel fragments (or signals PMTU) for that peer only
+pub(super) fn set_peer_mtu(name: &str, addr: IpAddr, mtu: usize) -> Result<()> {
+    let host = host_route(addr);
+    debug!("Route {} via {} with MTU {}", host, name, mtu);
+    run_ip(
+        &[
+            "route",
+            "replace",
+            &host,
+            "dev",
+            name,
+            "mtu",
+            &mtu.to_string(),
+        ],
+        "Failed to set peer MTU route",
+    )
+}
+
+pub(super) fn clear_peer_mtu(name: &str, addr: IpAddr) -> Result<()> {
+    run_ip(
+        &["route", "del", &host_route(addr), "dev", name],
+        "Failed to remove peer MTU route",
+    )
+}
+
+// Route a network pushed by the server through the tunnel
+pub(super) fn add_route(name: &str, cidr: &str) -> Result<()> {
+    parse_cidr(cidr).ok_or_else(|| VpnError::Config(format!("Invalid route: {}", cidr)))?;
+    run_ip(
+        &["route", "replace", cidr, "dev", name],
+        "Failed to add route",
+    )
+}
+
+pub(super) fn del_route(name: &str, cidr: &str) -> Result<()> {
+    run_ip(
+        &["route", "del", cidr, "dev", name],
+        "Failed to remove route",
+    )
+}
+
+// Use these DNS servers for lookups through the tunnel (systemd-resolved)
+pub(super) fn set_dns(name: &str, servers: &[IpAddr]) -> Result<()> {
+    info!("Setting DNS {:?} on {}", servers, name);
+    let servers: Vec<String> = servers.iter().map(IpAddr::to_string).collect();
+    let mut args = vec!["dns", name];
+    args.extend(servers.iter().map(String::as_str));
+    run("resolvectl", &args, "Failed to set DNS")
+}
+
+// The routes of the main table (IPv4) and of all tables (IPv6), from /proc.
+// Multicast and link-local IPv6 routes, which every interface has, are
+// left out.
+pub(super) fn routes() -> io::Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ..., with
+    // addresses as hex of the raw (network order) value
+    for line in fs::read_to_string("/proc/net/route")?.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let hex = |i: usize| fields.get(i).and_then(|f| u32::from_str_radix(f, 16).ok());
+        let (Some(net), Some(mask)) = (hex(1), hex(7)) else {
+            continue;
+        };
+        routes.push(Route {
+            net: IpAddr::V4(Ipv4Addr::from(net.to_ne_bytes())),
+            prefix: mask.count_ones() as u8,
+            dev: fields[0].to_string(),
+        });
+    }
+    // Destination, prefix length (hex), source, source prefix, next hop,
+    // metric, refcount, use, flags, device
+    if let Ok(table) = fs::read_to_string("/proc/net/ipv6_route") {
+        for line in table.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+            let (Ok(net), Ok(prefix)) = (
+                u128::from_str_radix(fields[0], 16),
+                u8::from_str_radix(fields[1], 16),
+            ) else {
+                continue;
+            };
+            let net = Ipv6Addr::from(net);
+            if net.is_multicast() || net.segments()[0] & 0xffc0 == 0xfe80 {
+                continue;
+            }
+            routes.push(Route {
+                net: IpAddr::V6(net),
+                prefix,
+                dev: fields[9].to_string(),
+            });
+        }
+    }
+    Ok(routes)
+}
+
+fn host_route(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(a) => format!("{}/32", a),
+        IpAddr::V6(a) => format!("{}/128", a),
+    }
+}
+
+fn run_ip(args: &[&str], context: &str) -> Result<()> {
+    run("ip", args, context)
+}
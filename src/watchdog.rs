@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::packet;
+
+// End-to-end check of the data path for the client (`watchdog = <address>`).
+//
+// A TCP connection can look healthy while packets through the tunnel go
+// nowhere, e.g. when the server lost its TUN or its route back. The client
+// sends an ICMP echo request from its tunnel address to the watchdog
+// address every watchdog_interval, straight into the send queue; replies
+// are taken out before they reach the TUN. After watchdog_failures
+// unanswered probes in a row the connection is dropped so the client
+// reconnects.
+#[derive(Debug)]
+pub struct Watchdog {
+    source: IpAddr,
+    target: IpAddr,
+    ident: u16,
+    seq: AtomicU16,
+    // Whether the last probe was answered
+    answered: AtomicBool,
+}
+
+impl Watchdog {
+    // None if `target` is not of the same family as our tunnel address
+    pub fn new(source: IpAddr, target: IpAddr) -> Option<Watchdog> {
+        if source.is_ipv4() != target.is_ipv4() {
+            return None;
+        }
+        Some(Watchdog {
+            source,
+            target,
+            ident: std::process::id() as u16,
+            seq: AtomicU16::new(0),
+            answered: AtomicBool::new(true),
+        })
+    }
+
+    pub fn target(&self) -> IpAddr {
+        self.target
+    }
+
+    // The next probe; returns whether the previous one was answered
+    pub fn probe(&self) -> (Vec<u8>, bool) {
+        let answered = self.answered.swap(false, Ordering::Relaxed);
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let packet = packet::echo_request(self.source, self.target, self.ident, seq).unwrap();
+        (packet, answered)
+    }
+
+    // Forget outstanding probes, e.g. when a new session starts
+    pub fn reset(&self) {
+        self.answered.store(true, Ordering::Relaxed);
+    }
+
+    // Whether `packet` is the reply to our last probe; it is not for the TUN
+    pub fn take_reply(&self, packet: &[u8]) -> bool {
+        match packet::echo_reply(packet) {
+            Some((from, ident, seq)) if from == self.target && ident == self.ident => {
+                if seq == self.seq.load(Ordering::Relaxed) {
+                    self.answered.store(true, Ordering::Relaxed);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::control::Context;
+use crate::error::Result;
+use crate::handoff;
+
+// Read-only status page (`web = 127.0.0.1:8080`), optionally behind HTTP
+// basic auth with `web_password` (any user name).
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+// Samples kept per session: ten minutes
+const HISTORY_LEN: usize = 120;
+const REFRESH_SECS: u64 = 5;
+const MAX_HEADER_LINES: usize = 100;
+// Bytes of a request looked at; anything after is ignored
+const MAX_REQUEST: u64 = 16 * 1024;
+
+// Per-session throughput samples in bytes/s, (rx, tx), oldest first
+type History = Arc<Mutex<HashMap<u64, VecDeque<(u64, u64)>>>>;
+
+pub fn spawn(addr: &str, password: Option<String>, ctx: Arc<Context>) -> Result<()> {
+    let listener = handoff::claim(&format!("web {}", addr), || TcpListener::bind(addr))?;
+    info!("Web dashboard listening on http://{}/", addr);
+    let history = History::default();
+    spawn_sampler(ctx.clone(), history.clone());
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(stream, password.as_deref(), &ctx, &history) {
+                debug!("Web request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Thread: turn the session byte counters into throughput samples
+fn spawn_sampler(ctx: Arc<Context>, history: History) {
+    let Some(sessions) = ctx.sessions.clone() else {
+        return;
+    };
+    thread::spawn(move || {
+        let mut last: HashMap<u64, (u64, u64)> = HashMap::new();
+        loop {
+            thread::sleep(SAMPLE_INTERVAL);
+            let secs = SAMPLE_INTERVAL.as_secs().max(1);
+            let mut current = HashMap::new();
+            let mut history = history.lock().unwrap();
+            for s in sessions.list() {
+                let rx = s.rx_bytes.load(Ordering::Relaxed);
+                let tx = s.tx_bytes.load(Ordering::Relaxed);
+                let (last_rx, last_tx) = last.get(&s.id).copied().unwrap_or((0, 0));
+                let samples = history.entry(s.id).or_default();
+                if samples.len() == HISTORY_LEN {
+                    samples.pop_front();
+                }
+                samples.push_back(((rx - last_rx) / secs, (tx - last_tx) / secs));
+                current.insert(s.id, (rx, tx));
+            }
+            history.retain(|id, _| current.contains_key(id));
+            last = current;
+        }
+    });
+}
+
+fn handle(
+    stream: TcpStream,
+    password: Option<&str>,
+    ctx: &Context,
+    history: &History,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut authorization = None;
+    for _ in 0..MAX_HEADER_LINES {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let response = if password.is_some_and(|p| !authorized(authorization.as_deref(), p)) {
+        response(
+            "401 Unauthorized",
+            "text/plain",
+            "WWW-Authenticate: Basic realm=\"vpn\"\r\n",
+            "authentication required\n",
+        )
+    } else if method != "GET" {
+        response("405 Method Not Allowed", "text/plain", "", "GET only\n")
+    } else if path == "/" {
+        let page = render(ctx, &history.lock().unwrap());
+        response("200 OK", "text/html; charset=utf-8", "", &page)
+    } else {
+        response("404 Not Found", "text/plain", "", "not found\n")
+    };
+    (&stream).write_all(response.as_bytes())
+}
+
+fn response(code: &str, content_type: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n{}Connection: close\r\n\r\n{}",
+        code,
+        content_type,
+        body.len(),
+        headers,
+        body
+    )
+}
+
+// Check "Basic base64(user:password)"; the user name is not checked
+fn authorized(header: Option<&str>, password: &str) -> bool {
+    let Some(encoded) = header.and_then(|h| h.strip_prefix("Basic ")) else {
+        return false;
+    };
+    let Some(decoded) = base64_decode(encoded.trim()) else {
+        return false;
+    };
+    match decoded.iter().position(|&b| b == b':') {
+        Some(i) => constant_time_eq(&decoded[i + 1..], password.as_bytes()),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes().take_while(|&c| c != b'=') {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn render(ctx: &Context, history: &HashMap<u64, VecDeque<(u64, u64)>>) -> String {
+    let status = &ctx.status;
+    let health = status.health();
+    let mut page = String::new();
+    write!(
+        page,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>vpn {}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         td,th{{border:1px solid #ccc;padding:4px 8px;text-align:left}}</style></head><body>\n",
+        REFRESH_SECS, status.role
+    )
+    .unwrap();
+    writeln!(
+        page,
+        "<h1>vpn {}</h1>\n<p>Health: <b>{}</b> &middot; uptime {}</p>",
+        status.role,
+        health.label(),
+        duration(status.uptime())
+    )
+    .unwrap();
+
+    if let Some(sessions) = &ctx.sessions {
+        let list = sessions.list();
+        writeln!(page, "<h2>Clients ({})</h2>", list.len()).unwrap();
+        page.push_str(
+            "<table><tr><th>#</th><th>Address</th><th>Peer</th><th>Uptime</th>\
+             <th>Received</th><th>Sent</th><th>Dropped</th><th>Throughput</th></tr>\n",
+        );
+        for s in list {
+            let samples = history.get(&s.id);
+            writeln!(
+                page,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td></tr>",
+                s.id,
+                s.addr,
+                escape(&s.peer.to_string()),
+                duration(s.started.elapsed()),
+                bytes(s.rx_bytes.load(Ordering::Relaxed)),
+                bytes(s.tx_bytes.load(Ordering::Relaxed)),
+                s.tx_dropped.load(Ordering::Relaxed),
+                samples.map(graph).unwrap_or_default(),
+            )
+            .unwrap();
+        }
+        page.push_str("</table>\n");
+    }
+
+    page.push_str("<h2>Recent events</h2>\n<ul>\n");
+    for (age, message) in status.recent_events() {
+        writeln!(page, "<li>{} ago: {}</li>", duration(age), escape(&message)).unwrap();
+    }
+    page.push_str("</ul>\n</body></html>\n");
+    page
+}
+
+// Inline SVG of rx (blue) and tx (green) throughput, with the latest rates
+fn graph(samples: &VecDeque<(u64, u64)>) -> String {
+    const WIDTH: usize = 240;
+    const HEIGHT: u64 = 40;
+    let max = samples
+        .iter()
+        .map(|&(rx, tx)| rx.max(tx))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let step = WIDTH as f64 / (HISTORY_LEN - 1) as f64;
+    let line = |pick: fn(&(u64, u64)) -> u64| {
+        samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| format!("{:.1},{}", i as f64 * step, HEIGHT - pick(s) * HEIGHT / max))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let (rx, tx) = samples.back().copied().unwrap_or((0, 0));
+    format!(
+        "<svg width=\"{w}\" height=\"{h}\" style=\"background:#f6f6f6\">\
+         <polyline fill=\"none\" stroke=\"#36c\" points=\"{}\"/>\
+         <polyline fill=\"none\" stroke=\"#393\" points=\"{}\"/></svg><br>\
+         rx {}/s &middot; tx {}/s",
+        line(|s| s.0),
+        line(|s| s.1),
+        bytes(rx),
+        bytes(tx),
+        w = WIDTH,
+        h = HEIGHT,
+    )
+}
+
+fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn bytes(n: u64) -> String {
+    match n {
+        0..=1023 => format!("{} B", n),
+        1024..=1048575 => format!("{:.1} KiB", n as f64 / 1024.0),
+        1048576..=1073741823 => format!("{:.1} MiB", n as f64 / 1048576.0),
+        _ => format!("{:.1} GiB", n as f64 / 1073741824.0),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}